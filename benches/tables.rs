@@ -0,0 +1,46 @@
+// Stores and loads integer keys filled in different orders. Once the
+// rehash has moved them to the array part, every order should cost about
+// the same as a sequential fill.
+use lua::api::LuaAPI;
+use lua::state::LuaState;
+use std::time::Instant;
+
+const N: i64 = 200_000;
+
+fn fill(ls: &mut LuaState, keys: &[i64]) {
+    ls.new_table();
+    for &k in keys {
+        ls.push_integer(k);
+        ls.set_i(-2, k).unwrap();
+    }
+}
+
+fn read(ls: &mut LuaState, keys: &[i64]) {
+    for &k in keys {
+        ls.get_i(-1, k).unwrap();
+        ls.pop(1);
+    }
+}
+
+fn main() {
+    let sequential: Vec<i64> = (1..=N).collect();
+    let reverse: Vec<i64> = (1..=N).rev().collect();
+    let strided: Vec<i64> = (1..=N).step_by(2).chain((2..=N).step_by(2)).collect();
+
+    for (name, keys) in [("sequential", &sequential), ("reverse", &reverse), ("odd then even", &strided)].iter() {
+        let mut ls = LuaState::new();
+        let start = Instant::now();
+        fill(&mut ls, keys);
+        let stores = start.elapsed();
+        let start = Instant::now();
+        read(&mut ls, &sequential);
+        let loads = start.elapsed();
+        println!(
+            "{:>14}: {:6.1} ns/store {:6.1} ns/load (#t = {})",
+            name,
+            stores.as_nanos() as f64 / N as f64,
+            loads.as_nanos() as f64 / N as f64,
+            ls.raw_len(-1)
+        );
+    }
+}
@@ -3,4 +3,5 @@ mod lua_state;
 mod lua_vm;
 
 pub use self::lua_state::{LuaState as LuaAPI,RustFn};
-pub use self::lua_vm::LuaVM;
\ No newline at end of file
+pub use self::lua_vm::LuaVM;
+pub use crate::state::{DebugInfo, GcBudget, LuaError, LuaResult, LuaString};
\ No newline at end of file
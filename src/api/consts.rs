@@ -10,6 +10,19 @@ pub const LUA_TFUNCTION: i8 = 6;
 pub const LUA_TUSERDATA: i8 = 7;
 pub const LUA_TTHREAD: i8 = 8;
 
+pub const LUA_NUMTAGS: usize = 9;
+
+
+/* options for the garbage collector */
+pub const LUA_GCSTOP: u8 = 0;
+pub const LUA_GCRESTART: u8 = 1;
+pub const LUA_GCCOLLECT: u8 = 2;
+pub const LUA_GCCOUNT: u8 = 3;
+pub const LUA_GCCOUNTB: u8 = 4;
+pub const LUA_GCSTEP: u8 = 5;
+pub const LUA_GCSETPAUSE: u8 = 6;
+pub const LUA_GCSETSTEPMUL: u8 = 7;
+pub const LUA_GCISRUNNING: u8 = 9;
 
 /* arithmetic functions */
 pub const LUA_OPADD: u8 = 0; // +
@@ -35,4 +48,14 @@ pub const LUA_OPLE: u8 = 2; // <=
 pub const LUA_MINSTACK: usize = 20;
 pub const LUAI_MAXSTACK: usize = 1000000;
 pub const LUA_REGISTRYINDEX: isize = -(LUAI_MAXSTACK as isize) - 1000;
-pub const LUA_RIDX_GLOBALS: isize = 2;
\ No newline at end of file
+pub const LUA_RIDX_GLOBALS: isize = 2;
+
+/* option for multiple returns in 'pcall' and 'call' */
+pub const LUA_MULTRET: isize = -1;
+
+/* thread status */
+pub const LUA_OK: u8 = 0;
+pub const LUA_ERRRUN: u8 = 2;
+pub const LUA_ERRMEM: u8 = 4;
+pub const LUA_ERRGCMM: u8 = 5;
+pub const LUA_ERRERR: u8 = 6;
@@ -1,5 +1,7 @@
+use super::{DebugInfo, GcBudget, LuaError, LuaResult, LuaString};
+
 type TypeID = i8;
-pub type RustFn = fn(&LuaState) -> usize;
+pub type RustFn = fn(&mut dyn LuaState) -> LuaResult<usize>;
 
 pub trait LuaState {
 
@@ -8,13 +10,13 @@ pub trait LuaState {
     fn abs_index(&self, idx: isize) -> isize;
     fn check_stack(&mut self, n: usize) -> bool;
     fn pop(&mut self, n: usize);
-    fn copy(&mut self, from_idx: isize, to_idx: isize);
+    fn copy(&mut self, from_idx: isize, to_idx: isize) -> LuaResult<()>;
     fn push_value(&mut self, idx: isize);
-    fn replace(&mut self, idx: isize);
-    fn insert(&mut self, idx: isize);
-    fn remove(&mut self, idx: isize);
-    fn rotate(&mut self, idx: isize, n: isize);
-    fn set_top(&mut self, idx: isize);
+    fn replace(&mut self, idx: isize) -> LuaResult<()>;
+    fn insert(&mut self, idx: isize) -> LuaResult<()>;
+    fn remove(&mut self, idx: isize) -> LuaResult<()>;
+    fn rotate(&mut self, idx: isize, n: isize) -> LuaResult<()>;
+    fn set_top(&mut self, idx: isize) -> LuaResult<()>;
 
     /* access functions (stack -> rust) */
     fn type_name(&self, tp: i8) -> &str; // TODO
@@ -37,6 +39,11 @@ pub trait LuaState {
     fn to_numberx(&self, idx: isize) -> Option<f64>;
     fn to_string(&self, idx: isize) -> String;
     fn to_stringx(&self, idx: isize) -> Option<String>;
+    // the string at idx (numbers are converted) as it is stored, any bytes;
+    // to_string and to_stringx replace the bytes that are not UTF-8
+    fn to_bytes(&self, idx: isize) -> Option<LuaString>;
+    fn to_display_string(&mut self, idx: isize) -> LuaResult<String>;
+    fn to_pointer(&self, idx: isize) -> usize;
     fn to_rust_function(&self, idx: isize) -> Option<RustFn>;
 
     /* push functions (rust -> stack) */
@@ -45,34 +52,65 @@ pub trait LuaState {
     fn push_integer(&mut self, n: i64);
     fn push_number(&mut self, n: f64);
     fn push_string(&mut self, s: String);
+    fn push_bytes(&mut self, bytes: &[u8]);
     fn push_rust_function(&mut self, f: RustFn);
     fn push_rust_closure(&mut self,f: RustFn,n: usize);
     fn push_global_table(&mut self);
+    fn string_to_number(&mut self, s: &str) -> bool;
 
     /* comparison and arithmetic functions */
-    fn arith(&mut self, op: u8);
-    fn compare(&self, idx1: isize, idx2: isize, op: u8) -> bool;
+    fn arith(&mut self, op: u8) -> LuaResult<()>;
+    fn compare(&mut self, idx1: isize, idx2: isize, op: u8) -> LuaResult<bool>;
+    fn raw_equal(&self, idx1: isize, idx2: isize) -> bool;
 
     /* miscellaneous functions */
-    fn len(&mut self, idx: isize);
-    fn concat(&mut self, n: isize);
+    fn raw_len(&self, idx: isize) -> usize;
+    fn len(&mut self, idx: isize) -> LuaResult<()>;
+    fn concat(&mut self, n: isize) -> LuaResult<()>;
+    // pops a key and pushes the key and value that follow it in a
+    // traversal of the table at idx (nil starts it); false, pushing
+    // nothing, at the end
+    fn next(&mut self, idx: isize) -> LuaResult<bool>;
 
     /* get functions (Lua -> stack) */
     fn new_table(&mut self);
     fn create_table(&mut self, narr: usize, nrec: usize);
-    fn get_table(&mut self, idx: isize) -> TypeID;
-    fn get_field(&mut self, idx: isize, k: &str) -> TypeID;
-    fn get_i(&mut self, idx: isize, i: i64) -> TypeID;
-    fn get_global(&mut self, name: &str) -> TypeID;
+    fn get_table(&mut self, idx: isize) -> LuaResult<TypeID>;
+    fn get_field(&mut self, idx: isize, k: &str) -> LuaResult<TypeID>;
+    fn get_i(&mut self, idx: isize, i: i64) -> LuaResult<TypeID>;
+    fn get_global(&mut self, name: &str) -> LuaResult<TypeID>;
+    fn raw_get(&mut self, idx: isize) -> LuaResult<TypeID>;
+    fn raw_get_i(&mut self, idx: isize, i: i64) -> LuaResult<TypeID>;
+    fn get_metatable(&mut self, idx: isize) -> bool;
 
     /* set functions (stack -> Lua) */
-    fn set_table(&mut self, idx: isize);
-    fn set_field(&mut self, idx: isize, k: &str);
-    fn set_i(&mut self, idx: isize, i: i64);
-    fn set_global(&mut self, name: &str);
-    fn register(&mut self, name: &str, f: RustFn);
+    fn set_table(&mut self, idx: isize) -> LuaResult<()>;
+    fn set_field(&mut self, idx: isize, k: &str) -> LuaResult<()>;
+    fn set_i(&mut self, idx: isize, i: i64) -> LuaResult<()>;
+    fn set_global(&mut self, name: &str) -> LuaResult<()>;
+    fn raw_set(&mut self, idx: isize) -> LuaResult<()>;
+    fn raw_set_i(&mut self, idx: isize, i: i64) -> LuaResult<()>;
+    fn set_metatable(&mut self, idx: isize) -> LuaResult<()>;
+    fn register(&mut self, name: &str, f: RustFn) -> LuaResult<()>;
 
     /* 'load' and 'call' functions (load and run Lua code) */
     fn load(&mut self, chunk: Vec<u8>, chunk_name: &str, mode: &str) -> u8;
-    fn call(&mut self, nargs: usize, nresults: isize);
+    fn call(&mut self, nargs: usize, nresults: isize) -> LuaResult<()>;
+    fn pcall(&mut self, nargs: usize, nresults: isize, msgh: isize) -> u8;
+
+    /* garbage-collection function */
+    // an error in a '__gc' metamethod run by the collector is returned
+    // here, or raised at the next point that can fail when the collector
+    // ran by itself
+    fn gc(&mut self, what: u8, data: isize) -> LuaResult<isize>;
+    fn gc_step(&mut self, budget: GcBudget) -> LuaResult<bool>;
+    // makes room for 'bytes' about to be allocated under the memory limit,
+    // collecting if needed; a "not enough memory" error if they do not fit
+    fn reserve(&mut self, bytes: usize) -> LuaResult<()>;
+
+    /* error handling and debug information */
+    fn error(&mut self) -> LuaError;
+    fn _where(&self, level: usize) -> String;
+    fn stack_frames(&self, level: usize) -> Vec<DebugInfo>;
+    fn traceback(&self, msg: Option<&str>, level: usize) -> String;
 }
@@ -5,7 +5,16 @@ pub trait LuaVM: super::lua_state::LuaState {
     fn get_const(&mut self, idx: isize);
     fn get_rk(&mut self, rk: isize);
     fn register_count(&self) -> usize;
+    // R(idx) := n, without going through the top of the stack
+    fn set_integer(&mut self, idx: isize, n: i64) -> super::LuaResult<()>;
+    fn set_number(&mut self, idx: isize, n: f64) -> super::LuaResult<()>;
     fn load_vararg(&mut self, n: isize);
     fn load_proto(&mut self, idx: usize);
     fn close_upvalues(&mut self,a: isize);
+    fn runtime_error(&self, msg: &str) -> super::LuaError;
+    // starts a call from Lua code; true if it is done (a Rust function),
+    // false if a Lua function got a frame for the interpreter loop to run
+    fn pre_call(&mut self, nargs: usize, nresults: isize) -> super::LuaResult<bool>;
+    // like pre_call, but a Lua function's frame replaces the running one
+    fn tail_call(&mut self, nargs: usize) -> super::LuaResult<bool>;
 }
@@ -1,9 +1,11 @@
 pub mod chunk;
 mod reader;
+use crate::state::StringTable;
 use std::rc::Rc;
 
-pub fn undump(data: Vec<u8>) -> Rc<chunk::Prototype> {
-    let mut r = reader::Reader::new(data);
+// the string constants are interned in 'strings' as they are read
+pub fn undump(data: Vec<u8>, strings: &mut StringTable) -> Rc<chunk::Prototype> {
+    let mut r = reader::Reader::new(data, strings);
     r.check_header();
     r.read_upvalues(); // size_upvalues
     r.read_proto()
@@ -1,6 +1,8 @@
+use crate::state::LuaString;
 use std::rc::Rc;
 use crate::vm::instructions::Instruction;
 
+// the layout of a chunk, the reader checks it field by field
 #[allow(dead_code)]
 struct BinaryChunk {
     header: Header,
@@ -61,7 +63,7 @@ pub enum Constant {
     Boolean(bool),
     Number(f64),
     Integer(i64),
-    Str(String),
+    Str(LuaString),
 }
 
 /* header check constants */
@@ -101,17 +103,17 @@ impl Prototype{
     fn print_header(&self) {
         let func_type = if self.line_defined > 0 { "function" } else { "main" };
         let vararg_flag = if self.is_vararg > 0 { "+" } else { "" };
-        let source = self.source.as_ref().map(|x| x.as_str()).unwrap_or("");//TODO:：？
+        let source = self.source.as_deref().unwrap_or("");//TODO:：？
 
         print!("\n{}", func_type);
         print!(" <{}:{},{}>", source, self.line_defined, self.last_line_defined);
-        print!(" ({} instructions)\n", self.code.len());
+        println!(" ({} instructions)", self.code.len());
         print!("{}{} params", self.num_params, vararg_flag);
         print!(", {} slots", self.max_stack_size);
         print!(", {} upvalues", self.upvalues.len());
         print!(", {} locals", self.loc_vars.len());
         print!(", {} constants", self.constants.len());
-        print!(", {} functions\n", self.protos.len());
+        println!(", {} functions", self.protos.len());
     }
 
     fn print_code(&self) {
@@ -119,7 +121,7 @@ impl Prototype{
             let line = self.line_info.get(pc).map(|n| n.to_string()).unwrap_or(String::new());
             let ins = self.code[pc];
             print!("\t{}\t[{}]\t{} ", pc + 1, line,ins.opname());
-            Instruction::print_operands(ins);
+            <dyn Instruction>::print_operands(ins);
             println!();
         }
     }
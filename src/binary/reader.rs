@@ -1,15 +1,17 @@
 use super::chunk;
 use super::chunk::Prototype;
+use crate::state::{LuaString, StringTable};
 use std::rc::Rc;
 
-pub struct Reader {
+pub struct Reader<'a> {
     data: Vec<u8>,
     pos: usize,
+    strings: &'a mut StringTable,
 }
 
-impl Reader {
-    pub fn new(data: Vec<u8>) -> Reader {
-        Reader { data: data, pos: 0 }
+impl<'a> Reader<'a> {
+    pub fn new(data: Vec<u8>, strings: &'a mut StringTable) -> Reader<'a> {
+        Reader { data, pos: 0, strings }
     }
 
     pub fn read_byte(&mut self) -> u8 {
@@ -51,22 +53,29 @@ impl Reader {
     }
 
     fn read_string(&mut self) -> String {
-        match self.read_string0() {
-            None => String::new(),
-            Some(val) => val
-        }
+        self.read_string0().unwrap_or_default()
     }
 
     fn read_string0(&mut self) -> Option<String> {
+        let bytes = self.read_string_bytes()?;
+        let string = String::from_utf8(bytes);
+        string.ok() // Some(string.unwrap())
+    }
+
+    // a string constant, kept as raw bytes
+    fn read_lua_string(&mut self) -> LuaString {
+        let bytes = self.read_string_bytes().unwrap_or_default();
+        self.strings.intern(&bytes)
+    }
+
+    fn read_string_bytes(&mut self) -> Option<Vec<u8>> {
         let mut size = self.read_byte() as usize;
         if size == 0 {
             return None;
         } else if size == 0xFF {
             size = self.read_u64() as usize; // size_t
         }
-        let bytes = self.read_bytes(size - 1);
-        let string = String::from_utf8(bytes);
-        string.ok() // Some(string.unwrap())
+        Some(self.read_bytes(size - 1))
     }
 
     fn read_vec<T, F>(&mut self, f: F) -> Vec<T>
@@ -129,8 +138,7 @@ impl Reader {
             chunk::TAG_BOOLEAN => chunk::Constant::Boolean(self.read_byte() != 0),
             chunk::TAG_INTEGER => chunk::Constant::Integer(self.read_lua_integer()),
             chunk::TAG_NUMBER => chunk::Constant::Number(self.read_lua_number()),
-            chunk::TAG_SHORT_STR => chunk::Constant::Str(self.read_string()),
-            chunk::TAG_LONG_STR => chunk::Constant::Str(self.read_string()),
+            chunk::TAG_SHORT_STR | chunk::TAG_LONG_STR => chunk::Constant::Str(self.read_lua_string()),
             _ => panic!("corrupted!"),
         }
     }
@@ -0,0 +1,5 @@
+pub mod api;
+mod binary;
+pub mod state;
+pub mod stdlib;
+mod vm;
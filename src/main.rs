@@ -1,48 +1,41 @@
-mod api;
-mod binary;
-mod state;
-mod vm;
-
-use crate::api::{ LuaAPI, LuaVM};
-use crate::binary::chunk::Prototype;
-use crate::vm::instructions::Instruction;
+use lua::api::{LuaAPI, LuaResult};
+use lua::{state, stdlib};
 use std::env;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::process;
 
 fn main() -> io::Result<()> {
-    if env::args().count() > 1 {
-        let filename = env::args().nth(1).unwrap();
-        let mut file = File::open(&filename)?;
+    // --locals: report the variables of each function on uncaught errors
+    let (flags, args): (Vec<String>, Vec<String>) = env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    let dump_locals = flags.iter().any(|flag| flag == "--locals");
+
+    if let Some(filename) = args.first() {
+        let mut file = File::open(filename)?;
 
         let mut data = Vec::new();
         file.read_to_end(&mut data)?;
 
-        let mut ls = state::new_lua_state();
-        ls.register("print", print);
-        ls.load(data, &filename, "b");
-        ls.call(0, 0);
+        if let Err(err) = run(data, filename, dump_locals) {
+            eprintln!("lua: {}", err);
+            if let Some(traceback) = err.traceback() {
+                eprintln!("{}", traceback);
+            }
+            process::exit(1);
+        }
     } else {
         println!("need to specify a file!");
     }
     Ok(())
 }
 
-fn print(ls: &LuaAPI) -> usize {
-    let nargs = ls.get_top();
-    for i in 1..(nargs + 1) {
-        if ls.is_boolean(i) {
-            print!("{}", ls.to_boolean(i));
-        } else if ls.is_string(i) {
-            print!("{}", ls.to_string(i));
-        } else {
-            print!("{}", ls.type_name(ls.type_id(i)));
-        }
-        if i < nargs {
-            print!("\t")
-        }
-    }
-    println!();
-    return 0;
+fn run(chunk: Vec<u8>, chunk_name: &str, dump_locals: bool) -> LuaResult<()> {
+    let mut ls = state::new_lua_state();
+    ls.set_dump_locals(dump_locals);
+    ls.set_quiet_panics(true); // the error message reports them
+    stdlib::open_libs(&mut ls)?;
+    ls.load(chunk, chunk_name, "b");
+    ls.call(0, 0)?;
+    ls.close() // a failing finalizer is reported like any error
 }
@@ -5,10 +5,20 @@ mod lua_value;
 mod arith_ops;
 mod cmp_ops;
 mod math;
+mod number;
 mod lua_table;
+mod lua_string;
+mod lua_error;
+mod debug;
+mod gc;
+mod tm;
 
-use crate::binary::chunk::Prototype;
 pub use self::lua_state::LuaState;
+pub use self::debug::DebugInfo;
+pub use self::gc::GcBudget;
+pub use self::lua_error::{LuaError, LuaResult};
+pub use self::lua_string::{LuaString, StringTable};
+pub use self::math::float_to_integer;
 
 pub fn new_lua_state() -> LuaState {
     LuaState::new()
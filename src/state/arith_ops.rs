@@ -1,7 +1,8 @@
 use super::lua_value::LuaValue;
+use crate::api::consts::*;
 
 fn iadd(a: i64, b: i64) -> i64 {
-    a + b
+    a.wrapping_add(b)
 }
 
 fn fadd(a: f64, b: f64) -> f64 {
@@ -9,7 +10,7 @@ fn fadd(a: f64, b: f64) -> f64 {
 }
 
 fn isub(a: i64, b: i64) -> i64 {
-    a - b
+    a.wrapping_sub(b)
 }
 
 fn fsub(a: f64, b: f64) -> f64 {
@@ -17,7 +18,7 @@ fn fsub(a: f64, b: f64) -> f64 {
 }
 
 fn imul(a: i64, b: i64) -> i64 {
-    a * b
+    a.wrapping_mul(b)
 }
 
 fn fmul(a: f64, b: f64) -> f64 {
@@ -69,7 +70,7 @@ fn shr(a: i64, b: i64) -> i64 {
 }
 
 fn iunm(a: i64, _: i64) -> i64 {
-    -a
+    a.wrapping_neg()
 }
 
 fn funm(a: f64, _: f64) -> f64 {
@@ -80,55 +81,212 @@ fn bnot(a: i64, _: i64) -> i64 {
     !a
 }
 
-fn inone(_: i64, _: i64) -> i64 {
-    0
-}
-fn fnone(_: f64, _: f64) -> f64 {
-    0.0
-}
-
-pub const OPS: &'static [(fn(i64, i64) -> i64, fn(f64, f64) -> f64)] = &[
-    (iadd, fadd),
-    (isub, fsub),
-    (imul, fmul),
-    (imod, fmod),
-    (inone, pow),
-    (inone, div),
-    (iidiv, fidiv),
-    (band, fnone),
-    (bor, fnone),
-    (bxor, fnone),
-    (shl, fnone),
-    (shr, fnone),
-    (iunm, funm),
-    (bnot, fnone),
+type IntOp = fn(i64, i64) -> i64;
+type FloatOp = fn(f64, f64) -> f64;
+
+pub const OPS: &[(Option<IntOp>, Option<FloatOp>)] = &[
+    (Some(iadd), Some(fadd)),
+    (Some(isub), Some(fsub)),
+    (Some(imul), Some(fmul)),
+    (Some(imod), Some(fmod)),
+    (None, Some(pow)),
+    (None, Some(div)),
+    (Some(iidiv), Some(fidiv)),
+    (Some(band), None),
+    (Some(bor), None),
+    (Some(bxor), None),
+    (Some(shl), None),
+    (Some(shr), None),
+    (Some(iunm), Some(funm)),
+    (Some(bnot), None),
 ];
 
-pub fn arith(a: &LuaValue, b: &LuaValue, op: u8) -> Option<LuaValue> {
-    let iop = OPS[op as usize].0;
-    let fop = OPS[op as usize].1;
-    if fop == fnone {
-        // bitwise
-        if let Some(x) = a.to_integer() {
-            if let Some(y) = b.to_integer() {
-                return Some(LuaValue::Integer(iop(x, y)));
+pub enum ArithError {
+    NotNumber,
+    NoIntegerRep,
+    DivideByZero(&'static str),
+}
+
+pub fn arith(a: &LuaValue, b: &LuaValue, op: u8) -> Result<LuaValue, ArithError> {
+    // strings are converted first, so integer strings stay integers
+    let a = &a.to_numeric().ok_or(ArithError::NotNumber)?;
+    let b = &b.to_numeric().ok_or(ArithError::NotNumber)?;
+    match OPS[op as usize] {
+        (Some(iop), None) => {
+            // bitwise
+            let x = a.to_integer().ok_or(ArithError::NoIntegerRep)?;
+            let y = b.to_integer().ok_or(ArithError::NoIntegerRep)?;
+            Ok(LuaValue::Integer(iop(x, y)))
+        }
+        (iop, Some(fop)) => {
+            // arith
+            if let Some(iop) = iop {
+                // add,sub,mul,mod,idiv,unm
+                if let (LuaValue::Integer(x), LuaValue::Integer(y)) = (a, b) {
+                    if *y == 0 {
+                        match op {
+                            LUA_OPMOD => return Err(ArithError::DivideByZero("n%0")),
+                            LUA_OPIDIV => return Err(ArithError::DivideByZero("n//0")),
+                            _ => (),
+                        }
+                    }
+                    return Ok(LuaValue::Integer(iop(*x, *y)));
+                }
             }
+            let x = a.to_number().ok_or(ArithError::NotNumber)?;
+            let y = b.to_number().ok_or(ArithError::NotNumber)?;
+            Ok(LuaValue::Number(fop(x, y)))
+        }
+        (None, None) => Err(ArithError::NotNumber),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EDGES: [i64; 15] = [
+        i64::MIN, i64::MIN + 1, -65, -64, -63, -2, -1, 0, 1, 2, 3, 63, 64, i64::MAX - 1, i64::MAX,
+    ];
+
+    fn int_arith(a: i64, b: i64, op: u8) -> Result<i64, &'static str> {
+        match arith(&LuaValue::Integer(a), &LuaValue::Integer(b), op) {
+            Ok(LuaValue::Integer(r)) => Ok(r),
+            Ok(v) => panic!("{} op {} {}: not an integer: {:?}", a, op, b, v),
+            Err(ArithError::DivideByZero(what)) => Err(what),
+            Err(_) => panic!("{} op {} {}: unexpected error", a, op, b),
         }
-    } else {
-        // arith
-        if iop != inone {
-            // add,sub,mul,mod,idiv,unm
-            if let LuaValue::Integer(x) = a {
-                if let LuaValue::Integer(y) = b {
-                    return Some(LuaValue::Integer(iop(*x, *y)));
+    }
+
+    // the results are computed exactly in i128 and then wrapped, the way
+    // Lua defines them on two's complement integers
+    fn wrap(r: i128) -> i64 {
+        r as i64
+    }
+
+    fn floor_div(a: i64, b: i64) -> i64 {
+        let (a, b) = (a as i128, b as i128);
+        let q = a / b;
+        wrap(if (a % b != 0) && ((a < 0) != (b < 0)) { q - 1 } else { q })
+    }
+
+    fn floor_mod(a: i64, b: i64) -> i64 {
+        let (a, b) = (a as i128, b as i128);
+        let r = a % b;
+        wrap(if r != 0 && (r < 0) != (b < 0) { r + b } else { r })
+    }
+
+    fn shift_left(a: i64, n: i64) -> i64 {
+        let x = a as u64 as u128;
+        let r = if n <= -64 || n >= 64 {
+            0
+        } else if n >= 0 {
+            x << n
+        } else {
+            x >> -n
+        };
+        r as u64 as i64
+    }
+
+    // a fixed pseudo-random sequence of operands: full-range values, small
+    // values and values next to the edges, so every case gets its share
+    fn operands(count: usize) -> Vec<i64> {
+        let mut seed: u64 = 12345;
+        let mut next = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            seed
+        };
+        (0..count)
+            .map(|_| {
+                let r = next();
+                match r % 4 {
+                    0 => (next() >> 1) as i64 ^ (r as i64 >> 63),
+                    1 => (r >> 40) as i64 % 200 - 100,
+                    2 => EDGES[(r >> 33) as usize % EDGES.len()].wrapping_add((r >> 60) as i64 - 8),
+                    _ => next() as i64,
                 }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_operands_match_i128() {
+        let ops = operands(300);
+        for &a in ops.iter() {
+            for &b in ops.iter() {
+                let (x, y) = (a as i128, b as i128);
+                assert_eq!(int_arith(a, b, LUA_OPADD), Ok(wrap(x + y)), "{} + {}", a, b);
+                assert_eq!(int_arith(a, b, LUA_OPSUB), Ok(wrap(x - y)), "{} - {}", a, b);
+                assert_eq!(int_arith(a, b, LUA_OPMUL), Ok(wrap(x * y)), "{} * {}", a, b);
+                if b == 0 {
+                    assert_eq!(int_arith(a, b, LUA_OPIDIV), Err("n//0"));
+                    assert_eq!(int_arith(a, b, LUA_OPMOD), Err("n%0"));
+                } else {
+                    assert_eq!(int_arith(a, b, LUA_OPIDIV), Ok(floor_div(a, b)), "{} // {}", a, b);
+                    assert_eq!(int_arith(a, b, LUA_OPMOD), Ok(floor_mod(a, b)), "{} % {}", a, b);
+                }
+                let n = b % 70; // shift counts around the width
+                assert_eq!(int_arith(a, n, LUA_OPSHL), Ok(shift_left(a, n)), "{} << {}", a, n);
+                assert_eq!(int_arith(a, n, LUA_OPSHR), Ok(shift_left(a, -n)), "{} >> {}", a, n);
+            }
+            assert_eq!(int_arith(a, a, LUA_OPUNM), Ok(wrap(-(a as i128))), "-{}", a);
+        }
+    }
+
+    #[test]
+    fn wrapping_add_sub_mul() {
+        for &a in EDGES.iter() {
+            for &b in EDGES.iter() {
+                let (x, y) = (a as i128, b as i128);
+                assert_eq!(int_arith(a, b, LUA_OPADD), Ok(wrap(x + y)), "{} + {}", a, b);
+                assert_eq!(int_arith(a, b, LUA_OPSUB), Ok(wrap(x - y)), "{} - {}", a, b);
+                assert_eq!(int_arith(a, b, LUA_OPMUL), Ok(wrap(x * y)), "{} * {}", a, b);
+            }
+            assert_eq!(int_arith(a, a, LUA_OPUNM), Ok(wrap(-(a as i128))), "-{}", a);
+        }
+    }
+
+    #[test]
+    fn floor_division_and_modulo() {
+        for &a in EDGES.iter() {
+            for &b in EDGES.iter().filter(|&&b| b != 0) {
+                assert_eq!(int_arith(a, b, LUA_OPIDIV), Ok(floor_div(a, b)), "{} // {}", a, b);
+                assert_eq!(int_arith(a, b, LUA_OPMOD), Ok(floor_mod(a, b)), "{} % {}", a, b);
             }
+            assert_eq!(int_arith(a, 0, LUA_OPIDIV), Err("n//0"));
+            assert_eq!(int_arith(a, 0, LUA_OPMOD), Err("n%0"));
         }
-        if let Some(x) = a.to_number() {
-            if let Some(y) = b.to_number() {
-                return Some(LuaValue::Number(fop(x, y)));
+        assert_eq!(int_arith(i64::MIN, -1, LUA_OPIDIV), Ok(i64::MIN));
+        assert_eq!(int_arith(i64::MIN, -1, LUA_OPMOD), Ok(0));
+        assert_eq!(int_arith(-7, 2, LUA_OPMOD), Ok(1));
+        assert_eq!(int_arith(7, -2, LUA_OPMOD), Ok(-1));
+        assert_eq!(int_arith(-7, 2, LUA_OPIDIV), Ok(-4));
+    }
+
+    #[test]
+    fn logical_shifts() {
+        for &a in EDGES.iter() {
+            for &n in EDGES.iter() {
+                assert_eq!(int_arith(a, n, LUA_OPSHL), Ok(shift_left(a, n)), "{} << {}", a, n);
+                let back = if n == i64::MIN { 0 } else { shift_left(a, -n) };
+                assert_eq!(int_arith(a, n, LUA_OPSHR), Ok(back), "{} >> {}", a, n);
             }
         }
+        assert_eq!(int_arith(-1, 1, LUA_OPSHR), Ok(i64::MAX));
+        assert_eq!(int_arith(1, 63, LUA_OPSHL), Ok(i64::MIN));
+    }
+
+    #[test]
+    fn float_operands() {
+        let f = |a: f64, b: f64, op: u8| match arith(&LuaValue::Number(a), &LuaValue::Number(b), op) {
+            Ok(LuaValue::Number(r)) => r,
+            _ => panic!("not a float"),
+        };
+        assert_eq!(f(5.5, -2.0, LUA_OPMOD), -0.5);
+        assert_eq!(f(-5.5, 2.0, LUA_OPMOD), 0.5);
+        assert_eq!(f(7.0, 0.0, LUA_OPIDIV), f64::INFINITY);
+        assert!(f(0.0, 0.0, LUA_OPMOD).is_nan());
+        assert_eq!(f(1.0, f64::INFINITY, LUA_OPMOD), 1.0);
+        assert_eq!(f(-1.0, f64::INFINITY, LUA_OPMOD), f64::INFINITY);
     }
-    None
 }
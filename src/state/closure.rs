@@ -1,22 +1,17 @@
 use crate::binary::chunk::Prototype;
 use crate::api::RustFn;
-use std::hash::{Hash, Hasher};
 use std::rc::Rc;
 use std::cell::RefCell;
 use crate::state::lua_value::LuaValue;
 
+// an upvalue cell, shared by the closures that capture the same variable
+// (and by the stack frame that owns it while the variable is still open)
+pub type Upvalue = Rc<RefCell<LuaValue>>;
+
 pub struct Closure {
     pub proto: Rc<Prototype>,//lua closure
     pub rust_fn: Option<RustFn>,//rust closure
-    pub upvalues: RefCell<Vec<RefCell<LuaValue>>>,
-    rdm: usize,
-}
-
-//TODO::?usage?
-impl Hash for Closure {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.rdm.hash(state);
-    }
+    pub upvalues: RefCell<Vec<Upvalue>>,
 }
 
 impl Closure {
@@ -25,7 +20,6 @@ impl Closure {
         Closure {
             proto: new_empty_prototype(), // TODO
             rust_fn: None,
-            rdm: super::math::random(),
             upvalues: RefCell::new(Vec::new()) //empty
         }
     }
@@ -33,36 +27,31 @@ impl Closure {
     pub fn new_lua_closure(proto: Rc<Prototype>) -> Closure {
         let len = proto.upvalues.len();
         let mut vec = Vec::new();
-        for i in 0..len {
-            vec.push(RefCell::new(LuaValue::Nil));
+        for _ in 0..len {
+            vec.push(Rc::new(RefCell::new(LuaValue::Nil)));
         }
         Closure {
             upvalues: RefCell::new(vec),
             proto,
             rust_fn: None,
-            rdm: super::math::random()
         }
     }
 
     pub fn new_rust_closure(f: RustFn,n_upvals: usize) -> Closure {
         let len = n_upvals;
         let mut vec = Vec::new();
-        for i in 0..len {
-            vec.push(RefCell::new(LuaValue::Nil));
+        for _ in 0..len {
+            vec.push(Rc::new(RefCell::new(LuaValue::Nil)));
         }
         Closure {
             proto: new_empty_prototype(), // TODO
             rust_fn: Some(f),
-            rdm: super::math::random(),
             upvalues:RefCell::new(vec)
         }
     }
 
     pub fn is_fake(&self) -> bool {
-        return match (self.proto.is_empty(),self.rust_fn) {
-            (true,None) => true,
-            _ => false
-        }
+        matches!((self.proto.is_empty(),self.rust_fn), (true,None))
     }
 }
 
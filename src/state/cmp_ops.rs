@@ -1,59 +1,168 @@
 use super::lua_value::LuaValue;
+use super::math::float_to_integer;
 use crate::api::consts::*;
 
 pub fn compare(a: &LuaValue, b: &LuaValue, op: u8) -> Option<bool> {
     match op {
-        LUA_OPEQ => Some(eq(a, b)),
+        LUA_OPEQ => Some(a == b),
         LUA_OPLT => lt(a, b),
         LUA_OPLE => le(a, b),
         _ => None,
     }
 }
 
-macro_rules! cmp {
-    ($a:ident $op:tt $b:ident) => {
-        match $a {
-            LuaValue::Str(x) => match $b {
-                LuaValue::Str(y) => Some(x $op y),
-                _ => None,
-            },
-            LuaValue::Integer(x) => match $b {
-                LuaValue::Integer(y) => Some(x $op y),
-                LuaValue::Number(y) => Some((*x as f64) $op *y),
-                _ => None,
-            },
-            LuaValue::Number(x) => match $b {
-                LuaValue::Number(y) => Some(x $op y),
-                LuaValue::Integer(y) => Some(*x $op (*y as f64)),
-                _ => None,
-            },
-            _ => None,
-        }
+fn lt(a: &LuaValue, b: &LuaValue) -> Option<bool> {
+    match (a, b) {
+        (LuaValue::Integer(x), LuaValue::Integer(y)) => Some(x < y),
+        (LuaValue::Number(x), LuaValue::Number(y)) => Some(x < y),
+        (LuaValue::Integer(x), LuaValue::Number(y)) => Some(lt_int_float(*x, *y)),
+        (LuaValue::Number(x), LuaValue::Integer(y)) => Some(lt_float_int(*x, *y)),
+        (LuaValue::Str(x), LuaValue::Str(y)) => Some(x.as_bytes() < y.as_bytes()),
+        _ => None,
     }
 }
 
-fn eq(a: &LuaValue, b: &LuaValue) -> bool {
-    if let Some(x) = cmp!(a == b) {
-        x
-    } else {
-        match a {
-            LuaValue::Nil => match b {
-                LuaValue::Nil => true,
-                _ => false,
-            },
-            LuaValue::Boolean(x) => match b {
-                LuaValue::Boolean(y) => x == y,
-                _ => false,
-            },
-            _ => false,
-        }
+fn le(a: &LuaValue, b: &LuaValue) -> Option<bool> {
+    match (a, b) {
+        (LuaValue::Integer(x), LuaValue::Integer(y)) => Some(x <= y),
+        (LuaValue::Number(x), LuaValue::Number(y)) => Some(x <= y),
+        (LuaValue::Integer(x), LuaValue::Number(y)) => Some(le_int_float(*x, *y)),
+        (LuaValue::Number(x), LuaValue::Integer(y)) => Some(le_float_int(*x, *y)),
+        (LuaValue::Str(x), LuaValue::Str(y)) => Some(x.as_bytes() <= y.as_bytes()),
+        _ => None,
     }
 }
 
-fn lt(a: &LuaValue, b: &LuaValue) -> Option<bool> {
-    cmp!(a < b)
+/*
+** Mixed integer/float comparisons never convert the integer to a float
+** (that loses precision above 2^53). Instead the float is rounded to an
+** integer in the direction that keeps the comparison exact; a float that
+** can't be represented is out of the integer range (or NaN), so only its
+** sign decides the result (and NaN compares false both ways).
+*/
+
+pub fn eq_int_float(i: i64, f: f64) -> bool {
+    float_to_integer(f) == Some(i)
 }
 
-fn le(a: &LuaValue, b: &LuaValue) -> Option<bool> {
-    cmp!(a <= b)
+// i < f <=> i < ceil(f)
+fn lt_int_float(i: i64, f: f64) -> bool {
+    match float_to_integer(f.ceil()) {
+        Some(fi) => i < fi,
+        None => f > 0.0,
+    }
+}
+
+// i <= f <=> i <= floor(f)
+fn le_int_float(i: i64, f: f64) -> bool {
+    match float_to_integer(f.floor()) {
+        Some(fi) => i <= fi,
+        None => f > 0.0,
+    }
+}
+
+// f < i <=> floor(f) < i
+fn lt_float_int(f: f64, i: i64) -> bool {
+    match float_to_integer(f.floor()) {
+        Some(fi) => fi < i,
+        None => f < 0.0,
+    }
+}
+
+// f <= i <=> ceil(f) <= i
+fn le_float_int(f: f64, i: i64) -> bool {
+    match float_to_integer(f.ceil()) {
+        Some(fi) => fi <= i,
+        None => f < 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering;
+
+    const TWO_53: i64 = 1 << 53;
+    const TWO_63: f64 = 9223372036854775808.0;
+
+    const INTS: [i64; 12] = [
+        0, 1, -1, TWO_53 - 1, TWO_53, TWO_53 + 1, -TWO_53 - 1, i64::MAX, i64::MAX - 1, i64::MIN, i64::MIN + 1, 42,
+    ];
+
+    const FLOATS: [f64; 18] = [
+        0.0, -0.0, 0.5, -0.5, 1.0, (TWO_53 - 1) as f64, TWO_53 as f64, (TWO_53 + 2) as f64, -(TWO_53 as f64),
+        TWO_63, -TWO_63, TWO_63 - 1024.0, 1e300, -1e300, f64::INFINITY, f64::NEG_INFINITY, f64::NAN, 42.000001,
+    ];
+
+    // the exact order between i and f, None if f is NaN
+    fn exact_cmp(i: i64, f: f64) -> Option<Ordering> {
+        if f.is_nan() {
+            return None;
+        }
+        if f >= 1e30 {
+            return Some(Ordering::Less);
+        }
+        if f <= -1e30 {
+            return Some(Ordering::Greater);
+        }
+        // |f| < 2^100, so floor(f) is exact in an i128
+        let fl = f.floor();
+        match (i as i128).cmp(&(fl as i128)) {
+            Ordering::Equal if fl != f => Some(Ordering::Less),
+            ord => Some(ord),
+        }
+    }
+
+    #[test]
+    fn mixed_comparisons_are_exact() {
+        for &i in INTS.iter() {
+            for &f in FLOATS.iter() {
+                let ord = exact_cmp(i, f);
+                let (iv, fv) = (LuaValue::Integer(i), LuaValue::Number(f));
+                let eq = ord == Some(Ordering::Equal);
+                let lt = ord == Some(Ordering::Less);
+                let gt = ord == Some(Ordering::Greater);
+                assert_eq!(eq_int_float(i, f), eq, "{} == {}", i, f);
+                assert_eq!(compare(&iv, &fv, LUA_OPEQ), Some(eq), "{} == {}", i, f);
+                assert_eq!(compare(&fv, &iv, LUA_OPEQ), Some(eq), "{} == {}", f, i);
+                assert_eq!(compare(&iv, &fv, LUA_OPLT), Some(lt), "{} < {}", i, f);
+                assert_eq!(compare(&iv, &fv, LUA_OPLE), Some(lt || eq), "{} <= {}", i, f);
+                assert_eq!(compare(&fv, &iv, LUA_OPLT), Some(gt), "{} < {}", f, i);
+                assert_eq!(compare(&fv, &iv, LUA_OPLE), Some(gt || eq), "{} <= {}", f, i);
+            }
+        }
+    }
+
+    #[test]
+    fn values_the_float_conversion_gets_wrong() {
+        // 2^53 + 1 rounds to 2^53 as a float
+        assert!(lt_float_int(TWO_53 as f64, TWO_53 + 1));
+        assert!(!eq_int_float(TWO_53 + 1, TWO_53 as f64));
+        // i64::MAX rounds up to 2^63, which is out of the integer range
+        assert!(lt_int_float(i64::MAX, TWO_63));
+        assert!(!le_float_int(TWO_63, i64::MAX));
+        assert!(!eq_int_float(i64::MAX, TWO_63));
+        // -2^63 is exactly MININTEGER
+        assert!(eq_int_float(i64::MIN, -TWO_63));
+        assert!(le_int_float(i64::MIN, -TWO_63));
+        assert!(!lt_int_float(i64::MIN, -TWO_63));
+    }
+
+    #[test]
+    fn nan_compares_false_both_ways() {
+        for &i in INTS.iter() {
+            assert!(!lt_int_float(i, f64::NAN));
+            assert!(!le_int_float(i, f64::NAN));
+            assert!(!lt_float_int(f64::NAN, i));
+            assert!(!le_float_int(f64::NAN, i));
+            assert!(!eq_int_float(i, f64::NAN));
+        }
+    }
+
+    #[test]
+    fn integral_floats_are_equal_keys() {
+        assert!(LuaValue::Integer(1) == LuaValue::Number(1.0));
+        assert!(LuaValue::Number(-0.0) == LuaValue::Integer(0));
+        assert!(LuaValue::Integer(TWO_53 + 1) != LuaValue::Number(TWO_53 as f64));
+    }
 }
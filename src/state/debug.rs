@@ -0,0 +1,488 @@
+use super::lua_stack::LuaStack;
+use super::lua_value::LuaValue;
+use super::number::float_to_str;
+use crate::api::consts::LUA_REGISTRYINDEX;
+use crate::binary::chunk::{Constant, Prototype};
+use crate::vm::instructions::Instruction;
+use crate::vm::opcodes::*;
+
+/* size of the "short source" shown in messages */
+const LUA_IDSIZE: usize = 60;
+
+/* size of the first and second parts of a long traceback */
+const LEVELS1: usize = 10;
+const LEVELS2: usize = 11;
+
+/* limits of the values shown in a dump of the variables of a frame */
+const DUMP_DEPTH: usize = 2; // nesting of tables
+const DUMP_FIELDS: usize = 8; // fields shown per table
+const DUMP_STRLEN: usize = 40; // characters shown per string
+
+// what is known about an active function, like lua_Debug
+#[derive(Clone, Debug)]
+pub struct DebugInfo {
+    pub what: &'static str, // "Lua", "Rust" or "main"
+    pub source: String,
+    pub short_src: String,
+    pub current_line: Option<u32>,
+    pub line_defined: u32,
+    pub last_line_defined: u32,
+    pub name: Option<String>,
+    // "global", "local", "method", "field", "upvalue", "for iterator",
+    // "metamethod" or "" when the name is unknown
+    pub name_what: &'static str,
+    // the name of a global (or a field of a global table) holding the function
+    pub global_name: Option<String>,
+    pub is_tail_call: bool,
+    // the live locals and the upvalues, only filled in for error reports
+    pub locals: Vec<Variable>,
+    pub upvalues: Vec<Variable>,
+}
+
+// a variable of an active function and its value, rendered for display
+#[derive(Clone, Debug)]
+pub struct Variable {
+    pub name: String,
+    pub value: String,
+}
+
+impl DebugInfo {
+    pub fn new(frame: &LuaStack, is_tail_call: bool) -> DebugInfo {
+        let c = &frame.closure;
+        let (what, source) = if c.rust_fn.is_some() {
+            ("Rust", String::from("=[C]"))
+        } else {
+            let what = if c.proto.line_defined == 0 { "main" } else { "Lua" };
+            (what, c.proto.source.clone().unwrap_or_else(|| String::from("=?")))
+        };
+        DebugInfo {
+            what,
+            short_src: chunk_id(&source),
+            source,
+            current_line: current_line(frame),
+            line_defined: c.proto.line_defined,
+            last_line_defined: c.proto.last_line_defined,
+            name: None,
+            name_what: "",
+            global_name: None,
+            is_tail_call,
+            locals: Vec::new(),
+            upvalues: Vec::new(),
+        }
+    }
+
+    // how a traceback refers to the function
+    fn func_name(&self) -> String {
+        if let Some(name) = &self.global_name {
+            format!("function '{}'", name)
+        } else if let Some(name) = &self.name {
+            format!("{} '{}'", self.name_what, name)
+        } else if self.what == "main" {
+            String::from("main chunk")
+        } else if self.what == "Lua" {
+            format!("function <{}:{}>", self.short_src, self.line_defined)
+        } else {
+            String::from("?")
+        }
+    }
+}
+
+// "stack traceback:" followed by a line per frame, like luaL_traceback
+pub fn traceback_text(frames: &[DebugInfo]) -> String {
+    let mut text = String::from("stack traceback:");
+    let n = frames.len();
+    for (level, ar) in frames.iter().enumerate() {
+        if n > LEVELS1 + LEVELS2 && level >= LEVELS1 && level < n - LEVELS2 {
+            if level == LEVELS1 {
+                text.push_str("\n\t..."); // too many levels, skip to the last ones
+            }
+            continue;
+        }
+        text.push_str(&format!("\n\t{}:", ar.short_src));
+        if let Some(line) = ar.current_line {
+            text.push_str(&format!("{}:", line));
+        }
+        text.push_str(&format!(" in {}", ar.func_name()));
+        for var in &ar.locals {
+            text.push_str(&format!("\n\t\tlocal {} = {}", var.name, var.value));
+        }
+        for var in &ar.upvalues {
+            text.push_str(&format!("\n\t\tupvalue {} = {}", var.name, var.value));
+        }
+        if ar.is_tail_call {
+            text.push_str("\n\t(...tail calls...)");
+        }
+    }
+    text
+}
+
+// the locals live at the current pc of a Lua frame, with their registers
+pub fn frame_locals(frame: &LuaStack) -> Vec<Variable> {
+    let c = &frame.closure;
+    if c.rust_fn.is_some() || c.is_fake() || frame.pc <= 0 {
+        return Vec::new();
+    }
+    let pc = frame.pc as usize - 1;
+    c.proto
+        .loc_vars
+        .iter()
+        .take_while(|var| var.start_pc as usize <= pc)
+        .filter(|var| pc < var.end_pc as usize)
+        .enumerate()
+        .map(|(reg, var)| Variable {
+            name: var.var_name.clone(),
+            value: render_value(&frame.get(reg as isize + 1), DUMP_DEPTH),
+        })
+        .collect()
+}
+
+pub fn frame_upvalues(frame: &LuaStack) -> Vec<Variable> {
+    let c = &frame.closure;
+    if c.is_fake() {
+        return Vec::new();
+    }
+    let upvals = c.upvalues.borrow();
+    upvals
+        .iter()
+        .enumerate()
+        .map(|(i, uv)| Variable {
+            name: upval_name(&c.proto, i as isize),
+            value: render_value(&uv.borrow(), DUMP_DEPTH),
+        })
+        .collect()
+}
+
+// a short, printable form of a value; tables show at most DUMP_FIELDS
+// fields and nest 'depth' levels
+fn render_value(val: &LuaValue, depth: usize) -> String {
+    match val {
+        LuaValue::Nil => String::from("nil"),
+        LuaValue::Boolean(b) => b.to_string(),
+        LuaValue::Integer(i) => i.to_string(),
+        LuaValue::Number(n) => float_to_str(*n),
+        LuaValue::Str(s) => {
+            let s = s.to_string();
+            if s.chars().count() > DUMP_STRLEN {
+                let s: String = s.chars().take(DUMP_STRLEN).collect();
+                format!("{:?}...", s)
+            } else {
+                format!("{:?}", s)
+            }
+        }
+        LuaValue::Function(c) => {
+            if c.rust_fn.is_some() {
+                String::from("function [C]")
+            } else {
+                let source = c.proto.source.as_deref().unwrap_or("=?");
+                format!("function <{}:{}>", chunk_id(source), c.proto.line_defined)
+            }
+        }
+        LuaValue::Table(t) => {
+            let t = match t.try_borrow() {
+                Ok(t) if depth > 0 => t,
+                _ => return String::from("{...}"),
+            };
+            let mut fields: Vec<String> = t.arr.iter().map(|v| render_value(v, depth - 1)).collect();
+            let mut rest: Vec<String> = t
+                .map
+                .iter()
+                .filter(|(_, v)| !v.is_nil())
+                .map(|(k, v)| format!("{} = {}", render_key(k, depth - 1), render_value(v, depth - 1)))
+                .collect();
+            rest.sort(); // hash order is not stable
+            fields.append(&mut rest);
+            let more = fields.len() > DUMP_FIELDS;
+            fields.truncate(DUMP_FIELDS);
+            if more {
+                fields.push(String::from("..."));
+            }
+            format!("{{{}}}", fields.join(", "))
+        }
+    }
+}
+
+fn render_key(key: &LuaValue, depth: usize) -> String {
+    match key {
+        LuaValue::Str(s) if s.to_str().is_some_and(is_name) => s.to_string(),
+        _ => format!("[{}]", render_value(key, depth)),
+    }
+}
+
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// the line being executed by a Lua frame, None for Rust functions and
+// for chunks without line information
+pub fn current_line(frame: &LuaStack) -> Option<u32> {
+    let c = &frame.closure;
+    if c.rust_fn.is_some() || c.is_fake() || frame.pc <= 0 {
+        return None;
+    }
+    c.proto.line_info.get(frame.pc as usize - 1).copied()
+}
+
+// the printable name of a chunk, like luaO_chunkid:
+// "=name" -> name, "@file" -> file, other sources -> [string "source"]
+pub fn chunk_id(source: &str) -> String {
+    const RETS: &str = "...";
+    if let Some(name) = source.strip_prefix('=') {
+        // 'literal' source, truncated if too long
+        name.chars().take(LUA_IDSIZE - 1).collect()
+    } else if let Some(file) = source.strip_prefix('@') {
+        // file name, keep its end if too long
+        let n = file.chars().count();
+        if n < LUA_IDSIZE {
+            file.to_string()
+        } else {
+            let keep = LUA_IDSIZE - 1 - RETS.len();
+            let tail: String = file.chars().skip(n - keep).collect();
+            format!("{}{}", RETS, tail)
+        }
+    } else {
+        // string source, show its first line
+        let max = LUA_IDSIZE - "[string \"...\"]".len() - 1;
+        let line = source.split('\n').next().unwrap_or("");
+        if line.len() == source.len() && source.chars().count() < max {
+            format!("[string \"{}\"]", source)
+        } else {
+            let first: String = line.chars().take(max).collect();
+            format!("[string \"{}{}\"]", first, RETS)
+        }
+    }
+}
+
+/*
+** Variable names for error messages, found by symbolic execution of the
+** function's code like ldebug.c: a register is named after the local that
+** lives in it or, for temporaries, after the instruction that last set it.
+*/
+
+// " (kind 'name')" for an operand of the instruction being executed, if it
+// still holds the offending value 'v'; 'operand' selects among several
+// (the two sides of an arithmetic operation, the values of a concat)
+pub fn var_info(frame: &LuaStack, v: &LuaValue, operand: usize) -> String {
+    let c = &frame.closure;
+    if c.rust_fn.is_some() || c.is_fake() || frame.pc <= 0 {
+        return String::new();
+    }
+    let proto = &c.proto;
+    let pc = frame.pc as usize - 1;
+    let i = proto.code[pc];
+    let (a, b, c) = i.abc();
+
+    let found = match i.opcode() {
+        OP_ADD..=OP_SHR => {
+            let rk = if operand == 0 { b } else { c };
+            reg_name(frame, v, pc, rk)
+        }
+        OP_UNM | OP_BNOT | OP_LEN | OP_GETTABLE | OP_SELF => reg_name(frame, v, pc, b),
+        OP_CONCAT => reg_name(frame, v, pc, b + operand as isize),
+        OP_SETTABLE | OP_CALL | OP_TAILCALL => reg_name(frame, v, pc, a),
+        OP_GETTABUP => upval_value_name(frame, v, b),
+        OP_SETTABUP => upval_value_name(frame, v, a),
+        _ => None,
+    };
+    match found {
+        Some((kind, name)) => format!(" ({} '{}')", kind, name),
+        None => String::new(),
+    }
+}
+
+fn reg_name(frame: &LuaStack, v: &LuaValue, pc: usize, reg: isize) -> Option<(&'static str, String)> {
+    if reg > 0xFF || frame.get(reg + 1) != *v {
+        return None; // a constant, or the value didn't come from there
+    }
+    get_obj_name(&frame.closure.proto, pc, reg)
+}
+
+fn upval_value_name(frame: &LuaStack, v: &LuaValue, uv: isize) -> Option<(&'static str, String)> {
+    if frame.get(LUA_REGISTRYINDEX - uv - 1) != *v {
+        return None;
+    }
+    Some(("upvalue", upval_name(&frame.closure.proto, uv)))
+}
+
+// the name of the function called by the frame's current instruction,
+// like funcnamefromcode
+pub fn func_name_from_code(frame: &LuaStack) -> Option<(&'static str, String)> {
+    let c = &frame.closure;
+    if c.rust_fn.is_some() || c.is_fake() || frame.pc <= 0 {
+        return None;
+    }
+    let p = &c.proto;
+    let pc = frame.pc as usize - 1;
+    let i = p.code[pc];
+    let tm = match i.opcode() {
+        OP_CALL | OP_TAILCALL => return get_obj_name(p, pc, i.abc().0),
+        OP_TFORCALL => return Some(("for iterator", String::from("for iterator"))),
+        // other instructions can do calls through metamethods
+        OP_SELF | OP_GETTABUP | OP_GETTABLE => "__index",
+        OP_SETTABUP | OP_SETTABLE => "__newindex",
+        OP_ADD => "__add",
+        OP_SUB => "__sub",
+        OP_MUL => "__mul",
+        OP_MOD => "__mod",
+        OP_POW => "__pow",
+        OP_DIV => "__div",
+        OP_IDIV => "__idiv",
+        OP_BAND => "__band",
+        OP_BOR => "__bor",
+        OP_BXOR => "__bxor",
+        OP_SHL => "__shl",
+        OP_SHR => "__shr",
+        OP_UNM => "__unm",
+        OP_BNOT => "__bnot",
+        OP_LEN => "__len",
+        OP_CONCAT => "__concat",
+        OP_EQ => "__eq",
+        OP_LT => "__lt",
+        OP_LE => "__le",
+        _ => return None,
+    };
+    Some(("metamethod", String::from(tm)))
+}
+
+// the kind and name of the value in register 'reg' at 'lastpc'
+fn get_obj_name(p: &Prototype, lastpc: usize, reg: isize) -> Option<(&'static str, String)> {
+    if let Some(name) = get_local_name(p, reg + 1, lastpc) {
+        return Some(("local", name.to_string()));
+    }
+
+    // else try symbolic execution
+    let pc = find_set_reg(p, lastpc, reg)?;
+    let i = p.code[pc];
+    let (a, b, c) = i.abc();
+    match i.opcode() {
+        OP_MOVE if b < a => get_obj_name(p, pc, b), // get name for 'b'
+        OP_GETTABUP | OP_GETTABLE => {
+            // name of the indexed variable
+            let t = if i.opcode() == OP_GETTABLE {
+                get_local_name(p, b + 1, pc).map(|s| s.to_string())
+            } else {
+                Some(upval_name(p, b))
+            };
+            let kind = if t.as_deref() == Some("_ENV") { "global" } else { "field" };
+            Some((kind, k_name(p, pc, c)))
+        }
+        OP_GETUPVAL => Some(("upvalue", upval_name(p, b))),
+        OP_LOADK | OP_LOADKX => {
+            let bx = if i.opcode() == OP_LOADK {
+                i.a_bx().1
+            } else {
+                p.code[pc + 1].ax()
+            };
+            match &p.constants[bx as usize] {
+                Constant::Str(s) => Some(("constant", s.to_string())),
+                _ => None,
+            }
+        }
+        OP_SELF => Some(("method", k_name(p, pc, c))),
+        _ => None,
+    }
+}
+
+// the name of a key: a string constant, or a register holding one
+fn k_name(p: &Prototype, pc: usize, c: isize) -> String {
+    if c > 0xFF {
+        if let Constant::Str(s) = &p.constants[(c & 0xFF) as usize] {
+            return s.to_string(); // literal constant, it is its own name
+        }
+    } else if let Some(("constant", name)) = get_obj_name(p, pc, c) {
+        return name;
+    }
+    String::from("?") // no reasonable name found
+}
+
+// the name of the n-th (1-based) local variable active at 'pc'
+fn get_local_name(p: &Prototype, mut local_number: isize, pc: usize) -> Option<&str> {
+    for var in p.loc_vars.iter().take_while(|var| var.start_pc as usize <= pc) {
+        if pc < var.end_pc as usize {
+            // is variable active?
+            local_number -= 1;
+            if local_number == 0 {
+                return Some(&var.var_name);
+            }
+        }
+    }
+    None
+}
+
+fn upval_name(p: &Prototype, uv: isize) -> String {
+    match p.upvalue_names.get(uv as usize) {
+        Some(name) => name.clone(),
+        None => String::from("?"),
+    }
+}
+
+// the last instruction before 'lastpc' that changed 'reg', None if that
+// depends on a conditional jump
+fn find_set_reg(p: &Prototype, lastpc: usize, reg: isize) -> Option<usize> {
+    let mut setreg = None; // keep last instruction that changed 'reg'
+    let mut jmptarget = 0; // any code before this address is conditional
+    // the current position sets the register, unless inside a jump
+    let filter = |pc: usize, jmptarget: usize| if pc < jmptarget { None } else { Some(pc) };
+
+    for pc in 0..lastpc {
+        let i = p.code[pc];
+        let (a, b, _) = i.abc();
+        match i.opcode() {
+            OP_LOADNIL => {
+                if a <= reg && reg <= a + b {
+                    // set registers from 'a' to 'a+b'
+                    setreg = filter(pc, jmptarget);
+                }
+            }
+            OP_TFORCALL => {
+                if reg >= a + 2 {
+                    // affect all regs above its base
+                    setreg = filter(pc, jmptarget);
+                }
+            }
+            OP_CALL | OP_TAILCALL => {
+                if reg >= a {
+                    // affect all registers above base
+                    setreg = filter(pc, jmptarget);
+                }
+            }
+            OP_JMP => {
+                let dest = (pc as isize + 1 + i.a_sbx().1) as usize;
+                // jump is forward and does not skip 'lastpc'?
+                if pc < dest && dest <= lastpc && dest > jmptarget {
+                    jmptarget = dest;
+                }
+            }
+            op => {
+                if sets_a(op) && reg == a {
+                    // any instruction that sets A
+                    setreg = filter(pc, jmptarget);
+                }
+            }
+        }
+    }
+    setreg
+}
+
+// whether the instruction writes register A (testAMode)
+fn sets_a(op: u8) -> bool {
+    !matches!(
+        op,
+        OP_SETTABUP
+            | OP_SETUPVAL
+            | OP_SETTABLE
+            | OP_JMP
+            | OP_EQ
+            | OP_LT
+            | OP_LE
+            | OP_TEST
+            | OP_RETURN
+            | OP_TFORCALL
+            | OP_SETLIST
+            | OP_EXTRAARG
+    )
+}
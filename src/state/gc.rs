@@ -0,0 +1,933 @@
+use super::closure::{Closure, Upvalue};
+use super::lua_table::LuaTable;
+use super::lua_value::LuaValue;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+/*
+** Values are reference counted, so the collector only has to find the
+** objects that are kept alive by cycles. It tracks every table and closure
+** through a weak reference and marks what is reachable from the roots.
+** An unreachable object may still be held by the host or by a Rust local
+** of the interpreter: its strong count is then larger than the number of
+** references it gets from the other unreachable objects, and everything
+** it reaches is kept too. The rest is garbage; clearing the contents of
+** those tables and closures breaks the cycles and lets Rc free them.
+**
+** A cycle is incremental and tri-color: white objects are not marked,
+** gray ones are marked but wait in 'gray' to be traversed, black ones are
+** done. The program runs between steps, so a store into a black table
+** turns it gray again and a value stored into an upvalue is marked (the
+** barriers). Objects created while marking are marked at once. Once
+** nothing is gray the roots are marked again, so values that only the
+** stack holds are not lost; marking goes on until that adds nothing. Then
+** the objects tracked at that point are swept in steps to find the white
+** ones, the candidates. The barriers keep them close to the real garbage:
+** the reference counts alone would keep every object linked during the
+** cycle safe, but as candidates to check.
+**
+** The candidates are checked in steps too: their references are counted,
+** the held ones are marked with what they reach, and the rest is freed.
+** The program runs in between, so a store into a candidate, or into a
+** weak table whose references were counted, first marks what that object
+** references, and before weak entries are cleared the candidates they
+** refer to are checked again, in case the program took one of them from
+** the table.
+**
+** A table whose metatable has a '__gc' field when it is set is kept by
+** 'finobj' until the collector finds it unreachable. It is then moved to
+** 'tobefnz' and marked again, with everything it reaches, so none of that
+** is freed before its finalizer runs. Finalizers run in the reverse order
+** the tables were marked for finalization; after that a table is a plain
+** object, freed by a later cycle unless the finalizer stored it somewhere.
+**
+** The marking does not go through the weak parts of a table ('__mode'
+** with 'k' and/or 'v'); in a table with weak keys only (an ephemeron
+** table) a value is marked once its key is. Weak references count as
+** internal ones when looking for the objects held from outside, and the
+** entries whose weak key or value ends up dead are cleared from the live
+** weak tables before the dead objects are.
+*/
+
+/* a cycle starts when the heap grows to GC_PAUSE% of its size after the
+   previous one; each step does GC_STEPMUL% of the work allocated since
+   the last step */
+const GC_PAUSE: usize = 200;
+const GC_STEPMUL: usize = 200;
+
+/* allocation debt (in bytes) that triggers a step */
+const GC_STEP_SIZE: usize = 2048;
+
+const GC_MIN_THRESHOLD: usize = 64 * 1024;
+
+/* work (in bytes) of sweeping or checking one object */
+const GC_SWEEP_COST: usize = 32;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Pause,
+    Propagate,
+    // position in the tracked objects and how many tables and closures
+    // there were when the sweep started
+    Sweep(usize, usize, usize),
+    // the candidates found by the sweep are checked and freed
+    Free(Stage),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Count(usize),   // counting the references among the candidates, from a position
+    Weak(usize),    // counting those from the live weak tables, from a position
+    Held(usize),    // marking the candidates held from outside, from a position
+    Mark,           // marking what they reach
+    Resurrect,      // marking what the tables to be finalized reach
+    Release(usize), // clearing the dead candidates, from a position
+}
+
+// the white objects found by the sweep and what the check knows of them
+#[derive(Default)]
+struct Candidates {
+    nodes: Vec<Node>,
+    seen: HashSet<usize>,
+    // references to each candidate from the others and from weak tables
+    refs: HashMap<usize, usize>,
+    // the tables with a finalizer when the check started
+    fin: HashSet<usize>,
+    // the objects whose references were kept after they were counted
+    kept: HashSet<usize>,
+}
+
+impl Candidates {
+    fn push(&mut self, node: Node) {
+        self.seen.insert(node.addr());
+        self.nodes.push(node);
+    }
+
+    // whether the candidate at 'addr' with 'strong' references has some
+    // from outside the candidates ('nodes' holds one, and 'finobj' another
+    // for tables with a finalizer)
+    fn is_held(&self, addr: usize, strong: usize) -> bool {
+        let own = 1 + self.fin.contains(&addr) as usize;
+        strong - own > self.refs.get(&addr).copied().unwrap_or(0)
+    }
+}
+
+// how much a step may do
+#[derive(Clone, Copy, Debug)]
+pub enum GcBudget {
+    Work(usize), // bytes of objects
+    Time(Duration),
+}
+
+pub struct Gc {
+    tables: Vec<Weak<RefCell<LuaTable>>>,
+    closures: Vec<Weak<Closure>>,
+    running: bool,
+    phase: Phase,
+    marker: Marker,
+    candidates: Candidates,
+    estimate: usize, // bytes in use after the last cycle
+    allocated: usize, // bytes allocated since the last cycle
+    debt: usize,      // bytes allocated since the last step
+    live: usize,      // bytes seen alive by the running sweep
+    pause: usize,
+    stepmul: usize,
+    // tables with a finalizer, in the order they got it
+    finobj: Vec<Rc<RefCell<LuaTable>>>,
+    // unreachable tables whose finalizers must run, the next one last
+    tobefnz: Vec<Rc<RefCell<LuaTable>>>,
+    stacks: usize, // bytes used by the call frames
+    limit: Option<usize>,
+    watch: Option<MemoryWatch>,
+}
+
+// a host callback told (threshold, bytes in use) each time the memory in
+// use goes above or back below one of the thresholds
+struct MemoryWatch {
+    thresholds: Vec<usize>,
+    callback: Box<dyn FnMut(usize, usize)>,
+    last: usize,
+}
+
+// an object the collector walks through
+#[derive(Clone)]
+enum Node {
+    Table(Rc<RefCell<LuaTable>>),
+    Closure(Rc<Closure>),
+    Cell(Upvalue),
+}
+
+impl Node {
+    fn from_value(val: &LuaValue) -> Option<Node> {
+        match val {
+            LuaValue::Table(t) => Some(Node::Table(t.clone())),
+            LuaValue::Function(c) => Some(Node::Closure(c.clone())),
+            _ => None,
+        }
+    }
+
+    fn addr(&self) -> usize {
+        match self {
+            Node::Table(t) => Rc::as_ptr(t) as *const u8 as usize,
+            Node::Closure(c) => Rc::as_ptr(c) as *const u8 as usize,
+            Node::Cell(uv) => Rc::as_ptr(uv) as *const u8 as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Table(t) => Rc::strong_count(t),
+            Node::Closure(c) => Rc::strong_count(c),
+            Node::Cell(uv) => Rc::strong_count(uv),
+        }
+    }
+
+    // an estimate of the memory used by the object, in bytes
+    fn size(&self) -> usize {
+        let value_size = mem::size_of::<LuaValue>();
+        match self {
+            Node::Table(t) => match t.try_borrow() {
+                Ok(t) => {
+                    let strings = t.arr.iter().chain(t.map.keys()).chain(t.map.values()).map(string_size);
+                    table_size(&t) + strings.sum::<usize>()
+                }
+                Err(_) => mem::size_of::<LuaTable>(),
+            },
+            Node::Closure(c) => {
+                let n = c.upvalues.try_borrow().map_or(0, |uvs| uvs.len());
+                mem::size_of::<Closure>() + n * mem::size_of::<Upvalue>()
+            }
+            Node::Cell(_) => value_size + 2 * mem::size_of::<usize>(),
+        }
+    }
+
+    // calls 'f' once for every strong reference the object holds
+    fn children(&self, mut f: impl FnMut(Node)) {
+        let mut value = |val: &LuaValue| {
+            if let Some(node) = Node::from_value(val) {
+                f(node);
+            }
+        };
+        match self {
+            Node::Table(t) => {
+                let t = t.borrow();
+                t.arr.iter().for_each(&mut value);
+                for (k, v) in t.map.iter() {
+                    value(k);
+                    value(v);
+                }
+                if let Some(mt) = &t.metatable {
+                    value(&LuaValue::Table(mt.clone()));
+                }
+            }
+            Node::Closure(c) => {
+                for uv in c.upvalues.borrow().iter() {
+                    f(Node::Cell(uv.clone()));
+                }
+            }
+            Node::Cell(uv) => value(&uv.borrow()),
+        }
+    }
+}
+
+// the objects marked in the running cycle (by address), the gray ones
+// and the weak tables traversed
+pub struct Marker {
+    marked: HashSet<usize>,
+    gray: Vec<Node>,
+    gray_set: HashSet<usize>,
+    weak: Vec<Rc<RefCell<LuaTable>>>,
+    weak_set: HashSet<usize>,
+    // the interned "__mode", the key of the weakness of a table
+    mode: LuaValue,
+}
+
+impl Marker {
+    fn new(mode: LuaValue) -> Marker {
+        Marker {
+            marked: HashSet::new(),
+            gray: Vec::new(),
+            gray_set: HashSet::new(),
+            weak: Vec::new(),
+            weak_set: HashSet::new(),
+            mode,
+        }
+    }
+
+    // whether the keys and the values of a table are weak, from its '__mode'
+    fn weakness(&self, t: &LuaTable) -> (bool, bool) {
+        let mode = match &t.metatable {
+            Some(mt) => mt.borrow().get(&self.mode),
+            None => return (false, false),
+        };
+        match mode {
+            LuaValue::Str(mode) => (mode.as_bytes().contains(&b'k'), mode.as_bytes().contains(&b'v')),
+            _ => (false, false),
+        }
+    }
+
+    pub fn mark_value(&mut self, val: &LuaValue) {
+        if let Some(node) = Node::from_value(val) {
+            self.mark(node);
+        }
+    }
+
+    pub fn mark_cell(&mut self, uv: &Upvalue) {
+        self.mark(Node::Cell(uv.clone()));
+    }
+
+    fn mark(&mut self, node: Node) {
+        if self.marked.insert(node.addr()) {
+            self.push_gray(node);
+        }
+    }
+
+    // makes a marked object gray, once until it is traversed
+    fn push_gray(&mut self, node: Node) {
+        if self.gray_set.insert(node.addr()) {
+            self.gray.push(node);
+        }
+    }
+
+    fn is_marked(&self, node: &Node) -> bool {
+        self.marked.contains(&node.addr())
+    }
+
+    // traverses gray objects until about 'work' bytes of them are done,
+    // returns the work done
+    fn propagate(&mut self, work: usize) -> usize {
+        let mut done = 0;
+        while done < work {
+            let node = match self.gray.pop() {
+                Some(node) => node,
+                None => break,
+            };
+            self.gray_set.remove(&node.addr());
+            let mut children = Vec::new();
+            match &node {
+                Node::Table(t) => self.traverse_table(t, &mut children),
+                _ => node.children(|child| children.push(child)),
+            }
+            for child in children {
+                self.mark(child);
+            }
+            done += node.size();
+        }
+        done
+    }
+
+    // the strong references of table 't'
+    fn traverse_table(&mut self, t: &Rc<RefCell<LuaTable>>, children: &mut Vec<Node>) {
+        let tbl = t.borrow();
+        let (weak_keys, weak_values) = self.weakness(&tbl);
+        if let Some(mt) = &tbl.metatable {
+            children.push(Node::Table(mt.clone()));
+        }
+        if (weak_keys || weak_values) && self.weak_set.insert(Rc::as_ptr(t) as *const u8 as usize) {
+            self.weak.push(t.clone());
+        }
+        if !weak_values {
+            children.extend(tbl.arr.iter().filter_map(Node::from_value));
+        }
+        for (k, v) in tbl.map.iter() {
+            if !weak_keys {
+                children.extend(Node::from_value(k));
+            }
+            if !weak_values && (!weak_keys || self.is_alive_key(k)) {
+                children.extend(Node::from_value(v));
+            }
+        }
+    }
+
+    // whether key 'k' of an ephemeron table is known to be alive
+    fn is_alive_key(&self, k: &LuaValue) -> bool {
+        Node::from_value(k).map_or(true, |node| self.is_marked(&node))
+    }
+
+    // marks the values of ephemeron tables whose keys got marked, returns
+    // whether there were any
+    fn mark_ephemerons(&mut self) -> bool {
+        let mut found = Vec::new();
+        for t in self.weak.iter() {
+            let t = t.borrow();
+            if self.weakness(&t) != (true, false) {
+                continue;
+            }
+            for (k, v) in t.map.iter() {
+                if let Some(node) = Node::from_value(v) {
+                    if !self.is_marked(&node) && self.is_alive_key(k) {
+                        found.push(node);
+                    }
+                }
+            }
+        }
+        let any = !found.is_empty();
+        found.into_iter().for_each(|node| self.mark(node));
+        any
+    }
+
+    // whether marking is done: nothing gray, no ephemeron value to add
+    fn is_complete(&mut self) -> bool {
+        self.gray.is_empty() && !self.mark_ephemerons()
+    }
+}
+
+impl Gc {
+    // 'mode' is the interned "__mode" of the state
+    pub fn new(mode: LuaValue) -> Gc {
+        Gc {
+            tables: Vec::new(),
+            closures: Vec::new(),
+            running: true,
+            phase: Phase::Pause,
+            marker: Marker::new(mode),
+            candidates: Candidates::default(),
+            estimate: 0,
+            allocated: 0,
+            debt: 0,
+            live: 0,
+            pause: GC_PAUSE,
+            stepmul: GC_STEPMUL,
+            finobj: Vec::new(),
+            tobefnz: Vec::new(),
+            stacks: 0,
+            limit: None,
+            watch: None,
+        }
+    }
+
+    // starts tracking a new table or closure, marked if the marking is
+    // under way: the remarks of the roots then never find new objects
+    pub fn track(&mut self, val: &LuaValue) {
+        let node = match Node::from_value(val) {
+            Some(node) => node,
+            None => return,
+        };
+        match &node {
+            Node::Table(t) => self.tables.push(Rc::downgrade(t)),
+            Node::Closure(c) => self.closures.push(Rc::downgrade(c)),
+            Node::Cell(_) => (),
+        }
+        self.allocate(node.size());
+        if self.phase == Phase::Propagate {
+            if let Node::Table(_) = node {
+                self.marker.marked.insert(node.addr()); // empty, so black
+            } else {
+                self.marker.mark(node);
+            }
+        }
+    }
+
+    // accounts 'bytes' allocated for strings, table parts or new objects
+    pub fn allocate(&mut self, bytes: usize) {
+        self.allocated += bytes;
+        match self.phase {
+            Phase::Pause => (),
+            Phase::Propagate => self.debt += bytes,
+            Phase::Sweep(..) | Phase::Free(_) => {
+                self.debt += bytes;
+                self.live += bytes; // not swept in this cycle
+            }
+        }
+        self.notify();
+    }
+
+    pub fn total_stacks(&self) -> usize {
+        self.stacks
+    }
+
+    // the call frames now use 'bytes'
+    pub fn set_stacks(&mut self, bytes: usize) {
+        self.stacks = bytes;
+        self.notify();
+    }
+
+    // the memory in use as far as it is accounted, in bytes: what was
+    // alive after the last cycle, what was allocated since and the frames
+    pub fn total(&self) -> usize {
+        self.estimate + self.allocated + self.stacks
+    }
+
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    // whether 'bytes' more stay under the limit
+    pub fn fits(&self, bytes: usize) -> bool {
+        self.limit.map_or(true, |limit| self.total().saturating_add(bytes) <= limit)
+    }
+
+    pub fn set_watch(&mut self, thresholds: Vec<usize>, callback: Box<dyn FnMut(usize, usize)>) {
+        let last = self.total();
+        self.watch = Some(MemoryWatch { thresholds, callback, last });
+    }
+
+    // calls the host back for the thresholds crossed since the last call
+    fn notify(&mut self) {
+        let total = self.total();
+        if let Some(watch) = &mut self.watch {
+            for &threshold in watch.thresholds.iter() {
+                if (watch.last < threshold) != (total < threshold) {
+                    (watch.callback)(threshold, total);
+                }
+            }
+            watch.last = total;
+        }
+    }
+
+    // marks table 't' for finalization, its metatable has a '__gc' field
+    pub fn check_finalizer(&mut self, t: &Rc<RefCell<LuaTable>>) {
+        if !self.finobj.iter().any(|obj| Rc::ptr_eq(obj, t)) {
+            self.finobj.push(t.clone());
+        }
+    }
+
+    // the next table whose finalizer must run
+    pub fn pending_finalizer(&mut self) -> Option<Rc<RefCell<LuaTable>>> {
+        self.tobefnz.pop()
+    }
+
+    // makes every table marked for finalization pending, reachable or not
+    pub fn separate_all(&mut self) {
+        let finobj = mem::take(&mut self.finobj);
+        self.tobefnz.splice(0..0, finobj);
+    }
+
+    // clears every object, breaking all the cycles among them
+    pub fn free_all(&mut self) {
+        self.tobefnz.clear();
+        let mut contents = Vec::new();
+        for t in self.tables.drain(..).filter_map(|w| w.upgrade()) {
+            if let Ok(mut t) = t.try_borrow_mut() {
+                contents.push(mem::replace(&mut *t, LuaTable::new(0, 0)));
+            }
+        }
+        let mut upvals = Vec::new();
+        for c in self.closures.drain(..).filter_map(|w| w.upgrade()) {
+            if let Ok(mut uvs) = c.upvalues.try_borrow_mut() {
+                upvals.push(mem::take(&mut *uvs));
+            }
+        }
+        drop(contents);
+        drop(upvals);
+    }
+
+    // a store into table 't': a black table becomes gray again
+    pub fn barrier_back(&mut self, t: &Rc<RefCell<LuaTable>>) {
+        match self.phase {
+            Phase::Propagate => {
+                let node = Node::Table(t.clone());
+                if self.marker.is_marked(&node) {
+                    self.marker.push_gray(node);
+                }
+            }
+            Phase::Free(_) => self.keep_children(Node::Table(t.clone())),
+            _ => (),
+        }
+    }
+
+    // a store of 'val' into upvalue 'uv': the value is marked
+    pub fn barrier(&mut self, uv: &Upvalue, val: &LuaValue) {
+        match self.phase {
+            Phase::Propagate => self.marker.mark_value(val),
+            Phase::Free(_) => self.keep_children(Node::Cell(uv.clone())),
+            _ => (),
+        }
+    }
+
+    // the references of 'node' are about to change while the candidates
+    // are checked: if they were counted, what it references now is kept
+    fn keep_children(&mut self, node: Node) {
+        if let Phase::Free(Stage::Release(_)) = self.phase {
+            return; // the check is over
+        }
+        let (m, c) = (&mut self.marker, &mut self.candidates);
+        let addr = node.addr();
+        if (c.seen.contains(&addr) || m.weak_set.contains(&addr)) && c.kept.insert(addr) {
+            node.children(|child| m.mark(child));
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn set_running(&mut self, running: bool) {
+        self.running = running;
+    }
+
+    // sets the pause or the step multiplier, returns the previous value
+    pub fn set_pause(&mut self, pause: usize) -> usize {
+        mem::replace(&mut self.pause, pause)
+    }
+
+    pub fn set_stepmul(&mut self, stepmul: usize) -> usize {
+        mem::replace(&mut self.stepmul, stepmul)
+    }
+
+    // whether the allocations since the last step call for a new one
+    pub fn needs_step(&self) -> bool {
+        if !self.running {
+            return false;
+        }
+        match self.phase {
+            Phase::Pause => {
+                let threshold = (self.estimate / 100 * self.pause).max(GC_MIN_THRESHOLD);
+                self.estimate + self.allocated >= threshold
+            }
+            _ => self.debt >= GC_STEP_SIZE,
+        }
+    }
+
+    // the work that pays the current debt
+    pub fn debt_work(&self) -> usize {
+        self.debt.max(GC_STEP_SIZE) / 100 * self.stepmul
+    }
+
+    // runs the collector within the budget; 'mark_roots' marks the roots,
+    // it is called when a cycle starts and again each time nothing is gray.
+    // Returns true if a cycle was finished.
+    pub fn step(&mut self, budget: GcBudget, mark_roots: &mut dyn FnMut(&mut Marker)) -> bool {
+        self.debt = 0;
+        let start = Instant::now();
+        let mut work = 0;
+        loop {
+            let over = match budget {
+                GcBudget::Work(limit) => work >= limit,
+                GcBudget::Time(limit) => start.elapsed() >= limit,
+            };
+            if over {
+                return false;
+            }
+            let (done, finished) = self.single_step(GC_STEP_SIZE, mark_roots);
+            if finished {
+                return true;
+            }
+            work += done.max(1);
+        }
+    }
+
+    // finishes a whole cycle from the start, abandoning the marks of one
+    // that is still marking; returns the number of objects freed
+    pub fn full_collect(&mut self, mark_roots: &mut dyn FnMut(&mut Marker)) -> usize {
+        if let Phase::Sweep(..) | Phase::Free(_) = self.phase {
+            while !self.single_step(usize::MAX, mark_roots).1 {}
+        }
+        self.phase = Phase::Pause;
+        self.marker = Marker::new(self.marker.mode.clone());
+        let before = self.tables.len() + self.closures.len();
+        while !self.single_step(usize::MAX, mark_roots).1 {}
+        self.debt = 0;
+        before - (self.tables.len() + self.closures.len())
+    }
+
+    // returns the work done and whether the cycle finished
+    fn single_step(&mut self, work: usize, mark_roots: &mut dyn FnMut(&mut Marker)) -> (usize, bool) {
+        match self.phase {
+            Phase::Pause => {
+                self.marker = Marker::new(self.marker.mode.clone());
+                mark_roots(&mut self.marker);
+                self.phase = Phase::Propagate;
+                (0, false)
+            }
+            Phase::Propagate => {
+                let done = self.marker.propagate(work);
+                if self.marker.gray.is_empty() {
+                    // the roots may have changed since they were marked
+                    mark_roots(&mut self.marker);
+                    if self.marker.is_complete() {
+                        self.live = 0;
+                        self.phase = Phase::Sweep(0, self.tables.len(), self.closures.len());
+                    }
+                }
+                (done, false)
+            }
+            Phase::Sweep(pos, ntables, nclosures) => {
+                let (pos, done) = self.sweep(pos, ntables, nclosures, work);
+                self.phase = if pos < ntables + nclosures {
+                    Phase::Sweep(pos, ntables, nclosures)
+                } else {
+                    Phase::Free(Stage::Count(0))
+                };
+                (done, false)
+            }
+            Phase::Free(stage) => {
+                let (stage, done) = self.check_candidates(stage, work);
+                if let Some(stage) = stage {
+                    self.phase = Phase::Free(stage);
+                    return (done, false);
+                }
+                self.candidates = Candidates::default();
+                self.tables.retain(|w| w.strong_count() > 0);
+                self.closures.retain(|w| w.strong_count() > 0);
+                self.estimate = self.live;
+                self.allocated = 0;
+                self.notify();
+                self.marker = Marker::new(self.marker.mode.clone());
+                self.phase = Phase::Pause;
+                (done, true)
+            }
+        }
+    }
+
+    // collects the white objects among the first 'ntables' tables and
+    // 'nclosures' closures tracked, from 'pos' on
+    fn sweep(&mut self, mut pos: usize, ntables: usize, nclosures: usize, work: usize) -> (usize, usize) {
+        let mut done = 0;
+        while done < work && pos < ntables + nclosures {
+            let node = if pos < ntables {
+                self.tables[pos].upgrade().map(Node::Table)
+            } else {
+                self.closures[pos - ntables].upgrade().map(Node::Closure)
+            };
+            pos += 1;
+            if let Some(node) = node {
+                let size = node.size();
+                done += GC_SWEEP_COST;
+                if self.marker.is_marked(&node) {
+                    self.live += size;
+                } else {
+                    self.candidates.push(node);
+                }
+            }
+        }
+        (pos, done)
+    }
+
+    // does about 'work' of the check of the candidates from 'stage' on,
+    // freeing those only referenced by other candidates; returns the stage
+    // to go on from (none once it is over) and the work done
+    fn check_candidates(&mut self, stage: Stage, work: usize) -> (Option<Stage>, usize) {
+        let (m, c) = (&mut self.marker, &mut self.candidates);
+        let mut done = 0;
+        let next = match stage {
+            // add the cells the candidates lead to, counting the references
+            // among all of them
+            Stage::Count(mut i) => {
+                while done < work && i < c.nodes.len() {
+                    let node = c.nodes[i].clone();
+                    let mut found = Vec::new();
+                    node.children(|child| {
+                        *c.refs.entry(child.addr()).or_insert(0) += 1;
+                        if let Node::Cell(_) = child {
+                            if !m.is_marked(&child) && c.seen.insert(child.addr()) {
+                                found.push(child);
+                            }
+                        }
+                    });
+                    done += GC_SWEEP_COST;
+                    c.nodes.append(&mut found);
+                    i += 1;
+                }
+                if i < c.nodes.len() {
+                    Stage::Count(i)
+                } else {
+                    Stage::Weak(0)
+                }
+            }
+            // and the weak references from the live weak tables
+            Stage::Weak(mut i) => {
+                while done < work && i < m.weak.len() {
+                    let t = m.weak[i].borrow();
+                    let (weak_keys, weak_values) = m.weakness(&t);
+                    let mut count = |val: &LuaValue| {
+                        if let Some(node) = Node::from_value(val) {
+                            *c.refs.entry(node.addr()).or_insert(0) += 1;
+                        }
+                    };
+                    if weak_values {
+                        t.arr.iter().for_each(&mut count);
+                    }
+                    for (k, v) in t.map.iter() {
+                        if weak_keys {
+                            count(k);
+                        }
+                        if weak_values || (weak_keys && !m.is_alive_key(k)) {
+                            count(v);
+                        }
+                    }
+                    done += table_size(&t);
+                    i += 1;
+                }
+                if i < m.weak.len() {
+                    Stage::Weak(i)
+                } else {
+                    c.fin = self.finobj.iter().map(|t| Rc::as_ptr(t) as *const u8 as usize).collect();
+                    Stage::Held(0)
+                }
+            }
+            // objects with references from outside are live
+            Stage::Held(mut i) => {
+                while done < work && i < c.nodes.len() {
+                    let node = &c.nodes[i];
+                    if !m.is_marked(node) && c.is_held(node.addr(), node.strong_count()) {
+                        m.mark(node.clone());
+                    }
+                    done += GC_SWEEP_COST;
+                    i += 1;
+                }
+                if i < c.nodes.len() {
+                    Stage::Held(i)
+                } else {
+                    Stage::Mark
+                }
+            }
+            Stage::Mark => {
+                done = m.propagate(work);
+                if !m.gray.is_empty() || self.mark_taken() || !self.marker.is_complete() {
+                    return (Some(Stage::Mark), done);
+                }
+                // dead values leave the weak tables before the finalized
+                // objects are resurrected, dead keys after
+                self.clear_weak(false);
+
+                // separate the unreachable tables with a finalizer and
+                // resurrect them, with what they reach, until their
+                // finalizers have run
+                let (m, c) = (&mut self.marker, &self.candidates);
+                let (dead, live): (Vec<_>, Vec<_>) = mem::take(&mut self.finobj).into_iter().partition(|t| {
+                    let node = Node::Table(t.clone());
+                    c.seen.contains(&node.addr()) && !m.is_marked(&node)
+                });
+                self.finobj = live;
+                for t in dead.iter() {
+                    m.mark(Node::Table(t.clone()));
+                }
+                self.tobefnz.splice(0..0, dead);
+                Stage::Resurrect
+            }
+            Stage::Resurrect => {
+                done = m.propagate(work);
+                if !m.gray.is_empty() || self.mark_taken() || !self.marker.is_complete() {
+                    return (Some(Stage::Resurrect), done);
+                }
+                self.clear_weak(true);
+                Stage::Release(0)
+            }
+            Stage::Release(mut i) => {
+                while done < work && i < c.nodes.len() {
+                    let node = &c.nodes[i];
+                    i += 1;
+                    done += GC_SWEEP_COST;
+                    if m.is_marked(node) {
+                        if let Node::Table(_) | Node::Closure(_) = node {
+                            self.live += node.size();
+                        }
+                        continue;
+                    }
+                    // 'nodes' keeps every candidate until the end, so none
+                    // is freed by the contents dropped here
+                    match node {
+                        Node::Table(t) => {
+                            if let Ok(mut t) = t.try_borrow_mut() {
+                                drop(mem::replace(&mut *t, LuaTable::new(0, 0)));
+                            }
+                        }
+                        Node::Closure(c) => {
+                            if let Ok(mut uvs) = c.upvalues.try_borrow_mut() {
+                                drop(mem::take(&mut *uvs));
+                            }
+                        }
+                        Node::Cell(_) => (), // freed with the closures holding it
+                    }
+                }
+                if i < c.nodes.len() {
+                    Stage::Release(i)
+                } else {
+                    return (None, done);
+                }
+            }
+        };
+        (Some(next), done)
+    }
+
+    // marks the unmarked candidates a live weak table refers to that got
+    // references from outside since they were counted: the program may have
+    // taken them from the table; returns whether there were any
+    fn mark_taken(&mut self) -> bool {
+        let (m, c) = (&mut self.marker, &self.candidates);
+        let mut found = Vec::new();
+        for t in m.weak.iter() {
+            let t = t.borrow();
+            let mut check = |val: &LuaValue| {
+                if let Some(node) = Node::from_value(val) {
+                    // 'node' holds one more reference
+                    let held = c.is_held(node.addr(), node.strong_count() - 1);
+                    if c.seen.contains(&node.addr()) && !m.is_marked(&node) && held {
+                        found.push(node);
+                    }
+                }
+            };
+            t.arr.iter().for_each(&mut check);
+            for (k, v) in t.map.iter() {
+                check(k);
+                check(v);
+            }
+        }
+        let any = !found.is_empty();
+        found.into_iter().for_each(|node| m.mark(node));
+        any
+    }
+
+    // clears the entries of the live weak tables whose weak value is dead,
+    // and with 'keys' those whose weak key is; their values are no longer
+    // counted as references to the candidates, the dead keys still are
+    fn clear_weak(&mut self, keys: bool) {
+        let (m, c) = (&self.marker, &mut self.candidates);
+        let is_dead = |val: &LuaValue| {
+            Node::from_value(val).is_some_and(|node| c.seen.contains(&node.addr()) && !m.is_marked(&node))
+        };
+        let mut cleared = Vec::new();
+        for t in m.weak.iter() {
+            if let Ok(mut t) = t.try_borrow_mut() {
+                let (weak_keys, weak_values) = m.weakness(&t);
+                clear_entries(&mut t, |k, v| (keys && weak_keys && is_dead(k)) || (weak_values && is_dead(v)), &mut cleared);
+            }
+        }
+        for node in cleared.iter().filter_map(Node::from_value) {
+            if let Some(n) = c.refs.get_mut(&node.addr()) {
+                *n = n.saturating_sub(1);
+            }
+        }
+    }
+}
+
+// the bytes of a table and of its two parts, the strings excepted
+pub fn table_size(t: &LuaTable) -> usize {
+    let value_size = mem::size_of::<LuaValue>();
+    mem::size_of::<LuaTable>() + t.arr.capacity() * value_size + t.map.capacity() * 2 * value_size
+}
+
+// about the bytes a full part of a table takes to grow, it doubles
+pub fn table_growth(t: &LuaTable) -> usize {
+    let value_size = mem::size_of::<LuaValue>();
+    (t.arr.capacity() + t.map.capacity() * 2).max(4) * value_size
+}
+
+// the bytes of the text of a string value
+pub fn string_size(val: &LuaValue) -> usize {
+    match val {
+        LuaValue::Str(s) => s.len(),
+        _ => 0,
+    }
+}
+
+// clears the entries for which 'dead' holds, moving their values to
+// 'cleared'. The keys stay with nil values (dead keys), so a traversal of
+// the table can go on, and the array part keeps its size
+fn clear_entries(t: &mut LuaTable, dead: impl Fn(&LuaValue, &LuaValue) -> bool, cleared: &mut Vec<LuaValue>) {
+    let key = |i: usize| LuaValue::Integer(i as i64 + 1);
+    for (i, val) in t.arr.iter_mut().enumerate() {
+        if !val.is_nil() && dead(&key(i), val) {
+            cleared.push(mem::replace(val, LuaValue::Nil));
+        }
+    }
+    for (k, val) in t.map.iter_mut() {
+        if !val.is_nil() && dead(k, val) {
+            cleared.push(mem::replace(val, LuaValue::Nil));
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use super::debug::{self, DebugInfo};
+use super::lua_value::LuaValue;
+use crate::api::consts::*;
+use std::error::Error;
+use std::fmt;
+
+pub type LuaResult<T> = Result<T, LuaError>;
+
+// an error raised by Lua code or by the runtime, the error object can be
+// any Lua value (runtime errors use a string message)
+pub struct LuaError {
+    value: LuaValue,
+    status: u8,
+    // set once the message handler of the enclosing pcall has seen it
+    pub(super) handled: bool,
+    // the call stack when the error was raised, kept for errors that are
+    // not caught by a protected call
+    pub(super) frames: Option<Vec<DebugInfo>>,
+}
+
+impl LuaError {
+    pub fn new(value: LuaValue) -> LuaError {
+        LuaError::with_status(LUA_ERRRUN, value)
+    }
+
+    pub fn with_status(status: u8, value: LuaValue) -> LuaError {
+        LuaError {
+            value,
+            status,
+            handled: false,
+            frames: None,
+        }
+    }
+
+    // one of LUA_ERRRUN, LUA_ERRMEM, LUA_ERRGCMM or LUA_ERRERR
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    // the active functions where an uncaught error was raised, innermost first
+    pub fn frames(&self) -> Option<&[DebugInfo]> {
+        self.frames.as_deref()
+    }
+
+    pub fn traceback(&self) -> Option<String> {
+        self.frames.as_ref().map(|frames| debug::traceback_text(frames))
+    }
+
+    pub fn value(&self) -> &LuaValue {
+        &self.value
+    }
+
+    pub fn into_value(self) -> LuaValue {
+        self.value
+    }
+}
+
+impl From<String> for LuaError {
+    fn from(msg: String) -> LuaError {
+        LuaError::new(LuaValue::new_string(&msg))
+    }
+}
+
+impl From<&str> for LuaError {
+    fn from(msg: &str) -> LuaError {
+        LuaError::new(LuaValue::new_string(msg))
+    }
+}
+
+// renders the error object the way the standalone interpreter does
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value.to_str() {
+            Some(msg) => write!(f, "{}", msg),
+            None => write!(f, "(error object is a {} value)", self.value.type_name()),
+        }
+    }
+}
+
+impl fmt::Debug for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LuaError({:?})", self.value)
+    }
+}
+
+impl Error for LuaError {}
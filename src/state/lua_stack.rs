@@ -1,7 +1,10 @@
 use super::lua_value::LuaValue;
-use super::closure::Closure;
+use super::closure::{Closure, Upvalue};
+use super::lua_error::{LuaError, LuaResult};
+use super::gc::string_size;
 use crate::api::consts::*;
 use std::collections::HashMap;
+use std::mem;
 use std::rc::Rc;
 use std::cell::RefCell;
 
@@ -11,24 +14,63 @@ pub struct LuaStack {
     registry: LuaValue,
     pub closure: Rc<Closure>,
     pub varargs: Vec<LuaValue>,
-    pub openuvs: HashMap<i32,RefCell<LuaValue>>,
+    pub openuvs: HashMap<i32,Upvalue>,
     pub pc: isize,
+    // the number of results the caller expects, LUA_MULTRET for all
+    pub nresults: isize,
+    // the frame took the place of its caller's with a tail call
+    pub is_tail_call: bool,
+    // the slots counted against LUAI_MAXSTACK: the registers of a Lua
+    // function, the reserved size for Rust functions
+    pub slots: usize,
 }
 
 impl LuaStack {
     pub fn new(size: usize, registry: LuaValue, closure: Rc<Closure>) -> LuaStack {
+        let slots = if closure.rust_fn.is_some() || closure.is_fake() {
+            size
+        } else {
+            closure.proto.max_stack_size as usize
+        };
         LuaStack {
             vec: Vec::with_capacity(size),
             registry,
             closure,
             varargs: Vec::new(),
             pc: 0,
-            openuvs: HashMap::new()
+            openuvs: HashMap::new(),
+            nresults: LUA_MULTRET,
+            is_tail_call: false,
+            slots,
         }
     }
 
-    pub fn slots(&self,i: usize) -> RefCell<LuaValue> {
-        RefCell::new(self.vec[i].clone())
+    // the upvalue for register i (0-based), created on first capture; while
+    // it is open the cell is the authoritative copy of the register
+    pub fn open_upvalue(&mut self, i: usize) -> Upvalue {
+        let val = self.vec[i].clone();
+        self.openuvs
+            .entry(i as i32)
+            .or_insert_with(|| Rc::new(RefCell::new(val)))
+            .clone()
+    }
+
+    // closes the upvalues of registers >= i (0-based), the cells keep their
+    // current values and are no longer linked to the stack
+    pub fn close_upvalues(&mut self, i: usize) {
+        self.openuvs.retain(|idx, _| (*idx as usize) < i);
+    }
+
+    // the bytes used by the frame and the strings in it
+    pub fn size(&self) -> usize {
+        let value_size = mem::size_of::<LuaValue>();
+        let strings: usize = self.vec.iter().chain(self.varargs.iter()).map(string_size).sum();
+        mem::size_of::<LuaStack>() + (self.vec.capacity() + self.varargs.capacity()) * value_size + strings
+    }
+
+    // the registers and temporaries of the frame, for the collector
+    pub fn values(&self) -> &[LuaValue] {
+        &self.vec
     }
 
     pub fn top(&self) -> isize {
@@ -71,25 +113,20 @@ impl LuaStack {
         }
     }
 
-    pub fn set_top(&mut self, idx: isize) {
+    pub fn set_top(&mut self, idx: isize) -> LuaResult<()> {
         let new_top = self.abs_index(idx);
-        if new_top < 0 {
-            panic!("stack underflow!");
+        if new_top < 0 || idx <= LUA_REGISTRYINDEX {
+            return Err(LuaError::from("invalid new top"));
         }
+        self.resize(new_top as usize);
+        Ok(())
+    }
 
-        let n = self.top() - new_top;
-        if n > 0 {
-            for _ in 0..n {
-                self.pop();
-            }
-        } else if n < 0 {
-            for _ in n..0 {
-                self.push(LuaValue::Nil);
-            }
-        }
+    // pops or pushes nils until there are n values
+    pub fn resize(&mut self, n: usize) {
+        self.vec.resize(n, LuaValue::Nil);
     }
 
-    #[allow(dead_code)]
     pub fn peek(&self, idx: isize) -> &LuaValue {
         let abs_idx = self.abs_index(idx);
         if abs_idx > 0 && abs_idx <= self.top() {
@@ -129,7 +166,8 @@ impl LuaStack {
                 LuaValue::Nil
             } else {
                 //println!("stack.get() upvals1 {:?}",self.closure.upvalues);
-                c.upvalues.borrow()[uv_idx as usize].borrow().clone()
+                let val = c.upvalues.borrow()[uv_idx as usize].borrow().clone();
+                val
             }
         }
         if idx == LUA_REGISTRYINDEX {
@@ -138,31 +176,45 @@ impl LuaStack {
         let abs_idx = self.abs_index(idx);
         if abs_idx > 0 && abs_idx <= self.top() {
             let idx = abs_idx as usize - 1;
+            if let Some(uv) = self.openuvs.get(&(idx as i32)) {
+                return uv.borrow().clone();
+            }
             self.vec[idx].clone() // TODO
         } else {
             LuaValue::Nil
         }
     }
 
-    pub fn set(&mut self, idx: isize, val: LuaValue) {
+    // the cell of the upvalue at pseudo-index 'idx', if there is one
+    pub fn upvalue(&self, idx: isize) -> Option<Upvalue> {
+        let uv_idx = LUA_REGISTRYINDEX - idx - 1;
+        let c = &self.closure;
+        if idx >= LUA_REGISTRYINDEX || c.is_fake() || uv_idx >= c.upvalues.borrow().len() as isize {
+            return None;
+        }
+        Some(c.upvalues.borrow()[uv_idx as usize].clone())
+    }
+
+    pub fn set(&mut self, idx: isize, val: LuaValue) -> LuaResult<()> {
         if idx < LUA_REGISTRYINDEX {
-            let uv_idx = LUA_REGISTRYINDEX - idx - 1;
-            let c = &self.closure;
-            if (!c.is_fake()) && (uv_idx < c.upvalues.borrow().len() as isize) {
-                c.upvalues.borrow_mut().as_mut_slice()[uv_idx as usize] = RefCell::new(val);
-            }
-            return;
+            let uv = self.upvalue(idx).ok_or_else(|| LuaError::from("invalid index"))?;
+            *uv.borrow_mut() = val;
+            return Ok(());
         }
         if idx == LUA_REGISTRYINDEX {
             self.registry = val;
-            return;
+            return Ok(());
         }
         let abs_idx = self.abs_index(idx);
         if abs_idx > 0 && abs_idx <= self.top() {
             let idx = abs_idx as usize - 1;
+            if let Some(uv) = self.openuvs.get(&(idx as i32)) {
+                *uv.borrow_mut() = val.clone();
+            }
             self.vec[idx] = val;
+            Ok(())
         } else {
-            panic!("invalid index: {}", idx);
+            Err(LuaError::from("invalid index"))
         }
     }
 
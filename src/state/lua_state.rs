@@ -1,30 +1,82 @@
 use super::lua_stack::LuaStack;
 use super::lua_value::LuaValue;
+use super::lua_table::LuaTable;
 use super::closure::Closure;
+use super::arith_ops::ArithError;
+use super::lua_error::{LuaError, LuaResult};
+use super::debug::{self, DebugInfo};
+use super::gc::{self, Gc, GcBudget, Marker};
+use super::lua_string::{LuaString, StringTable};
+use super::tm::*;
 use crate::api::RustFn;
 use crate::api::consts::*;
 use crate::api::{LuaAPI,LuaVM};
-use crate::binary::chunk::{Constant,Prototype};
+use crate::binary::chunk::Constant;
 use crate::vm::instructions::*;
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::sync::Once;
 
 const LUA_RIDX_GLOBALS: LuaValue = LuaValue::Integer(crate::api::consts::LUA_RIDX_GLOBALS as i64);
 
+/* maximum depth of nested calls made from Rust (the API, metamethods,
+   iterators), each one uses the Rust stack; calls from Lua to Lua do not */
+const LUAI_MAXCCALLS: usize = 200;
+
+/* room over LUAI_MAXSTACK and LUAI_MAXCCALLS for the message handler of
+   an overflow error */
+const ERRORSTACKSIZE: usize = LUAI_MAXSTACK + 200;
+const ERRORCCALLS: usize = LUAI_MAXCCALLS + (LUAI_MAXCCALLS >> 3);
+
+/* limit for table tag-method chains (to avoid loops) */
+const MAXTAGLOOP: usize = 2000;
+
+
 
 //TODO::current assume luaState has only one stack
 pub struct LuaState {
     frames: Vec<LuaStack>,
     registry: LuaValue,
+    // the stack slots used by all the frames, limited to LUAI_MAXSTACK
+    nslots: usize,
+    // the calls made from Rust that are running, limited to LUAI_MAXCCALLS
+    n_ccalls: usize,
+    // message handlers of the active protected calls, innermost last
+    handlers: Vec<Option<LuaValue>>,
+    // a message handler is running, it may go over the stack limits
+    in_handler: bool,
+    // turn panics in Rust functions into Lua errors instead of unwinding
+    catch_panics: bool,
+    quiet_panics: bool,
+    // add the variables of each frame to the stack kept by uncaught errors
+    dump_locals: bool,
+    // metatables shared by all the values of a basic type, tables excepted
+    type_metatables: Vec<Option<Rc<RefCell<LuaTable>>>>,
+    gc: Gc,
+    // the short strings in use, interned
+    strings: StringTable,
+    // the names of the metatable events, by TM_*
+    tm_names: Vec<LuaValue>,
+    // an allocation went over the memory limit, the error is raised at the
+    // next point that can fail
+    out_of_memory: bool,
+    // the same for an error in a finalizer run by an automatic step
+    gc_error: Option<LuaError>,
 }
 
 
 
 impl LuaState {
     pub fn new() -> LuaState {
+        let mut strings = StringTable::new();
+        let tm_names = tm_names(&mut strings);
+        let mut gc = Gc::new(tm_names[TM_MODE].clone());
         let registry = LuaValue::new_table(0, 0);
+        gc.track(&registry);
         if let LuaValue::Table(t) = &registry {
             let globals = LuaValue::new_table(0, 0);
+            gc.track(&globals);
             t.borrow_mut().put(LUA_RIDX_GLOBALS, globals);
         }
 
@@ -33,7 +85,65 @@ impl LuaState {
         LuaState {
             registry,
             frames: vec![fake_frame],
+            nslots: 0,
+            n_ccalls: 0,
+            handlers: Vec::new(),
+            in_handler: false,
+            catch_panics: true,
+            quiet_panics: false,
+            dump_locals: false,
+            type_metatables: vec![None; LUA_NUMTAGS],
+            gc,
+            strings,
+            tm_names,
+            out_of_memory: false,
+            gc_error: None,
+        }
+    }
+
+    // runs the finalizers of every table that has one, then frees all the
+    // objects; dropping the state does the same. The finalizers all run
+    // even if some fail, the first error is returned (LUA_ERRGCMM)
+    pub fn close(mut self) -> LuaResult<()> {
+        self.close_state()
+    }
+
+    // chooses whether a panic in a Rust function becomes a Lua error
+    // (the default) or unwinds through the interpreter to the host
+    pub fn set_catch_panics(&mut self, on: bool) {
+        self.catch_panics = on;
+    }
+
+    // chooses whether the panic hook keeps quiet about the panics this state
+    // turns into Lua errors (off by default, the hook reports them all).
+    // The first state that turns it on replaces the process-wide hook with
+    // one that wraps it: panics that no quiet state catches, including
+    // those on other threads, still go to the wrapped hook. A hook the host
+    // sets afterwards replaces the wrapper and the silencing with it.
+    pub fn set_quiet_panics(&mut self, on: bool) {
+        if on {
+            quiet_caught_panics();
         }
+        self.quiet_panics = on;
+    }
+
+    // caps the memory the state may use, in bytes; going over it raises a
+    // "not enough memory" error (LUA_ERRMEM) once a full collection fails
+    // to bring the memory in use back under the limit
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.gc.set_limit(limit);
+    }
+
+    // calls 'callback(threshold, bytes)' whenever the memory in use goes
+    // above or back below one of the thresholds
+    pub fn set_memory_callback(&mut self, thresholds: Vec<usize>, callback: impl FnMut(usize, usize) + 'static) {
+        self.gc.set_watch(thresholds, Box::new(callback));
+    }
+
+    // chooses whether uncaught errors keep the locals and upvalues of
+    // every active function along with the traceback
+    pub fn set_dump_locals(&mut self, on: bool) {
+        self.dump_locals = on;
     }
 
     fn stack_mut(&mut self) -> &mut LuaStack {
@@ -44,15 +154,28 @@ impl LuaState {
         self.frames.last().unwrap() // TODO
     }
 
-    fn push_frame(&mut self, frame: LuaStack) {
+    fn push_frame(&mut self, frame: LuaStack) -> LuaResult<()> {
+        let limit = if self.in_handler { ERRORSTACKSIZE } else { LUAI_MAXSTACK };
+        if self.nslots + frame.slots > limit {
+            return Err(self.runtime_error("stack overflow"));
+        }
+        let size = frame.size();
+        self.check_limit(size);
+        self.nslots += frame.slots;
         self.frames.push(frame);
+        self.gc.set_stacks(self.gc.total_stacks() + size);
+        Ok(())
     }
 
     fn pop_frame(&mut self) -> LuaStack {
-        self.frames.pop().unwrap()
+        let frame = self.frames.pop().unwrap();
+        self.nslots -= frame.slots;
+        self.gc.set_stacks(self.gc.total_stacks().saturating_sub(frame.size()));
+        frame
     }
 
     // debug
+    #[allow(dead_code)]
     fn print_stack(&self,opname: &str) {
         print!("  {} ", opname);
         let top = self.get_top();
@@ -70,6 +193,12 @@ impl LuaState {
 
 }
 
+impl Default for LuaState {
+    fn default() -> LuaState {
+        LuaState::new()
+    }
+}
+
 impl LuaVM for LuaState {
     fn pc(&self) -> isize {
         self.stack().pc
@@ -82,7 +211,7 @@ impl LuaVM for LuaState {
     fn fetch(&mut self) -> u32 {
         let instr = self.stack().closure.proto.code[self.stack().pc as usize];
         self.stack_mut().pc += 1;
-        return instr;
+        instr
     }
 
     fn get_const(&mut self, idx: isize) {
@@ -92,7 +221,7 @@ impl LuaVM for LuaState {
             Constant::Boolean(b) => LuaValue::Boolean(*b),
             Constant::Integer(i) => LuaValue::Integer(*i),
             Constant::Number(n) => LuaValue::Number(*n),
-            Constant::Str(s) => LuaValue::Str((*s).clone()),
+            Constant::Str(s) => LuaValue::Str(s.clone()),
         };
         self.stack_mut().push(val);
     }
@@ -111,6 +240,14 @@ impl LuaVM for LuaState {
         self.stack().closure.proto.max_stack_size as usize
     }
 
+    fn set_integer(&mut self, idx: isize, n: i64) -> LuaResult<()> {
+        self.stack_mut().set(idx, LuaValue::Integer(n))
+    }
+
+    fn set_number(&mut self, idx: isize, n: f64) -> LuaResult<()> {
+        self.stack_mut().set(idx, LuaValue::Number(n))
+    }
+
     fn load_vararg(&mut self, mut n: isize) {
         if n < 0 {
             n = self.stack().varargs.len() as isize;
@@ -123,51 +260,58 @@ impl LuaVM for LuaState {
 
     fn load_proto(&mut self, idx: usize) {
         let proto = self.stack().closure.proto.protos[idx].clone();
-        let closure = LuaValue::new_lua_closure(proto.clone());
+        let closure = self.new_object(LuaValue::new_lua_closure(proto.clone()));
         self.stack_mut().push(closure.clone());
 
-        let mut iter = proto.upvalues.iter().enumerate();
-        loop {
-            match iter.next() {
-                Some((i,uv_info)) => {
-                    let uv_idx  = uv_info.idx as i32;
-                    if let LuaValue::Function(cl) = &closure {
-                        if uv_info.instack == 1 {
-                            match self.stack().openuvs.get(&uv_idx) {
-                                Some(opennv) => {
-                                    //println!("lua_state.138 len={} upvalues[{}]={:?}",cl.upvalues.borrow().len(),i,opennv);
-                                    cl.upvalues.borrow_mut().as_mut_slice()[i] = opennv.clone();
-                                },
-                                None => {
-                                    //println!("lua_state.142 len={} upvalues[{}]={:?}",cl.upvalues.borrow().len(),i,self.stack().get(uv_idx as isize));
-                                    cl.upvalues.borrow_mut().as_mut_slice()[i] = self.stack().slots(uv_idx as usize);
-                                    let v = cl.upvalues.borrow()[i].clone();
-                                    self.stack_mut().openuvs.insert(uv_idx,v);//RefCell<LuaValue>
-                                }
-                            }
-                        } else {
-                            cl.upvalues.borrow_mut().as_mut_slice()[i] = self.stack().closure.upvalues.borrow()[i].clone();
-                        }
-                    }
-                },
-                None => break
+        for (i,uv_info) in proto.upvalues.iter().enumerate() {
+            let uv_idx  = uv_info.idx as i32;
+            if let LuaValue::Function(cl) = &closure {
+                if uv_info.instack == 1 {
+                    // shared with the other closures capturing this local
+                    let uv = self.stack_mut().open_upvalue(uv_idx as usize);
+                    cl.upvalues.borrow_mut().as_mut_slice()[i] = uv;
+                } else {
+                    cl.upvalues.borrow_mut().as_mut_slice()[i] = self.stack().closure.upvalues.borrow()[i].clone();
+                }
             }
         }
+        self.check_gc();
     }
 
-    fn close_upvalues(&mut self,a: isize) {
-        let mut iter = self.stack_mut().openuvs.iter_mut();
-        loop {
-            match iter.next() {
-                Some((i,openuv)) => {
-                    if *i as isize >= a-1 {
-                        //let val = open//TODO::p201
+    fn close_upvalues(&mut self, a: isize) {
+        self.stack_mut().close_upvalues(a as usize - 1);
+    }
 
-                    }
-                },
-                None => break
-            }
+    // all errors raised by the runtime itself are built here, they get the
+    // position of the running Lua function (none if it is a Rust function)
+    fn runtime_error(&self, msg: &str) -> LuaError {
+        LuaError::from(format!("{}{}", self._where(0), msg))
+    }
+
+    fn pre_call(&mut self, nargs: usize, nresults: isize) -> LuaResult<bool> {
+        let (nargs, c) = self.callee(nargs)?;
+        if c.rust_fn.is_some() {
+            self.call_rust_closure(nargs, nresults, c)?;
+            return Ok(true);
+        }
+        let mut frame = self.lua_frame(nargs, c);
+        frame.nresults = nresults;
+        self.push_frame(frame)?;
+        Ok(false)
+    }
+
+    fn tail_call(&mut self, nargs: usize) -> LuaResult<bool> {
+        let (nargs, c) = self.callee(nargs)?;
+        if c.rust_fn.is_some() {
+            self.call_rust_closure(nargs, LUA_MULTRET, c)?;
+            return Ok(true);
         }
+        let mut frame = self.lua_frame(nargs, c);
+        let caller = self.pop_frame(); // its open upvalues are closed
+        frame.nresults = caller.nresults;
+        frame.is_tail_call = true;
+        self.push_frame(frame)?;
+        Ok(false)
     }
 }
 
@@ -195,11 +339,15 @@ impl LuaAPI for LuaState {
         }
     }
 
-    fn copy(&mut self, from_idx: isize, to_idx: isize) {
+    fn copy(&mut self, from_idx: isize, to_idx: isize) -> LuaResult<()> {
+        self.check_index(to_idx)?;
         let val = self.stack().get(from_idx);
         //println!("{} {:?} {}",from_idx,val,to_idx);
         //println!("copy() upvals:{:?}",self.stack().closure.upvalues);
-        self.stack_mut().set(to_idx, val);
+        if let Some(uv) = self.stack().upvalue(to_idx) {
+            self.gc.barrier(&uv, &val);
+        }
+        self.stack_mut().set(to_idx, val)
     }
 
     fn push_value(&mut self, idx: isize) {
@@ -207,24 +355,29 @@ impl LuaAPI for LuaState {
         self.stack_mut().push(val);
     }
 
-    fn replace(&mut self, idx: isize) {
+    fn replace(&mut self, idx: isize) -> LuaResult<()> {
+        self.check_index(idx)?;
         let val = self.stack_mut().pop();
-        self.stack_mut().set(idx, val);
+        if let Some(uv) = self.stack().upvalue(idx) {
+            self.gc.barrier(&uv, &val);
+        }
+        self.stack_mut().set(idx, val)
     }
 
-    fn insert(&mut self, idx: isize) {
-        self.rotate(idx, 1);
+    fn insert(&mut self, idx: isize) -> LuaResult<()> {
+        self.rotate(idx, 1)
     }
 
-    fn remove(&mut self, idx: isize) {
-        self.rotate(idx, -1);
+    fn remove(&mut self, idx: isize) -> LuaResult<()> {
+        self.rotate(idx, -1)?;
         self.pop(1);
+        Ok(())
     }
 
-    fn rotate(&mut self, idx: isize, n: isize) {
+    fn rotate(&mut self, idx: isize, n: isize) -> LuaResult<()> {
         let abs_idx = self.stack().abs_index(idx);
         if abs_idx < 0 || !self.stack().is_valid(abs_idx) {
-            panic!("invalid index!");
+            return Err(LuaError::from("invalid index"));
         }
 
         let t = self.stack().top() - 1; /* end of stack segment being rotated */
@@ -233,10 +386,11 @@ impl LuaAPI for LuaState {
         self.stack_mut().reverse(p as usize, m as usize); /* reverse the prefix with length 'n' */
         self.stack_mut().reverse(m as usize + 1, t as usize); /* reverse the suffix */
         self.stack_mut().reverse(p as usize, t as usize); /* reverse the entire segment */
+        Ok(())
     }
 
-    fn set_top(&mut self, idx: isize) {
-        self.stack_mut().set_top(idx);
+    fn set_top(&mut self, idx: isize) -> LuaResult<()> {
+        self.stack_mut().set_top(idx)
     }
 
     /* access functions (stack -> rust) */
@@ -303,10 +457,7 @@ impl LuaAPI for LuaState {
     }
 
     fn is_integer(&self, idx: isize) -> bool {
-        match self.stack().get(idx) {
-            LuaValue::Integer(_) => true,
-            _ => false,
-        }
+        matches!(self.stack().get(idx), LuaValue::Integer(_))
     }
 
     fn is_rust_function(&self, idx: isize) -> bool {
@@ -321,41 +472,74 @@ impl LuaAPI for LuaState {
     }
 
     fn to_integer(&self, idx: isize) -> i64 {
-        self.to_integerx(idx).unwrap()
+        self.to_integerx(idx).unwrap_or(0)
     }
 
     fn to_integerx(&self, idx: isize) -> Option<i64> {
-        match self.stack().get(idx) {
-            LuaValue::Integer(i) => Some(i),
-            _ => None,
-        }
+        self.stack().get(idx).to_integer()
     }
 
     fn to_number(&self, idx: isize) -> f64 {
-        self.to_numberx(idx).unwrap()
+        self.to_numberx(idx).unwrap_or(0.0)
     }
 
     fn to_numberx(&self, idx: isize) -> Option<f64> {
-        match self.stack().get(idx) {
-            LuaValue::Number(n) => Some(n),
-            LuaValue::Integer(i) => Some(i as f64),
-            _ => None,
-        }
+        self.stack().get(idx).to_number()
     }
 
     fn to_string(&self, idx: isize) -> String {
-        self.to_stringx(idx).unwrap()
+        self.to_stringx(idx).unwrap_or_default()
     }
 
     fn to_stringx(&self, idx: isize) -> Option<String> {
+        self.stack().get(idx).to_str()
+    }
+
+    fn to_bytes(&self, idx: isize) -> Option<LuaString> {
         match self.stack().get(idx) {
             LuaValue::Str(s) => Some(s),
-            LuaValue::Number(n) => Some(n.to_string()),
-            LuaValue::Integer(i) => Some(i.to_string()),
-            _ => None,
+            v => v.to_str().map(|s| LuaString::from(s.as_str())),
         }
     }
 
+    // converts any value to a string in a reasonable format and pushes it,
+    // honouring '__tostring' and '__name' (like luaL_tolstring)
+    fn to_display_string(&mut self, idx: isize) -> LuaResult<String> {
+        let val = self.stack().get(idx);
+        let mm = self.get_metafield(&val, TM_TOSTRING);
+        let s = if !mm.is_nil() {
+            self.stack_mut().push(mm);
+            self.stack_mut().push(val);
+            self.call(1, 1)?;
+            match self.stack_mut().pop().to_str() {
+                Some(s) => s,
+                None => {
+                    let msg = format!("{}'__tostring' must return a string", self._where(1));
+                    return Err(LuaError::from(msg));
+                }
+            }
+        } else {
+            match &val {
+                LuaValue::Nil => String::from("nil"),
+                LuaValue::Boolean(b) => b.to_string(),
+                LuaValue::Table(_) | LuaValue::Function(_) => {
+                    let kind = match self.get_metafield(&val, TM_NAME) {
+                        LuaValue::Str(name) => name.to_string(),
+                        _ => val.type_name().to_string(),
+                    };
+                    format!("{}: {:#x}", kind, val.to_pointer())
+                }
+                _ => val.to_str().unwrap(), // numbers and strings
+            }
+        };
+        self.push_string(s.clone());
+        Ok(s)
+    }
+
+    fn to_pointer(&self, idx: isize) -> usize {
+        self.stack().get(idx).to_pointer()
+    }
+
     fn to_rust_function(&self, idx: isize) -> Option<RustFn> {
         match self.stack().get(idx) {
             LuaValue::Function(c) => c.rust_fn,
@@ -382,23 +566,41 @@ impl LuaAPI for LuaState {
     }
 
     fn push_string(&mut self, s: String) {
+        self.push_bytes(s.as_bytes());
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        self.check_limit(bytes.len());
+        let s = self.strings.intern(bytes);
         self.stack_mut().push(LuaValue::Str(s));
+        self.gc.allocate(bytes.len());
     }
 
     fn push_rust_function(&mut self, f: RustFn) {
-        self.stack_mut().push(LuaValue::new_rust_closure(f,0));
+        self.push_rust_closure(f, 0);
     }
 
     fn push_rust_closure(&mut self,f: RustFn, n: usize) {
-        let closure = LuaValue::new_rust_closure(f,n);
+        let closure = self.new_object(LuaValue::new_rust_closure(f,n));
         for i in 0..n {
             let val = self.stack_mut().pop();
             if let LuaValue::Function(cl) = &closure {
                 //println!("lua_state.392 len={} upvalues[{}]={:?}",cl.upvalues.borrow().len(),n-i-1,val);
-                cl.upvalues.borrow_mut().as_mut_slice()[n-i-1] = RefCell::new(val);
+                *cl.upvalues.borrow()[n-i-1].borrow_mut() = val;
             }
         }
         self.stack_mut().push(closure);
+        self.check_gc();
+    }
+
+    fn string_to_number(&mut self, s: &str) -> bool {
+        match super::number::str_to_number(s) {
+            Some(n) => {
+                self.stack_mut().push(n);
+                true
+            }
+            None => false,
+        }
     }
 
     fn push_global_table(&mut self) {
@@ -410,67 +612,182 @@ impl LuaAPI for LuaState {
 
     /* comparison and arithmetic functions */
 
-    fn arith(&mut self, op: u8) {
-        if op != LUA_OPUNM && op != LUA_OPBNOT {
+    fn arith(&mut self, op: u8) -> LuaResult<()> {
+        let (a, b) = if op != LUA_OPUNM && op != LUA_OPBNOT {
             let b = self.stack_mut().pop();
             let a = self.stack_mut().pop();
-            //println!("{:?}-{:?} {:?}",a,b,super::arith_ops::arith(&a, &b, op));//TODO:fix!
-            if let Some(result) = super::arith_ops::arith(&a, &b, op) {
-                self.stack_mut().push(result);
-                return;
-            }
+            (a, b)
         } else {
             let a = self.stack_mut().pop();
-            if let Some(result) = super::arith_ops::arith(&a, &a, op) {
+            (a.clone(), a)
+        };
+        let err = match super::arith_ops::arith(&a, &b, op) {
+            Ok(result) => {
+                self.stack_mut().push(result);
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+        if let ArithError::NotNumber | ArithError::NoIntegerRep = err {
+            if let Some(result) = self.call_metamethod(&a, &b, TM_ADD + op as usize)? {
                 self.stack_mut().push(result);
-                return;
+                return Ok(());
+            }
+        }
+        match err {
+            ArithError::NotNumber => {
+                // blame the first operand that is not a number
+                let operand = if a.to_numeric().is_none() { 0 } else { 1 };
+                let culprit = if operand == 0 { &a } else { &b };
+                let what = if op >= LUA_OPBAND && op != LUA_OPUNM {
+                    "perform bitwise operation on"
+                } else {
+                    "perform arithmetic on"
+                };
+                Err(self.type_error(culprit, what, operand))
+            }
+            ArithError::NoIntegerRep => {
+                let operand = if a.to_integer().is_none() { 0 } else { 1 };
+                let culprit = if operand == 0 { &a } else { &b };
+                let info = debug::var_info(self.stack(), culprit, operand);
+                let msg = format!("number{} has no integer representation", info);
+                Err(self.runtime_error(&msg))
+            }
+            ArithError::DivideByZero(what) => {
+                Err(self.runtime_error(&format!("attempt to perform '{}'", what)))
             }
         }
-        panic!("arithmetic error!");
     }
 
-    fn compare(&self, idx1: isize, idx2: isize, op: u8) -> bool {
+    fn compare(&mut self, idx1: isize, idx2: isize, op: u8) -> LuaResult<bool> {
         if !self.stack().is_valid(idx1) || !self.stack().is_valid(idx2) {
-            false
-        } else {
-            let a = self.stack().get(idx1);
-            let b = self.stack().get(idx2);
-            if let Some(result) = super::cmp_ops::compare(&a, &b, op) {
-                return result;
+            return Ok(false);
+        }
+        let a = self.stack().get(idx1);
+        let b = self.stack().get(idx2);
+        match super::cmp_ops::compare(&a, &b, op) {
+            Some(false) if op == LUA_OPEQ => {
+                // only two distinct tables may still be equal
+                if let (LuaValue::Table(_), LuaValue::Table(_)) = (&a, &b) {
+                    if let Some(result) = self.call_metamethod(&a, &b, TM_EQ)? {
+                        return Ok(result.to_boolean());
+                    }
+                }
+                Ok(false)
+            }
+            Some(result) => Ok(result),
+            None if op == LUA_OPLT => match self.call_metamethod(&a, &b, TM_LT)? {
+                Some(result) => Ok(result.to_boolean()),
+                None => Err(self.compare_error(&a, &b)),
+            },
+            None => {
+                if let Some(result) = self.call_metamethod(&a, &b, TM_LE)? {
+                    return Ok(result.to_boolean());
+                }
+                // try 'not (b < a)'
+                match self.call_metamethod(&b, &a, TM_LT)? {
+                    Some(result) => Ok(!result.to_boolean()),
+                    None => Err(self.compare_error(&a, &b)),
+                }
             }
-            panic!("comparison error!")
         }
     }
 
+    fn raw_equal(&self, idx1: isize, idx2: isize) -> bool {
+        if !self.stack().is_valid(idx1) || !self.stack().is_valid(idx2) {
+            return false;
+        }
+        self.stack().get(idx1) == self.stack().get(idx2)
+    }
+
     /* miscellaneous functions */
 
-    fn len(&mut self, idx: isize) {
-        let _len = match self.stack().get(idx) {
+    fn raw_len(&self, idx: isize) -> usize {
+        match self.stack().get(idx) {
             LuaValue::Str(s) => s.len(),
             LuaValue::Table(t) => t.borrow().len(),
-            _ => panic!("length error!"),
-        };
-        self.stack_mut().push(LuaValue::Integer(_len as i64));
+            _ => 0,
+        }
+    }
+
+    fn len(&mut self, idx: isize) -> LuaResult<()> {
+        let val = self.stack().get(idx);
+        if let LuaValue::Str(s) = &val {
+            self.stack_mut().push(LuaValue::Integer(s.len() as i64));
+            return Ok(());
+        }
+        if let Some(result) = self.call_metamethod(&val, &val, TM_LEN)? {
+            self.stack_mut().push(result);
+            return Ok(());
+        }
+        match &val {
+            LuaValue::Table(t) => {
+                let len = t.borrow().len();
+                self.stack_mut().push(LuaValue::Integer(len as i64));
+                Ok(())
+            }
+            _ => Err(self.type_error(&val, "get length of", 0)),
+        }
     }
 
-    fn concat(&mut self, n: isize) {
+    fn concat(&mut self, n: isize) -> LuaResult<()> {
         if n == 0 {
-            self.stack_mut().push(LuaValue::Str(String::new()))
+            self.push_string(String::new())
         } else if n >= 2 {
-            for _ in 1..n {
+            for i in 1..n {
                 if self.is_string(-1) && self.is_string(-2) {
-                    let s2 = self.to_string(-1);
-                    let mut s1 = self.to_string(-2);
-                    s1.push_str(&s2);
+                    let (a, b) = (self.to_bytes(-2).unwrap(), self.to_bytes(-1).unwrap());
+                    let len = a.len() + b.len();
+                    self.reserve(len)?;
+                    let mut s = Vec::with_capacity(len);
+                    s.extend_from_slice(a.as_bytes());
+                    s.extend_from_slice(b.as_bytes());
+                    self.stack_mut().pop();
+                    self.stack_mut().pop();
+                    self.push_bytes(&s);
+                    continue;
+                }
+                let (a, b) = (self.stack().get(-2), self.stack().get(-1));
+                if let Some(result) = self.call_metamethod(&a, &b, TM_CONCAT)? {
                     self.stack_mut().pop();
                     self.stack_mut().pop();
-                    self.stack_mut().push(LuaValue::Str(s1));
+                    self.stack_mut().push(result);
                 } else {
-                    panic!("concatenation error!");
+                    // blame the second operand if the first one is fine,
+                    // the values below the top two are still the originals
+                    let (culprit, operand) = if self.is_string(-2) {
+                        (-1, n as usize - 1)
+                    } else {
+                        (-2, (n - 1 - i) as usize)
+                    };
+                    let v = self.stack().get(culprit);
+                    return Err(self.type_error(&v, "concatenate", operand));
                 }
             }
         }
         // n == 1, do nothing
+        self.check_pending()
+    }
+
+    fn next(&mut self, idx: isize) -> LuaResult<bool> {
+        let t = match self.stack().get(idx) {
+            LuaValue::Table(t) => t,
+            v => return Err(self.type_error(&v, "index", 0)),
+        };
+        let key = self.stack_mut().pop();
+        let entry = {
+            let t = t.borrow();
+            t.next_position(&key).map(|pos| t.entry_from(pos))
+        };
+        match entry {
+            Some(Some((k, v))) => {
+                self.stack_mut().push(k);
+                self.stack_mut().push(v);
+                Ok(true)
+            }
+            Some(None) => Ok(false),
+            None => Err(self.runtime_error("invalid key to 'next'")),
+        }
     }
 
     /* get functions (Lua -> stack) */
@@ -480,88 +797,144 @@ impl LuaAPI for LuaState {
     }
 
     fn create_table(&mut self, narr: usize, nrec: usize) {
-        self.stack_mut().push(LuaValue::new_table(narr, nrec));
+        let t = self.new_object(LuaValue::new_table(narr, nrec));
+        self.stack_mut().push(t);
+        self.check_gc();
     }
 
-    fn get_table(&mut self, idx: isize) -> i8 {
+    fn get_table(&mut self, idx: isize) -> LuaResult<i8> {
         //println!("idx={}",idx);
         let t = self.stack().get(idx);
         let k = self.stack_mut().pop();
         //println!("{:?} {:?}",t,k);
-        self.get_table_impl(&t, &k)
+        self.get_table_impl(&t, &k, false)
     }
 
-    fn get_field(&mut self, idx: isize, k: &str) -> i8 {
+    fn get_field(&mut self, idx: isize, k: &str) -> LuaResult<i8> {
         let t = self.stack().get(idx);
-        let k = LuaValue::Str(k.to_string()); // TODO
-        self.get_table_impl(&t, &k)
+        let k = LuaValue::Str(self.strings.intern(k.as_bytes()));
+        self.get_table_impl(&t, &k, false)
     }
 
-    fn get_i(&mut self, idx: isize, i: i64) -> i8 {
+    fn get_i(&mut self, idx: isize, i: i64) -> LuaResult<i8> {
         let t = self.stack().get(idx);
         let k = LuaValue::Integer(i);
-        self.get_table_impl(&t, &k)
+        self.get_table_impl(&t, &k, false)
     }
 
-    fn get_global(&mut self, name: &str) -> i8 {
-        if let LuaValue::Table(r) = &self.registry {
-            let t = r.borrow().get(&LUA_RIDX_GLOBALS);
-            let k = LuaValue::Str(name.to_string()); // TODO
-            self.get_table_impl(&t, &k)
-        } else {
-            0
+    fn get_global(&mut self, name: &str) -> LuaResult<i8> {
+        let t = self.globals();
+        let k = LuaValue::Str(self.strings.intern(name.as_bytes()));
+        self.get_table_impl(&t, &k, false)
+    }
+
+    fn raw_get(&mut self, idx: isize) -> LuaResult<i8> {
+        let t = self.stack().get(idx);
+        let k = self.stack_mut().pop();
+        self.get_table_impl(&t, &k, true)
+    }
+
+    fn raw_get_i(&mut self, idx: isize, i: i64) -> LuaResult<i8> {
+        let t = self.stack().get(idx);
+        let k = LuaValue::Integer(i);
+        self.get_table_impl(&t, &k, true)
+    }
+
+    // pushes the metatable of the value at idx, if it has one
+    fn get_metatable(&mut self, idx: isize) -> bool {
+        match self.get_metatable_of(&self.stack().get(idx)) {
+            Some(mt) => {
+                self.stack_mut().push(LuaValue::Table(mt));
+                true
+            }
+            None => false,
         }
     }
 
     /* set functions (stack -> Lua) */
 
-    fn set_table(&mut self, idx: isize) {
+    fn set_table(&mut self, idx: isize) -> LuaResult<()> {
         let t = self.stack().get(idx);
         let v = self.stack_mut().pop();
         let k = self.stack_mut().pop();
-        LuaState::set_table_impl(&t, k, v);
+        self.set_table_impl(&t, k, v, false)
     }
 
-    fn set_field(&mut self, idx: isize, k: &str) {
+    fn set_field(&mut self, idx: isize, k: &str) -> LuaResult<()> {
         let t = self.stack().get(idx);
         let v = self.stack_mut().pop();
-        let k = LuaValue::Str(k.to_string()); // TODO
-        LuaState::set_table_impl(&t, k, v);
+        let k = LuaValue::Str(self.strings.intern(k.as_bytes()));
+        self.set_table_impl(&t, k, v, false)
     }
 
-    fn set_i(&mut self, idx: isize, i: i64) {
+    fn set_i(&mut self, idx: isize, i: i64) -> LuaResult<()> {
         let t = self.stack().get(idx);
         let v = self.stack_mut().pop();
         let k = LuaValue::Integer(i);
-        LuaState::set_table_impl(&t, k, v);
+        self.set_table_impl(&t, k, v, false)
     }
 
-    fn set_global(&mut self, name: &str) {
-        if let LuaValue::Table(r) = &self.registry {
-            let t = r.borrow().get(&LUA_RIDX_GLOBALS);
-            let v = self.stack_mut().pop();
-            let k = LuaValue::Str(name.to_string()); // TODO
-            LuaState::set_table_impl(&t, k, v);
+    fn set_global(&mut self, name: &str) -> LuaResult<()> {
+        let t = self.globals();
+        let v = self.stack_mut().pop();
+        let k = LuaValue::Str(self.strings.intern(name.as_bytes()));
+        self.set_table_impl(&t, k, v, false)
+    }
+
+    fn raw_set(&mut self, idx: isize) -> LuaResult<()> {
+        let t = self.stack().get(idx);
+        let v = self.stack_mut().pop();
+        let k = self.stack_mut().pop();
+        self.set_table_impl(&t, k, v, true)
+    }
+
+    fn raw_set_i(&mut self, idx: isize, i: i64) -> LuaResult<()> {
+        let t = self.stack().get(idx);
+        let v = self.stack_mut().pop();
+        let k = LuaValue::Integer(i);
+        self.set_table_impl(&t, k, v, true)
+    }
+
+    // pops a table or nil and makes it the metatable of the value at idx
+    fn set_metatable(&mut self, idx: isize) -> LuaResult<()> {
+        let val = self.stack().get(idx);
+        let mt = match self.stack_mut().pop() {
+            LuaValue::Nil => None,
+            LuaValue::Table(mt) => Some(mt),
+            v => return Err(LuaError::from(format!("table expected as metatable, got {}", v.type_name()))),
+        };
+        match val {
+            LuaValue::Table(t) => {
+                self.gc.barrier_back(&t);
+                let gc_event = &self.tm_names[TM_GC];
+                let has_gc = mt.as_ref().is_some_and(|mt| !mt.borrow().get(gc_event).is_nil());
+                t.borrow_mut().metatable = mt;
+                if has_gc {
+                    self.gc.check_finalizer(&t);
+                }
+            }
+            _ => self.type_metatables[val.type_id() as usize] = mt,
         }
+        Ok(())
     }
 
-    fn register(&mut self, name: &str, f: RustFn) {
+    fn register(&mut self, name: &str, f: RustFn) -> LuaResult<()> {
         self.push_rust_function(f);
-        self.set_global(name);
+        self.set_global(name)
     }
 
     /* 'load' and 'call' functions (load and run Lua code) */
 
     fn load(&mut self, chunk: Vec<u8>, _chunk_name: &str, _mode: &str) -> u8 {
-        let proto = crate::binary::undump(chunk);
-        let c = LuaValue::new_lua_closure(proto.clone());
+        let proto = crate::binary::undump(chunk, &mut self.strings);
+        let c = self.new_object(LuaValue::new_lua_closure(proto.clone()));
         self.stack_mut().push(c.clone());
-        if proto.upvalues.len() > 0 {
+        if !proto.upvalues.is_empty() {
             if let LuaValue::Table(tbl) = &(self.registry) {
                 let env = tbl.borrow().get(&(self::LUA_RIDX_GLOBALS));
                 if let LuaValue::Function(cl) = c {
                     //debug sum.lua pushed (table) print=>function
-                    cl.upvalues.borrow_mut().as_mut_slice()[0]=RefCell::new(env);
+                    *cl.upvalues.borrow()[0].borrow_mut() = env;
                 }
             }
         }
@@ -569,17 +942,125 @@ impl LuaAPI for LuaState {
     }
 
 
-    fn call(&mut self, nargs: usize, nresults: isize) {
-        let val = self.stack().get(-(nargs as isize + 1));
-        if let LuaValue::Function(c) = val {
-            if c.rust_fn.is_some() {
-                self.call_rust_closure(nargs, nresults, c);
-            } else {
-                self.call_lua_closure(nargs, nresults, c);
-            }
+    fn call(&mut self, nargs: usize, nresults: isize) -> LuaResult<()> {
+        let limit = if self.in_handler { ERRORCCALLS } else { LUAI_MAXCCALLS };
+        if self.n_ccalls >= limit {
+            return Err(self.runtime_error("C stack overflow"));
+        }
+        self.n_ccalls += 1;
+        let base = self.frames.len() + 1;
+        let r = match self.pre_call(nargs, nresults) {
+            Ok(false) => self.execute(base),
+            r => r.map(|_| ()),
+        };
+        self.n_ccalls -= 1;
+        r
+    }
+
+    // calls a function in protected mode: on error the frames it pushed are
+    // gone (closing their open upvalues), the function and its arguments are
+    // replaced by the error object and the error status is returned
+    fn pcall(&mut self, nargs: usize, nresults: isize, msgh: isize) -> u8 {
+        let handler = if msgh == 0 {
+            None
         } else {
-            println!("val = {:?}",val);
-            panic!("not function!");
+            Some(self.stack().get(msgh))
+        };
+        let func_idx = self.abs_index(-(nargs as isize + 1));
+
+        self.handlers.push(handler);
+        let r = match self.call(nargs, nresults) {
+            Err(err) => Err(self.handle_error(err)),
+            ok => ok,
+        };
+        self.handlers.pop();
+
+        match r {
+            Ok(()) => LUA_OK,
+            Err(err) => {
+                let status = err.status();
+                self.stack_mut().close_upvalues(func_idx as usize - 1);
+                self.stack_mut().resize(func_idx as usize - 1);
+                self.stack_mut().push(err.into_value());
+                status
+            }
+        }
+    }
+
+    /* garbage-collection function */
+
+    fn gc(&mut self, what: u8, data: isize) -> LuaResult<isize> {
+        match what {
+            LUA_GCSTOP => self.gc.set_running(false),
+            LUA_GCRESTART => self.gc.set_running(true),
+            LUA_GCCOLLECT => {
+                self.collect_garbage()?;
+            }
+            LUA_GCSTEP => {
+                let work = if data > 0 {
+                    data as usize * 1024 // 'data' is in Kbytes
+                } else {
+                    self.gc.debt_work() // a basic step
+                };
+                return self.gc_step_impl(GcBudget::Work(work)).map(|finished| finished as isize);
+            }
+            LUA_GCCOUNT => return Ok((self.gc.total() >> 10) as isize),
+            LUA_GCCOUNTB => return Ok((self.gc.total() & 0x3ff) as isize),
+            LUA_GCSETPAUSE => return Ok(self.gc.set_pause(data.max(0) as usize) as isize),
+            LUA_GCSETSTEPMUL => return Ok(self.gc.set_stepmul(data.max(0) as usize) as isize),
+            LUA_GCISRUNNING => return Ok(self.gc.is_running() as isize),
+            _ => return Ok(-1), // invalid option
+        }
+        Ok(0)
+    }
+
+    // runs the collector within the budget, returns true if a cycle was
+    // finished
+    fn gc_step(&mut self, budget: GcBudget) -> LuaResult<bool> {
+        self.gc_step_impl(budget)
+    }
+
+    fn reserve(&mut self, bytes: usize) -> LuaResult<()> {
+        if !self.gc.fits(bytes) {
+            self.full_gc();
+            if !self.gc.fits(bytes) {
+                return Err(memory_error());
+            }
+        }
+        Ok(())
+    }
+
+    /* error handling and debug information */
+
+    // pops the error object, the caller raises it by returning Err
+    fn error(&mut self) -> LuaError {
+        let val = self.stack_mut().pop();
+        LuaError::new(val)
+    }
+
+    // "chunkname:currentline:" of the function at the given level of the
+    // call stack (0 is the running function), or "" if it is not known
+    fn _where(&self, level: usize) -> String {
+        if let Some(&i) = self.levels().get(level) {
+            let ar = DebugInfo::new(&self.frames[i], false);
+            if let Some(line) = ar.current_line {
+                return format!("{}:{}: ", ar.short_src, line);
+            }
+        }
+        String::new()
+    }
+
+    // the active functions from the given level down to the main chunk
+    fn stack_frames(&self, level: usize) -> Vec<DebugInfo> {
+        let levels = self.levels();
+        levels.iter().skip(level).map(|&i| self.frame_info(i)).collect()
+    }
+
+    fn traceback(&self, msg: Option<&str>, level: usize) -> String {
+        let text = debug::traceback_text(&self.stack_frames(level));
+        match msg {
+            Some(msg) => format!("{}\n{}", msg, text),
+            None => text,
         }
     }
 }
@@ -588,26 +1069,381 @@ impl LuaAPI for LuaState {
 
 
 impl LuaState {
-    fn get_table_impl(&mut self, t: &LuaValue, k: &LuaValue) -> i8 {
-        if let LuaValue::Table(tbl) = t {
-            let v = tbl.borrow().get(k);
-            let type_id = v.type_id();
-            self.stack_mut().push(v);
-            type_id
+    // "attempt to <op> a <type> value", naming the variable that held the
+    // value when it is an operand of the running instruction
+    fn type_error(&self, v: &LuaValue, op: &str, operand: usize) -> LuaError {
+        let info = debug::var_info(self.stack(), v, operand);
+        let msg = format!("attempt to {} a {} value{}", op, v.type_name(), info);
+        self.runtime_error(&msg)
+    }
+
+    fn compare_error(&self, a: &LuaValue, b: &LuaValue) -> LuaError {
+        let (t1, t2) = (a.type_name(), b.type_name());
+        if t1 == t2 {
+            self.runtime_error(&format!("attempt to compare two {} values", t1))
         } else {
-            panic!("not a table!") // todo
+            self.runtime_error(&format!("attempt to compare {} with {}", t1, t2))
+        }
+    }
+
+    // gives the error to the message handler of the innermost pcall, at
+    // the point where it was raised, before any frame is unwound
+    fn handle_error(&mut self, mut err: LuaError) -> LuaError {
+        if err.handled {
+            return err;
+        }
+        err.handled = true;
+        let handler = match self.handlers.last() {
+            Some(Some(h)) if err.status() == LUA_ERRRUN => h.clone(),
+            Some(_) => return err,
+            None => {
+                // not caught by Lua code, keep the stack for the host
+                err.frames = Some(self.error_frames());
+                return err;
+            }
+        };
+
+        // errors inside the handler are not handled again
+        self.handlers.push(None);
+        let in_handler = std::mem::replace(&mut self.in_handler, true);
+        self.stack_mut().push(handler);
+        self.stack_mut().push(err.into_value());
+        let r = self.call(1, 1);
+        self.in_handler = in_handler;
+        self.handlers.pop();
+
+        let mut err = match r {
+            Ok(()) => LuaError::new(self.stack_mut().pop()),
+            Err(_) => {
+                let msg = LuaValue::new_string("error in error handling");
+                LuaError::with_status(LUA_ERRERR, msg)
+            }
+        };
+        err.handled = true;
+        err
+    }
+
+    // indexes into 'frames' of the active functions, from the running one
+    // (level 0) down
+    fn levels(&self) -> Vec<usize> {
+        (1..self.frames.len()).rev().collect() // frames[0] is not a function
+    }
+
+    // the active functions for an error report, with their variables if
+    // 'dump_locals' is set
+    fn error_frames(&self) -> Vec<DebugInfo> {
+        let levels = self.levels();
+        levels
+            .iter()
+            .map(|&i| {
+                let mut ar = self.frame_info(i);
+                if self.dump_locals {
+                    ar.locals = debug::frame_locals(&self.frames[i]);
+                    ar.upvalues = debug::frame_upvalues(&self.frames[i]);
+                }
+                ar
+            })
+            .collect()
+    }
+
+    fn frame_info(&self, i: usize) -> DebugInfo {
+        let frame = &self.frames[i];
+        let mut ar = DebugInfo::new(frame, frame.is_tail_call);
+        if !frame.is_tail_call {
+            if let Some((name_what, name)) = debug::func_name_from_code(&self.frames[i - 1]) {
+                ar.name_what = name_what;
+                ar.name = Some(name);
+            }
+        }
+        ar.global_name = self.global_func_name(&frame.closure);
+        ar
+    }
+
+    // looks for the function in the global table, then in the tables it
+    // holds (the libraries), like pushglobalfuncname
+    fn global_func_name(&self, c: &Rc<Closure>) -> Option<String> {
+        let globals = match self.globals() {
+            LuaValue::Table(g) => g,
+            _ => return None,
+        };
+        let is_func = |v: &LuaValue| matches!(v, LuaValue::Function(f) if Rc::ptr_eq(f, c));
+
+        let g = globals.borrow();
+        for (k, v) in g.map.iter() {
+            if let LuaValue::Str(name) = k {
+                if is_func(v) {
+                    return Some(name.to_string());
+                }
+            }
+        }
+        for (k, v) in g.map.iter() {
+            if let (LuaValue::Str(lib), LuaValue::Table(t)) = (k, v) {
+                if Rc::ptr_eq(t, &globals) {
+                    continue;
+                }
+                for (k, v) in t.borrow().map.iter() {
+                    if let LuaValue::Str(name) = k {
+                        if is_func(v) {
+                            return Some(format!("{}.{}", lib, name));
+                        }
+                    }
+                }
+            }
         }
+        None
     }
 
-    fn set_table_impl(t: &LuaValue, k: LuaValue, v: LuaValue) {
-        if let LuaValue::Table(tbl) = t {
-            tbl.borrow_mut().put(k, v);
+    fn check_index(&self, idx: isize) -> LuaResult<()> {
+        if self.stack().is_valid(idx) {
+            Ok(())
         } else {
-            panic!("not a table!");
+            Err(LuaError::from("invalid index"))
+        }
+    }
+
+    fn globals(&self) -> LuaValue {
+        match &self.registry {
+            LuaValue::Table(r) => r.borrow().get(&LUA_RIDX_GLOBALS),
+            _ => LuaValue::Nil,
+        }
+    }
+
+    // registers a new table or closure with the collector
+    fn new_object(&mut self, val: LuaValue) -> LuaValue {
+        self.gc.track(&val);
+        val
+    }
+
+    // pays the allocation debt with a step of the collector
+    fn check_gc(&mut self) {
+        self.check_limit(0);
+        if self.gc.needs_step() {
+            let work = self.gc.debt_work();
+            if let Err(err) = self.gc_step_impl(GcBudget::Work(work)) {
+                self.gc_error.get_or_insert(err);
+            }
+        }
+    }
+
+    fn gc_step_impl(&mut self, budget: GcBudget) -> LuaResult<bool> {
+        let (registry, mts, handlers, frames) =
+            (&self.registry, &self.type_metatables, &self.handlers, &self.frames);
+        let mut roots = |m: &mut Marker| mark_roots(m, registry, mts, handlers, frames);
+        let finished = self.gc.step(budget, &mut roots);
+        if finished {
+            self.count_stacks();
+            self.call_finalizers(false)?;
+        }
+        Ok(finished)
+    }
+
+    fn collect_garbage(&mut self) -> LuaResult<usize> {
+        let n = self.full_gc();
+        self.call_finalizers(false)?;
+        Ok(n)
+    }
+
+    // a whole cycle, leaving the finalizers pending
+    fn full_gc(&mut self) -> usize {
+        let (registry, mts, handlers, frames) =
+            (&self.registry, &self.type_metatables, &self.handlers, &self.frames);
+        let mut roots = |m: &mut Marker| mark_roots(m, registry, mts, handlers, frames);
+        let n = self.gc.full_collect(&mut roots);
+        self.count_stacks();
+        n
+    }
+
+    fn count_stacks(&mut self) {
+        self.strings.sweep();
+        let size = self.frames.iter().map(LuaStack::size).sum();
+        self.gc.set_stacks(size);
+    }
+
+    // accounts a grown table, already in place so that an emergency
+    // collection counts it again
+    fn charge(&mut self, bytes: usize) {
+        self.gc.allocate(bytes);
+        self.check_limit(0);
+    }
+
+    // over the memory limit with 'bytes' about to be allocated, an
+    // emergency collection (without finalizers) runs first; if it does not
+    // free enough, the error is left pending
+    fn check_limit(&mut self, bytes: usize) {
+        if !self.gc.fits(bytes) && !self.out_of_memory {
+            self.full_gc();
+            self.out_of_memory = !self.gc.fits(bytes);
+        }
+    }
+
+    // raises the pending memory or finalizer error, if any
+    fn check_pending(&mut self) -> LuaResult<()> {
+        if self.out_of_memory {
+            self.out_of_memory = false;
+            return Err(memory_error());
+        }
+        match self.gc_error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // calls '__gc' on the tables separated by the collector, which is
+    // stopped meanwhile. The first error stops the calls, the remaining
+    // finalizers stay pending; with 'all' they run anyway and the first
+    // error is returned at the end
+    fn call_finalizers(&mut self, all: bool) -> LuaResult<()> {
+        let running = self.gc.is_running();
+        self.gc.set_running(false);
+        let mut first_error = None;
+        while let Some(t) = self.gc.pending_finalizer() {
+            let obj = LuaValue::Table(t);
+            let tm = self.get_metafield(&obj, TM_GC);
+            if let LuaValue::Function(_) = tm {
+                self.stack_mut().push(tm);
+                self.stack_mut().push(obj);
+                let status = self.pcall(1, 0, 0);
+                if status != LUA_OK {
+                    let err = self.error();
+                    first_error.get_or_insert(gc_metamethod_error(status, err));
+                    if !all {
+                        break;
+                    }
+                }
+            }
+        }
+        self.gc.set_running(running);
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // the end of the state: every finalizer runs, reachable or not, then
+    // the objects are freed; a second time does nothing
+    fn close_state(&mut self) -> LuaResult<()> {
+        self.gc.separate_all();
+        let r = self.call_finalizers(true);
+        self.gc.free_all();
+        r
+    }
+
+    fn get_metatable_of(&self, val: &LuaValue) -> Option<Rc<RefCell<LuaTable>>> {
+        match val {
+            LuaValue::Table(t) => t.borrow().metatable.clone(),
+            _ => self.type_metatables[val.type_id() as usize].clone(),
+        }
+    }
+
+    // the raw value of the event (TM_*) in the metatable of 'val'
+    fn get_metafield(&self, val: &LuaValue, event: usize) -> LuaValue {
+        match self.get_metatable_of(val) {
+            Some(mt) => mt.borrow().get(&self.tm_names[event]),
+            None => LuaValue::Nil,
         }
     }
 
-    fn call_rust_closure(&mut self, nargs: usize, nresults: isize, c: Rc<Closure>) {
+    // calls the handler of the event found on 'a' or else on 'b' with both
+    // operands, returns its first result or None if neither has a handler
+    fn call_metamethod(&mut self, a: &LuaValue, b: &LuaValue, event: usize) -> LuaResult<Option<LuaValue>> {
+        let mut mm = self.get_metafield(a, event);
+        if mm.is_nil() {
+            mm = self.get_metafield(b, event);
+            if mm.is_nil() {
+                return Ok(None);
+            }
+        }
+        self.stack_mut().push(mm);
+        self.stack_mut().push(a.clone());
+        self.stack_mut().push(b.clone());
+        self.call(2, 1)?;
+        Ok(Some(self.stack_mut().pop()))
+    }
+
+    // pushes t[k], following '__index' when the key is absent (unless raw)
+    fn get_table_impl(&mut self, t: &LuaValue, k: &LuaValue, raw: bool) -> LuaResult<i8> {
+        let mut t = t.clone();
+        for _ in 0..MAXTAGLOOP {
+            let tm = if let LuaValue::Table(tbl) = &t {
+                let v = tbl.borrow().get(k);
+                let tm = if v.is_nil() && !raw { self.get_metafield(&t, TM_INDEX) } else { LuaValue::Nil };
+                if tm.is_nil() {
+                    let type_id = v.type_id();
+                    self.stack_mut().push(v);
+                    return Ok(type_id);
+                }
+                tm
+            } else if raw {
+                return Err(self.type_error(&t, "index", 0));
+            } else {
+                let tm = self.get_metafield(&t, TM_INDEX);
+                if tm.is_nil() {
+                    return Err(self.type_error(&t, "index", 0));
+                }
+                tm
+            };
+            if let LuaValue::Function(_) = tm {
+                // call the handler with the original table and the key
+                self.stack_mut().push(tm);
+                self.stack_mut().push(t);
+                self.stack_mut().push(k.clone());
+                self.call(2, 1)?;
+                return Ok(self.stack().get(-1).type_id());
+            }
+            t = tm; // try the access again on the handler
+        }
+        Err(self.runtime_error("'__index' chain too long; possible loop"))
+    }
+
+    // t[k] = v, following '__newindex' when the key is absent (unless raw)
+    fn set_table_impl(&mut self, t: &LuaValue, k: LuaValue, v: LuaValue, raw: bool) -> LuaResult<()> {
+        let mut t = t.clone();
+        for _ in 0..MAXTAGLOOP {
+            let tm = if let LuaValue::Table(tbl) = &t {
+                let absent = tbl.borrow().get(&k).is_nil();
+                let tm = if absent && !raw { self.get_metafield(&t, TM_NEWINDEX) } else { LuaValue::Nil };
+                if tm.is_nil() {
+                    match k {
+                        LuaValue::Nil => return Err(self.runtime_error("table index is nil")),
+                        LuaValue::Number(n) if n.is_nan() => {
+                            return Err(self.runtime_error("table index is NaN"))
+                        }
+                        _ => (),
+                    }
+                    self.gc.barrier_back(tbl);
+                    if !v.is_nil() && tbl.borrow().grows_for(&k) {
+                        let growth = gc::table_growth(&tbl.borrow());
+                        self.reserve(growth)?;
+                    }
+                    let before = gc::table_size(&tbl.borrow());
+                    tbl.borrow_mut().put(k, v);
+                    let after = gc::table_size(&tbl.borrow());
+                    self.charge(after.saturating_sub(before));
+                    return self.check_pending();
+                }
+                tm
+            } else if raw {
+                return Err(self.type_error(&t, "index", 0));
+            } else {
+                let tm = self.get_metafield(&t, TM_NEWINDEX);
+                if tm.is_nil() {
+                    return Err(self.type_error(&t, "index", 0));
+                }
+                tm
+            };
+            if let LuaValue::Function(_) = tm {
+                self.stack_mut().push(tm);
+                self.stack_mut().push(t);
+                self.stack_mut().push(k);
+                self.stack_mut().push(v);
+                return self.call(3, 0);
+            }
+            t = tm; // repeat the assignment over the handler
+        }
+        Err(self.runtime_error("'__newindex' chain too long; possible loop"))
+    }
+
+    fn call_rust_closure(&mut self, nargs: usize, nresults: isize, c: Rc<Closure>) -> LuaResult<()> {
         // create new lua stack
         let rust_fn = c.rust_fn.unwrap();
         let mut new_stack = LuaStack::new(nargs + LUA_MINSTACK, self.registry.clone(), c);
@@ -620,9 +1456,39 @@ impl LuaState {
         self.stack_mut().pop(); // pop func
 
         // run closure
-        self.push_frame(new_stack);
-        let r = rust_fn(self);
-        new_stack = self.pop_frame();
+        self.push_frame(new_stack)?;
+        let r = if self.catch_panics {
+            let depth = self.frames.len();
+            let nhandlers = self.handlers.len();
+            let n_ccalls = self.n_ccalls;
+            let in_handler = self.in_handler;
+            let quiet = self.quiet_panics as usize;
+            CATCHING.with(|n| n.set(n.get() + quiet));
+            let r = panic::catch_unwind(AssertUnwindSafe(|| rust_fn(self)));
+            CATCHING.with(|n| n.set(n.get() - quiet));
+            match r {
+                Ok(r) => r,
+                Err(payload) => {
+                    // drop the calls the function left half done
+                    while self.frames.len() > depth {
+                        self.pop_frame();
+                    }
+                    self.handlers.truncate(nhandlers);
+                    self.n_ccalls = n_ccalls;
+                    self.in_handler = in_handler;
+                    Err(LuaError::from(panic_message(payload)))
+                }
+            }
+        } else {
+            rust_fn(self)
+        };
+        let r = match r {
+            Ok(n) => self.check_pending().map(|()| n),
+            err => err,
+        };
+        let r = r.map_err(|err| self.handle_error(err));
+        new_stack = self.pop_frame(); // the frame is gone, even on error
+        let r = r?;
 
         // return results
         if nresults != 0 {
@@ -630,9 +1496,29 @@ impl LuaState {
             self.stack_mut().check(results.len());
             self.stack_mut().push_n(results, nresults);
         }
+        Ok(())
     }
 
-    fn call_lua_closure(&mut self, nargs: usize, nresults: isize, c: Rc<Closure>) {
+    // the function below the 'nargs' arguments on top, or its '__call'
+    // handler with the object inserted as the first argument
+    fn callee(&mut self, nargs: usize) -> LuaResult<(usize, Rc<Closure>)> {
+        let val = self.stack().get(-(nargs as isize + 1));
+        match val {
+            LuaValue::Function(c) => Ok((nargs, c)),
+            _ => match self.get_metafield(&val, TM_CALL) {
+                LuaValue::Function(c) => {
+                    self.stack_mut().push(LuaValue::Function(c.clone()));
+                    self.insert(-(nargs as isize + 2))?;
+                    Ok((nargs + 1, c))
+                }
+                _ => Err(self.type_error(&val, "call", 0)),
+            },
+        }
+    }
+
+    // moves the function and its arguments from the top of the running
+    // frame into a new frame for the Lua function
+    fn lua_frame(&mut self, nargs: usize, c: Rc<Closure>) -> LuaStack {
         let nregs = c.proto.max_stack_size as usize;
         let nparams = c.proto.num_params as usize;
         let is_vararg = c.proto.is_vararg == 1;
@@ -655,37 +1541,809 @@ impl LuaState {
             }
         }
         new_stack.push_n(args, nparams as isize);
-        new_stack.set_top(nregs as isize);
-
-        // run closure
-        self.push_frame(new_stack);
-        self.run_lua_closure();
-        new_stack = self.pop_frame();
-
-        // return results
-        if nresults != 0 {
-            let nrets = new_stack.top() as usize - nregs;
-            let results = new_stack.pop_n(nrets);
+        new_stack.resize(nregs);
+        new_stack
+    }
+
+    // pops the frame of a Lua function that executed RETURN, its results
+    // go to the caller adjusted to the number it expects
+    fn post_call(&mut self) {
+        let mut frame = self.pop_frame();
+        if frame.nresults != 0 {
+            let nregs = frame.closure.proto.max_stack_size as usize;
+            let nrets = frame.top() as usize - nregs;
+            let results = frame.pop_n(nrets);
             self.stack_mut().check(nrets);
-            self.stack_mut().push_n(results, nresults);
+            self.stack_mut().push_n(results, frame.nresults);
+        }
+    }
+
+    // runs Lua functions until the frame at depth 'base' returns; calls
+    // from Lua to Lua push a frame and their returns pop it in this loop,
+    // so they do not nest on the Rust stack. On error the frames from
+    // 'base' up are gone, after the message handler saw them
+    fn execute(&mut self, base: usize) -> LuaResult<()> {
+        let r = self.run_lua_closure(base).map_err(|err| self.handle_error(err));
+        if r.is_err() {
+            while self.frames.len() >= base {
+                self.pop_frame();
+            }
         }
+        r
     }
 
-    fn run_lua_closure(&mut self) {
-        let mut cnt = 1;
+    fn run_lua_closure(&mut self, base: usize) -> LuaResult<()> {
         loop {
             let instr = self.fetch();
-            instr.execute(self);
+            instr.execute(self)?;
+            self.check_pending()?;
 
             //DEBUG
-            //print!("({})",cnt);
-            //cnt += 1;
             //self.print_stack(instr.opname());
-            //println!("now upvals: {:?}",self.stack().closure.upvalues.borrow());
 
             if instr.opcode() == crate::vm::opcodes::OP_RETURN {
+                let depth = self.frames.len();
+                self.post_call();
+                if depth == base {
+                    return Ok(());
+                }
+                // back in the caller, finish the CALL that pushed the frame
+                let pc = self.stack().pc;
+                let i = self.stack().closure.proto.code[pc as usize - 1];
+                i.finish_call(self)?;
+            }
+        }
+    }
+}
+
+impl Drop for LuaState {
+    fn drop(&mut self) {
+        let _ = self.close_state(); // nobody left to report to
+    }
+}
+
+// a runtime error in a finalizer becomes "error in __gc metamethod (msg)"
+// with status LUA_ERRGCMM, other errors keep their status
+fn gc_metamethod_error(status: u8, err: LuaError) -> LuaError {
+    if status != LUA_ERRRUN {
+        return LuaError::with_status(status, err.into_value());
+    }
+    let msg = match err.value() {
+        LuaValue::Str(s) => s.to_string(),
+        _ => "no message".to_string(),
+    };
+    let msg = format!("error in __gc metamethod ({})", msg);
+    LuaError::with_status(LUA_ERRGCMM, LuaValue::new_string(&msg))
+}
+
+thread_local! {
+    // Rust functions of quiet states running under catch_unwind on this
+    // thread
+    static CATCHING: Cell<usize> = const { Cell::new(0) };
+}
+
+// installs, once, a panic hook that keeps quiet about the panics that turn
+// into Lua errors and hands the others to the hook it replaced
+fn quiet_caught_panics() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CATCHING.with(|n| n.get()) == 0 {
+                previous(info);
+            }
+        }));
+    });
+}
+
+fn memory_error() -> LuaError {
+    let msg = LuaValue::new_string("not enough memory");
+    LuaError::with_status(LUA_ERRMEM, msg)
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    let msg = if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        String::from("(no message)")
+    };
+    format!("Rust function panicked: {}", msg)
+}
+
+// marks the values the program can reach directly
+fn mark_roots(
+    m: &mut Marker,
+    registry: &LuaValue,
+    type_metatables: &[Option<Rc<RefCell<LuaTable>>>],
+    handlers: &[Option<LuaValue>],
+    frames: &[LuaStack],
+) {
+    m.mark_value(registry);
+    for mt in type_metatables.iter().flatten() {
+        m.mark_value(&LuaValue::Table(mt.clone()));
+    }
+    for handler in handlers.iter().flatten() {
+        m.mark_value(handler);
+    }
+    for frame in frames.iter() {
+        m.mark_value(&LuaValue::Function(frame.closure.clone()));
+        frame.values().iter().for_each(|val| m.mark_value(val));
+        frame.varargs.iter().for_each(|val| m.mark_value(val));
+        frame.openuvs.values().for_each(|uv| m.mark_cell(uv));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Weak;
+    use std::time::Duration;
+
+    fn field(t: &LuaValue, k: &str) -> LuaValue {
+        match t {
+            LuaValue::Table(t) => t.borrow().get(&LuaValue::new_string(k)),
+            _ => LuaValue::Nil,
+        }
+    }
+
+    fn weak_table(t: &LuaValue) -> Weak<RefCell<LuaTable>> {
+        match t {
+            LuaValue::Table(t) => Rc::downgrade(t),
+            _ => Weak::new(),
+        }
+    }
+
+    fn weak_closure(f: &LuaValue) -> Weak<Closure> {
+        match f {
+            LuaValue::Function(c) => Rc::downgrade(c),
+            _ => Weak::new(),
+        }
+    }
+
+    fn nop(_: &mut dyn LuaAPI) -> LuaResult<usize> {
+        Ok(0)
+    }
+
+    // pushes a table t with t.self = t, returns it
+    fn push_self_cycle(ls: &mut LuaState) -> LuaValue {
+        ls.new_table();
+        ls.push_value(-1);
+        ls.set_field(-2, "self").unwrap();
+        ls.stack().get(-1)
+    }
+
+    #[test]
+    fn cycles_are_collected() {
+        let mut ls = LuaState::new();
+        let t = push_self_cycle(&mut ls);
+        ls.pop(1);
+
+        // a.other = b, b.other = a
+        ls.new_table();
+        ls.new_table();
+        ls.push_value(-2);
+        ls.set_field(-2, "other").unwrap();
+        ls.push_value(-1);
+        ls.set_field(-3, "other").unwrap();
+        let (a, b) = (ls.stack().get(-2), ls.stack().get(-1));
+        ls.pop(2);
+
+        // c.f = a function with c as its upvalue
+        ls.new_table();
+        ls.push_value(-1);
+        ls.push_rust_closure(nop, 1);
+        let f = ls.stack().get(-1);
+        ls.set_field(-2, "f").unwrap();
+        let c = ls.stack().get(-1);
+        ls.pop(1);
+
+        let weak = [weak_table(&t), weak_table(&a), weak_table(&b), weak_table(&c)];
+        let weak_f = weak_closure(&f);
+        drop((t, a, b, c, f));
+        assert!(weak.iter().all(|w| w.upgrade().is_some()));
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert!(weak.iter().all(|w| w.upgrade().is_none()));
+        assert!(weak_f.upgrade().is_none());
+    }
+
+    #[test]
+    fn collecting_cycles_gives_the_memory_back() {
+        let mut ls = LuaState::new();
+        ls.gc(LUA_GCSTOP, 0).unwrap();
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        let before = ls.gc(LUA_GCCOUNT, 0).unwrap();
+        for _ in 0..1000 {
+            push_self_cycle(&mut ls);
+            for i in 1..=100 {
+                ls.push_integer(i);
+                ls.set_i(-2, i).unwrap();
+            }
+            ls.pop(1);
+        }
+        assert!(ls.gc(LUA_GCCOUNT, 0).unwrap() > before + 1000); // over 1KB each
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert!(ls.gc(LUA_GCCOUNT, 0).unwrap() <= before + 1);
+    }
+
+    #[test]
+    fn objects_held_by_the_host_stay_alive() {
+        let mut ls = LuaState::new();
+        let t = push_self_cycle(&mut ls);
+        ls.new_table();
+        ls.set_field(-2, "child").unwrap();
+        ls.pop(1);
+
+        // only this Rust value holds the cycle, the collector must see it
+        // as a root and leave the table and what it reaches untouched
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert!(field(&t, "self") == t);
+        let child = field(&t, "child");
+        assert!(matches!(child, LuaValue::Table(_)));
+
+        // the same for a value held by an error the host caught
+        fn raise(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+            ls.new_table();
+            ls.push_value(-1);
+            ls.set_field(-2, "self")?;
+            Err(ls.error())
+        }
+        ls.push_rust_function(raise);
+        let err = ls.call(0, 0).unwrap_err();
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert!(field(err.value(), "self") == *err.value());
+
+        // once let go, they are collected
+        let weak = [weak_table(&t), weak_table(&child), weak_table(err.value())];
+        drop((t, child, err));
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert!(weak.iter().all(|w| w.upgrade().is_none()));
+    }
+
+    thread_local! {
+        // the ids of the finalized tables, in the order of the calls
+        static FINALIZED: RefCell<Vec<i64>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn finalized() -> Vec<i64> {
+        FINALIZED.with(|log| log.borrow_mut().drain(..).collect())
+    }
+
+    // __gc that logs the id of the table
+    fn log_gc(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+        ls.get_field(1, "id")?;
+        let id = ls.to_integer(-1);
+        FINALIZED.with(|log| log.borrow_mut().push(id));
+        Ok(0)
+    }
+
+    // __gc that also stores the table in the global 'saved'
+    fn save_gc(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+        log_gc(ls)?;
+        ls.push_value(1);
+        ls.set_global("saved")?;
+        Ok(0)
+    }
+
+    // pushes a table {id = id} with 'gc' as its finalizer
+    fn push_finalized(ls: &mut LuaState, id: i64, gc: RustFn) -> LuaValue {
+        ls.new_table();
+        ls.push_integer(id);
+        ls.set_field(-2, "id").unwrap();
+        ls.new_table();
+        ls.push_rust_function(gc);
+        ls.set_field(-2, "__gc").unwrap();
+        ls.set_metatable(-2).unwrap();
+        ls.stack().get(-1)
+    }
+
+    #[test]
+    fn finalizers_run_in_reverse_order() {
+        let mut ls = LuaState::new();
+        for id in 1..=3 {
+            push_finalized(&mut ls, id, log_gc);
+        }
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert!(finalized().is_empty()); // still on the stack
+
+        ls.pop(3);
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert_eq!(finalized(), [3, 2, 1]);
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert!(finalized().is_empty()); // once only
+    }
+
+    #[test]
+    fn finalizers_can_resurrect_objects() {
+        let mut ls = LuaState::new();
+        let t = push_finalized(&mut ls, 1, save_gc);
+        ls.new_table();
+        ls.push_integer(5);
+        ls.set_field(-2, "x").unwrap();
+        ls.set_field(-2, "child").unwrap();
+        ls.pop(1);
+        let weak = weak_table(&t);
+        drop(t);
+
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert_eq!(finalized(), [1]);
+        // saved by its finalizer, with what it reaches
+        ls.get_global("saved").unwrap();
+        let saved = ls.stack().get(-1);
+        ls.pop(1);
+        assert!(weak.upgrade().is_some_and(|t| saved == LuaValue::Table(t)));
+        assert!(field(&field(&saved, "child"), "x") == LuaValue::Integer(5));
+        drop(saved);
+
+        // the finalizer does not run again, the object is freed for good
+        ls.push_nil();
+        ls.set_global("saved").unwrap();
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert!(finalized().is_empty());
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn close_runs_the_pending_finalizers() {
+        let mut ls = LuaState::new();
+        for id in 1..=3 {
+            push_finalized(&mut ls, id, log_gc);
+            ls.set_global(&format!("t{}", id)).unwrap();
+        }
+        push_finalized(&mut ls, 4, log_gc); // left on the stack
+        ls.close().unwrap();
+        assert_eq!(finalized(), [4, 3, 2, 1]);
+
+        // dropping the state does the same
+        let mut ls = LuaState::new();
+        push_finalized(&mut ls, 1, log_gc);
+        ls.set_global("t").unwrap();
+        push_finalized(&mut ls, 2, log_gc);
+        drop(ls);
+        assert_eq!(finalized(), [2, 1]);
+    }
+
+    // __gc that logs the id of the table and fails with "<id> failed"
+    fn fail_gc(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+        log_gc(ls)?;
+        let msg = format!("{} failed", ls.to_integer(-1));
+        ls.push_string(msg);
+        Err(ls.error())
+    }
+
+    #[test]
+    fn finalizer_errors_stop_the_collection() {
+        let mut ls = LuaState::new();
+        push_finalized(&mut ls, 1, log_gc);
+        push_finalized(&mut ls, 2, fail_gc);
+        ls.set_top(0).unwrap();
+        let err = ls.gc(LUA_GCCOLLECT, 0).unwrap_err();
+        assert_eq!(err.status(), LUA_ERRGCMM);
+        assert_eq!(err.to_string(), "error in __gc metamethod (2 failed)");
+        // the other finalizer is still pending, for the next collection
+        assert_eq!(finalized(), [2]);
+        assert_eq!(ls.gc(LUA_GCCOLLECT, 0).unwrap(), 0);
+        assert_eq!(finalized(), [1]);
+        assert_eq!(ls.get_top(), 0);
+    }
+
+    #[test]
+    fn finalizer_errors_of_automatic_steps_are_raised_later() {
+        let mut ls = LuaState::new();
+        push_finalized(&mut ls, 1, fail_gc);
+        ls.pop(1);
+        // the steps run from functions that cannot fail
+        while ls.gc_error.is_none() {
+            ls.new_table();
+            ls.pop(1);
+        }
+        assert_eq!(finalized(), [1]);
+        ls.push_rust_function(|_| Ok(0));
+        assert_eq!(ls.pcall(0, 0, 0), LUA_ERRGCMM);
+        assert_eq!(ls.to_string(-1), "error in __gc metamethod (1 failed)");
+        ls.pop(1);
+        ls.push_rust_function(|_| Ok(0));
+        assert_eq!(ls.pcall(0, 0, 0), LUA_OK);
+    }
+
+    #[test]
+    fn close_runs_every_finalizer_and_returns_the_first_error() {
+        let mut ls = LuaState::new();
+        push_finalized(&mut ls, 1, fail_gc);
+        push_finalized(&mut ls, 2, log_gc);
+        push_finalized(&mut ls, 3, fail_gc);
+        let err = ls.close().unwrap_err();
+        assert_eq!(err.status(), LUA_ERRGCMM);
+        assert_eq!(err.to_string(), "error in __gc metamethod (3 failed)");
+        assert_eq!(finalized(), [3, 2, 1]);
+    }
+
+    // pushes a table with the given '__mode'
+    fn push_weak_table(ls: &mut LuaState, mode: &str) -> LuaValue {
+        ls.new_table();
+        ls.new_table();
+        ls.push_string(mode.to_string());
+        ls.set_field(-2, "__mode").unwrap();
+        ls.set_metatable(-2).unwrap();
+        ls.stack().get(-1)
+    }
+
+    fn entries(t: &LuaValue) -> (usize, usize) {
+        match t {
+            LuaValue::Table(t) => {
+                let t = t.borrow();
+                (t.arr.iter().filter(|v| !v.is_nil()).count(), t.map.values().filter(|v| !v.is_nil()).count())
+            }
+            _ => (0, 0),
+        }
+    }
+
+    #[test]
+    fn weak_values_are_cleared() {
+        let mut ls = LuaState::new();
+        let t = push_weak_table(&mut ls, "v"); // 1
+        ls.new_table(); // 2, kept on the stack
+        let kept = ls.stack().get(2);
+        ls.push_value(2);
+        ls.set_i(1, 1).unwrap();
+        ls.push_integer(5);
+        ls.set_i(1, 2).unwrap();
+        ls.new_table();
+        ls.set_i(1, 3).unwrap();
+        ls.new_table();
+        ls.set_field(1, "x").unwrap();
+        ls.push_value(2);
+        ls.set_field(1, "keep").unwrap();
+        ls.push_string("str".to_string());
+        ls.set_field(1, "s").unwrap();
+        assert_eq!(entries(&t), (3, 3));
+
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        // gone from the array and the hash part
+        assert_eq!(entries(&t), (2, 2));
+        assert_eq!(ls.raw_len(1), 2);
+        assert!(field(&t, "x").is_nil());
+        assert!(field(&t, "keep") == kept);
+        assert!(field(&t, "s") == LuaValue::new_string("str"));
+    }
+
+    #[test]
+    fn weak_keys_are_cleared() {
+        let mut ls = LuaState::new();
+        let t = push_weak_table(&mut ls, "k"); // 1
+        ls.new_table(); // 2, kept on the stack
+        ls.new_table();
+        ls.push_integer(1);
+        ls.set_table(1).unwrap(); // t[{}] = 1
+        ls.push_value(2);
+        ls.push_integer(2);
+        ls.set_table(1).unwrap(); // t[kept] = 2
+        ls.new_table();
+        ls.set_field(1, "name").unwrap(); // values are strong
+        assert_eq!(entries(&t), (0, 3));
+
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert_eq!(entries(&t), (0, 2));
+        ls.push_value(2);
+        ls.get_table(1).unwrap();
+        assert_eq!(ls.to_integer(-1), 2);
+        assert!(matches!(field(&t, "name"), LuaValue::Table(_)));
+    }
+
+    #[test]
+    fn weak_keys_and_values_are_cleared() {
+        let mut ls = LuaState::new();
+        let t = push_weak_table(&mut ls, "kv"); // 1
+        ls.new_table(); // 2, kept on the stack
+        ls.new_table();
+        ls.push_value(2);
+        ls.set_table(1).unwrap(); // t[{}] = kept
+        ls.push_value(2);
+        ls.new_table();
+        ls.set_table(1).unwrap(); // t[kept] = {}
+        ls.push_value(2);
+        ls.push_value(2);
+        ls.set_field(1, "both").unwrap(); // t.both = kept
+        ls.pop(1);
+
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert_eq!(entries(&t), (0, 1));
+        assert!(field(&t, "both") == ls.stack().get(2));
+    }
+
+    #[test]
+    fn ephemerons_keep_values_only_through_live_keys() {
+        let mut ls = LuaState::new();
+        let e = push_weak_table(&mut ls, "k"); // 1
+        ls.new_table(); // 2, k1 kept on the stack
+
+        // e[k1] = k2, e[k2] = v: alive through the chain from k1
+        ls.new_table();
+        let k2 = ls.stack().get(-1);
+        ls.push_value(2);
+        ls.push_value(-2);
+        ls.set_table(1).unwrap();
+        ls.new_table();
+        let v = ls.stack().get(-1);
+        ls.set_table(1).unwrap();
+
+        // e[k3] = v3 with v3.key = k3: only the value refers to the key
+        ls.new_table();
+        let k3 = ls.stack().get(-1);
+        ls.new_table();
+        let v3 = ls.stack().get(-1);
+        ls.push_value(-2);
+        ls.set_field(-2, "key").unwrap();
+        ls.set_table(1).unwrap();
+        assert_eq!(entries(&e), (0, 3));
+
+        let weak = [weak_table(&k2), weak_table(&v), weak_table(&k3), weak_table(&v3)];
+        drop((k2, v, k3, v3));
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert_eq!(entries(&e), (0, 2));
+        assert!(weak[0].upgrade().is_some() && weak[1].upgrade().is_some());
+        // k3 is left as a dead key, emptied
+        assert!(weak[2].upgrade().is_some_and(|k3| Rc::strong_count(&k3) == 2 && k3.borrow().map.is_empty()));
+        assert!(weak[3].upgrade().is_none());
+
+        // without k1 the whole chain goes
+        ls.pop(1);
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert_eq!(entries(&e), (0, 0));
+        assert!(weak[1].upgrade().is_none() && weak[3].upgrade().is_none());
+
+        // and the dead keys with it once a new key needs their room
+        fill_hash_part(&mut ls, 1);
+        assert!(weak.iter().all(|w| w.upgrade().is_none()));
+    }
+
+    // stores new keys in the table at 'idx' until its hash part is full,
+    // and one more, dropping the dead keys
+    fn fill_hash_part(ls: &mut LuaState, idx: isize) {
+        let t = match ls.stack().get(idx) {
+            LuaValue::Table(t) => t,
+            _ => unreachable!(),
+        };
+        let mut i = 0.5;
+        loop {
+            let full = {
+                let t = t.borrow();
+                t.map.len() == t.map.capacity()
+            };
+            ls.push_number(i);
+            ls.push_boolean(true);
+            ls.set_table(idx).unwrap();
+            if full {
                 break;
             }
+            i += 1.0;
+        }
+    }
+
+    #[test]
+    fn weak_entries_cleared_during_a_traversal() {
+        let mut ls = LuaState::new();
+        let t = push_weak_table(&mut ls, "kv"); // 1
+        ls.new_table(); // 2, kept on the stack
+        for name in ["a", "b", "c", "d"].iter() {
+            ls.new_table();
+            ls.set_field(1, name).unwrap(); // t.<name> = {}
+        }
+        ls.push_value(2);
+        ls.set_field(1, "kept").unwrap();
+        ls.new_table();
+        ls.push_integer(1);
+        ls.set_table(1).unwrap(); // t[{}] = 1
+
+        // visit "a", collect: every value but 'kept' goes, "a" included
+        ls.push_nil();
+        assert!(ls.next(1).unwrap());
+        assert_eq!(ls.to_string(-2), "a");
+        ls.pop(1);
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert_eq!(entries(&t), (0, 1));
+
+        // the traversal goes on from the dead key
+        assert!(ls.next(1).unwrap());
+        assert_eq!(ls.to_string(-2), "kept");
+        assert!(ls.raw_equal(-1, 2));
+        ls.pop(1);
+        assert!(!ls.next(1).unwrap());
+        assert_eq!(ls.get_top(), 2);
+    }
+
+    #[test]
+    fn resurrected_objects_leave_weak_values_first() {
+        let mut ls = LuaState::new();
+        let wv = push_weak_table(&mut ls, "v"); // 1
+        let wk = push_weak_table(&mut ls, "k"); // 2
+        push_finalized(&mut ls, 1, save_gc);
+        ls.push_value(-1);
+        ls.set_i(1, 1).unwrap(); // wv[1] = obj
+        ls.push_boolean(true);
+        ls.set_table(2).unwrap(); // wk[obj] = true
+
+        // the value is cleared before the finalizer runs, the key stays
+        // as long as the object is resurrected
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert_eq!(finalized(), [1]);
+        assert_eq!(entries(&wv), (0, 0));
+        assert_eq!(entries(&wk), (0, 1));
+
+        ls.push_nil();
+        ls.set_global("saved").unwrap();
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert_eq!(entries(&wk), (0, 0));
+    }
+
+    // pushes and drops 'n' cycles
+    fn make_garbage(ls: &mut LuaState, n: usize) {
+        for _ in 0..n {
+            push_self_cycle(ls);
+            ls.pop(1);
+        }
+    }
+
+    // runs steps of 'work' bytes until one finishes a cycle, with 'between'
+    // running before each as the program would; returns the steps taken
+    fn step_cycle(ls: &mut LuaState, work: usize, mut between: impl FnMut(&mut LuaState)) -> usize {
+        let mut steps = 0;
+        loop {
+            between(ls);
+            steps += 1;
+            if ls.gc_step(GcBudget::Work(work)).unwrap() {
+                return steps;
+            }
+        }
+    }
+
+    #[test]
+    fn a_step_does_a_bounded_part_of_the_cycle() {
+        let mut ls = LuaState::new();
+        ls.gc(LUA_GCSTOP, 0).unwrap();
+        let weak: Vec<_> = (0..5000).map(|_| weak_table(&push_self_cycle(&mut ls))).collect();
+        ls.pop(5000);
+
+        for _ in 0..100 {
+            assert!(!ls.gc_step(GcBudget::Time(Duration::ZERO)).unwrap());
+        }
+        assert!(weak.iter().all(|w| w.upgrade().is_some()));
+
+        // freeing the candidates is spread over the steps too
+        let steps = step_cycle(&mut ls, 1024, |_| ());
+        assert!(steps > 100, "{} steps", steps);
+        assert!(weak.iter().all(|w| w.upgrade().is_none()));
+
+        make_garbage(&mut ls, 5000);
+        while !ls.gc_step(GcBudget::Time(Duration::from_micros(100))).unwrap() {}
+    }
+
+    #[test]
+    fn stores_into_black_tables_between_steps_are_kept() {
+        let mut ls = LuaState::new();
+        ls.gc(LUA_GCSTOP, 0).unwrap();
+        ls.new_table(); // from, 1
+        ls.new_table(); // to, 2
+        for i in 1..=200 {
+            push_self_cycle(&mut ls);
+            ls.set_i(1, i).unwrap();
+        }
+        make_garbage(&mut ls, 1000);
+
+        // a cycle at a time moves from 'from' to 'to', whichever of them
+        // the collector has traversed
+        let mut moved = 0;
+        let steps = step_cycle(&mut ls, 64, |ls| {
+            if moved < 200 {
+                moved += 1;
+                ls.get_i(1, moved).unwrap();
+                ls.set_i(2, moved).unwrap();
+                ls.push_nil();
+                ls.set_i(1, moved).unwrap();
+                make_garbage(ls, 5);
+            }
+        });
+        assert!(steps > 20, "{} steps", steps);
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        for i in 1..=200 {
+            ls.get_i(if i <= moved { 2 } else { 1 }, i).unwrap();
+            let t = ls.stack_mut().pop();
+            assert!(field(&t, "self") == t, "cycle {} was cleared", i);
+        }
+    }
+
+    #[test]
+    fn candidates_changed_between_steps_are_kept() {
+        let mut ls = LuaState::new();
+        ls.gc(LUA_GCSTOP, 0).unwrap();
+        let p = push_self_cycle(&mut ls);
+        let q = push_self_cycle(&mut ls);
+        ls.pop(2);
+
+        // only these Rust values hold p and q; p lets q go at some step of
+        // the cycle, after the collector may have counted the reference
+        let set_child = |ls: &mut LuaState, child: &LuaValue| {
+            ls.stack_mut().push(p.clone());
+            ls.stack_mut().push(child.clone());
+            ls.set_field(-2, "child").unwrap();
+            ls.pop(1);
+        };
+        for at in 1..80 {
+            set_child(&mut ls, &q);
+            make_garbage(&mut ls, 1000);
+            let mut n = 0;
+            step_cycle(&mut ls, 64, |ls| {
+                n += 1;
+                if n == at {
+                    set_child(ls, &LuaValue::Nil);
+                }
+            });
+            assert!(field(&p, "self") == p);
+            assert!(field(&q, "self") == q, "cleared letting go at step {}", at);
+        }
+    }
+
+    #[test]
+    fn values_taken_from_weak_tables_between_steps_are_kept() {
+        let mut ls = LuaState::new();
+        ls.gc(LUA_GCSTOP, 0).unwrap();
+        push_weak_table(&mut ls, "v"); // 1
+
+        // the only reference to the cycle is weak until the host takes it
+        // at some step of the cycle
+        for at in 1..80 {
+            push_self_cycle(&mut ls);
+            ls.set_i(1, 1).unwrap();
+            make_garbage(&mut ls, 1000);
+            let mut n = 0;
+            let mut taken = LuaValue::Nil;
+            step_cycle(&mut ls, 64, |ls| {
+                n += 1;
+                if n == at {
+                    ls.get_i(1, 1).unwrap();
+                    taken = ls.stack_mut().pop();
+                }
+            });
+            if !taken.is_nil() {
+                assert!(field(&taken, "self") == taken, "cleared taking it at step {}", at);
+            }
+        }
+    }
+
+    // sets its upvalue to its argument
+    fn set_upvalue(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+        ls.copy(1, LUA_REGISTRYINDEX - 1)?;
+        Ok(0)
+    }
+
+    #[test]
+    fn upvalues_changed_between_steps_are_kept() {
+        let mut ls = LuaState::new();
+        ls.gc(LUA_GCSTOP, 0).unwrap();
+        let q = push_self_cycle(&mut ls);
+        ls.push_rust_closure(set_upvalue, 1);
+        let f = ls.stack_mut().pop();
+
+        // f is held by this Rust value only, like q; its upvalue lets q go
+        // at some step of the cycle
+        let set = |ls: &mut LuaState, val: &LuaValue| {
+            ls.stack_mut().push(f.clone());
+            ls.stack_mut().push(val.clone());
+            ls.call(1, 0).unwrap();
+        };
+        for at in 1..80 {
+            set(&mut ls, &q);
+            make_garbage(&mut ls, 1000);
+            let mut n = 0;
+            step_cycle(&mut ls, 64, |ls| {
+                n += 1;
+                if n == at {
+                    set(ls, &LuaValue::Nil);
+                }
+            });
+            assert!(field(&q, "self") == q, "cleared letting go at step {}", at);
         }
     }
 }
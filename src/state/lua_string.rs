@@ -0,0 +1,155 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+/* strings up to this length are interned (LUAI_MAXSHORTLEN) */
+const MAX_SHORT_LEN: usize = 40;
+
+/* at most about 2^HASH_LIMIT bytes of a string enter its hash */
+const HASH_LIMIT: usize = 5;
+const HASH_SEED: u32 = 0x2545_f491;
+
+/* initial number of buckets of the string table (MINSTRTABSIZE) */
+const MIN_STRTAB_SIZE: usize = 128;
+
+// an immutable byte string, shared by all its copies, with its hash
+#[derive(Clone)]
+pub struct LuaString {
+    bytes: Rc<[u8]>,
+    hash: u32,
+}
+
+impl LuaString {
+    pub fn new(bytes: &[u8]) -> LuaString {
+        LuaString {
+            bytes: Rc::from(bytes),
+            hash: hash(bytes),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // the text of the string, if it is valid UTF-8
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.bytes).ok()
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(s: &str) -> LuaString {
+        LuaString::new(s.as_bytes())
+    }
+}
+
+impl PartialEq for LuaString {
+    fn eq(&self, other: &LuaString) -> bool {
+        // interned copies share their buffer
+        Rc::ptr_eq(&self.bytes, &other.bytes) || (self.hash == other.hash && self.bytes == other.bytes)
+    }
+}
+
+impl Eq for LuaString {}
+
+impl Hash for LuaString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u32(self.hash);
+    }
+}
+
+impl fmt::Display for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.bytes))
+    }
+}
+
+impl fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(&self.bytes))
+    }
+}
+
+// like luaS_hash: long strings are sampled, so hashing is cheap for all
+fn hash(bytes: &[u8]) -> u32 {
+    let mut h = HASH_SEED ^ bytes.len() as u32;
+    let step = (bytes.len() >> HASH_LIMIT) + 1;
+    let mut l = bytes.len();
+    while l >= step {
+        h ^= (h << 5).wrapping_add(h >> 2).wrapping_add(bytes[l - 1] as u32);
+        l -= step;
+    }
+    h
+}
+
+// the short strings of a state, each one kept in a single copy
+pub struct StringTable {
+    buckets: Vec<Vec<LuaString>>,
+    count: usize,
+}
+
+impl Default for StringTable {
+    fn default() -> StringTable {
+        StringTable::new()
+    }
+}
+
+impl StringTable {
+    pub fn new() -> StringTable {
+        StringTable {
+            buckets: vec![Vec::new(); MIN_STRTAB_SIZE],
+            count: 0,
+        }
+    }
+
+    // the string with these bytes, the interned copy if it is short
+    pub fn intern(&mut self, bytes: &[u8]) -> LuaString {
+        if bytes.len() > MAX_SHORT_LEN {
+            return LuaString::new(bytes);
+        }
+        let h = hash(bytes);
+        let i = h as usize & (self.buckets.len() - 1);
+        if let Some(s) = self.buckets[i].iter().find(|s| s.hash == h && &*s.bytes == bytes) {
+            return s.clone();
+        }
+        if self.count >= self.buckets.len() {
+            self.resize(self.buckets.len() * 2);
+        }
+        let s = LuaString {
+            bytes: Rc::from(bytes),
+            hash: h,
+        };
+        let i = h as usize & (self.buckets.len() - 1);
+        self.buckets[i].push(s.clone());
+        self.count += 1;
+        s
+    }
+
+    // drops the strings only the table still holds, at the end of a cycle
+    pub fn sweep(&mut self) {
+        for bucket in self.buckets.iter_mut() {
+            bucket.retain(|s| Rc::strong_count(&s.bytes) > 1);
+        }
+        self.count = self.buckets.iter().map(Vec::len).sum();
+        let size = self.buckets.len();
+        if self.count < size / 4 && size > MIN_STRTAB_SIZE {
+            self.resize(size / 2);
+        }
+    }
+
+    fn resize(&mut self, size: usize) {
+        let old = std::mem::replace(&mut self.buckets, vec![Vec::new(); size]);
+        for s in old.into_iter().flatten() {
+            let i = s.hash as usize & (size - 1);
+            self.buckets[i].push(s);
+        }
+    }
+}
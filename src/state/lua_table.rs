@@ -1,17 +1,160 @@
 use super::lua_value::LuaValue;
-use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::mem;
+use std::rc::Rc;
+
+/* integer keys up to 2^MAXABITS are candidates for the array part */
+const MAXABITS: usize = 31;
 
 #[derive(Clone)]
 pub struct LuaTable {
     pub arr: Vec<LuaValue>,
-    pub map: HashMap<LuaValue, LuaValue>,
-    rdm: usize, // hash code
+    pub map: LuaMap,
+    pub metatable: Option<Rc<RefCell<LuaTable>>>,
+}
+
+// the hash part of a table, its entries in insertion order. As in the
+// nodes of a Lua table, a key whose value goes to nil stays (a dead key),
+// so that a traversal can go on from it; the dead keys go away when the
+// part is full and a new key comes in
+#[derive(Clone, Default)]
+pub struct LuaMap {
+    entries: Vec<(LuaValue, LuaValue)>,
+    // the positions in 'entries' by the hash of their keys, with linear
+    // probing; the keys are not held twice, the collector counts the
+    // references to objects
+    slots: Vec<usize>,
+    hasher: RandomState,
 }
 
-impl Hash for LuaTable {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.rdm.hash(state)//TODO::?
+const EMPTY: usize = usize::MAX;
+
+impl LuaMap {
+    pub fn with_capacity(n: usize) -> LuaMap {
+        let mut map = LuaMap {
+            entries: Vec::with_capacity(n),
+            slots: Vec::new(),
+            hasher: RandomState::new(),
+        };
+        map.reindex();
+        map
+    }
+
+    // the number of keys, the dead ones included
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.capacity()
+    }
+
+    // whether 'key' has an entry, dead or not
+    pub fn contains_key(&self, key: &LuaValue) -> bool {
+        self.position(key).is_some()
+    }
+
+    pub fn get(&self, key: &LuaValue) -> Option<&LuaValue> {
+        self.position(key).map(|i| &self.entries[i].1)
+    }
+
+    pub fn insert(&mut self, key: LuaValue, val: LuaValue) {
+        if let Some(i) = self.position(&key) {
+            self.entries[i].1 = val;
+            return;
+        }
+        self.entries.push((key, val));
+        if self.slots.len() < 2 * self.entries.capacity() {
+            self.reindex(); // the entries grew
+        } else {
+            let i = self.entries.len() - 1;
+            let slot = self.free_slot(&self.entries[i].0);
+            self.slots[slot] = i;
+        }
+    }
+
+    // takes the value out, the key stays dead
+    pub fn remove(&mut self, key: &LuaValue) -> Option<LuaValue> {
+        let i = self.position(key)?;
+        let val = mem::replace(&mut self.entries[i].1, LuaValue::Nil);
+        if val.is_nil() {
+            None
+        } else {
+            Some(val)
+        }
+    }
+
+    // every entry in order, the dead keys with nil values
+    pub fn iter(&self) -> impl Iterator<Item = (&LuaValue, &LuaValue)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&LuaValue, &mut LuaValue)> {
+        self.entries.iter_mut().map(|(k, v)| (&*k, v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &LuaValue> {
+        self.entries.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &LuaValue> {
+        self.entries.iter().map(|(_, v)| v)
+    }
+
+    // drops the dead keys, keeping the capacity
+    fn purge(&mut self) {
+        self.entries.retain(|(_, v)| !v.is_nil());
+        self.reindex();
+    }
+
+    fn hash(&self, key: &LuaValue) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        hasher.finish() as usize
+    }
+
+    fn position(&self, key: &LuaValue) -> Option<usize> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        let mask = self.slots.len() - 1;
+        let mut slot = self.hash(key) & mask;
+        loop {
+            match self.slots[slot] {
+                EMPTY => return None,
+                i if self.entries[i].0 == *key => return Some(i),
+                _ => slot = (slot + 1) & mask,
+            }
+        }
+    }
+
+    fn free_slot(&self, key: &LuaValue) -> usize {
+        let mask = self.slots.len() - 1;
+        let mut slot = self.hash(key) & mask;
+        while self.slots[slot] != EMPTY {
+            slot = (slot + 1) & mask;
+        }
+        slot
+    }
+
+    // rebuilds the slots for the capacity of the entries, at most half
+    // full (none without entries)
+    fn reindex(&mut self) {
+        let size = match self.entries.capacity() {
+            0 => 0,
+            n => (2 * n).next_power_of_two(),
+        };
+        self.slots = vec![EMPTY; size];
+        for i in 0..self.entries.len() {
+            let slot = self.free_slot(&self.entries[i].0);
+            self.slots[slot] = i;
+        }
     }
 }
 
@@ -19,21 +162,67 @@ impl LuaTable {
     pub fn new(narr: usize, nrec: usize) -> LuaTable {
         LuaTable {
             arr: Vec::with_capacity(narr),
-            map: HashMap::with_capacity(nrec),
-            rdm: super::math::random(),
+            map: LuaMap::with_capacity(nrec),
+            metatable: None,
         }
     }
 
+    // returns a border: an index n with t[n] ~= nil and t[n+1] == nil
+    // (or 0 if t[1] is nil), like luaH_getn
     pub fn len(&self) -> usize {
-        self.arr.len()
+        let n = self.arr.len();
+        if n > 0 && self.arr[n - 1].is_nil() {
+            // there is a border inside the array part, binary search for it
+            let (mut i, mut j) = (0, n);
+            while j - i > 1 {
+                let m = (i + j) / 2;
+                if self.arr[m - 1].is_nil() {
+                    j = m;
+                } else {
+                    i = m;
+                }
+            }
+            i
+        } else if self.map.is_empty() {
+            n
+        } else {
+            self.unbound_search(n)
+        }
     }
 
-    pub fn get(&self, key: &LuaValue) -> LuaValue {
-        if let Some(idx) = to_index(key) {
-            if idx >=1 && idx <= self.arr.len() {
-                return self.arr[idx - 1].clone(); // TODO
+    // t[j] is non-nil (or j is 0), look for a border in the hash part
+    fn unbound_search(&self, mut j: usize) -> usize {
+        let mut i = j;
+        j += 1;
+        // find 'i' and 'j' such that t[i] is non-nil and t[j] is nil
+        while !self.get_int(j as i64).is_nil() {
+            i = j;
+            if j > i64::MAX as usize / 2 {
+                // table built with bad purposes, resort to linear search
+                let mut k = 1;
+                while !self.get_int(k).is_nil() {
+                    k += 1;
+                }
+                return k as usize - 1;
+            }
+            j *= 2;
+        }
+        // binary search between them
+        while j - i > 1 {
+            let m = (i + j) / 2;
+            if self.get_int(m as i64).is_nil() {
+                j = m;
+            } else {
+                i = m;
             }
         }
+        i
+    }
+
+    pub fn get(&self, key: &LuaValue) -> LuaValue {
+        if let Some(i) = int_key(key) {
+            return self.get_int(i);
+        }
         if let Some(val) = self.map.get(key) {
             val.clone() // TODO
         } else {
@@ -41,79 +230,376 @@ impl LuaTable {
         }
     }
 
-    pub fn put(&mut self, key: LuaValue, val: LuaValue) {
-        if key.is_nil() {
-            panic!("table index is nil!");
+    pub fn get_int(&self, i: i64) -> LuaValue {
+        if i >= 1 && i as usize <= self.arr.len() {
+            return self.arr[i as usize - 1].clone(); // TODO
         }
-        if let LuaValue::Number(n) = key {
-            if n.is_nan() {
-                panic!("table index is NaN!");
-            }
+        if let Some(val) = self.map.get(&LuaValue::Integer(i)) {
+            val.clone() // TODO
+        } else {
+            LuaValue::Nil
         }
+    }
+
+    // the caller must reject nil and NaN keys
+    pub fn put(&mut self, key: LuaValue, val: LuaValue) {
+        let key = match int_key(&key) {
+            Some(i) => LuaValue::Integer(i),
+            None => key,
+        };
 
         if let Some(idx) = to_index(&key) {
             let arr_len = self.arr.len();
             if idx <= arr_len {
-                let val_is_nil = val.is_nil();
+                // a nil leaves the slot in place, a traversal may be on it
                 self.arr[idx - 1] = val;
-                if idx == arr_len && val_is_nil {
-                    self.shrink_array();
-                }
                 return;
             }
-            if idx == arr_len + 1 {
-                self.map.remove(&key);
-                if !val.is_nil() {
-                    self.arr.push(val);
-                    self.expand_array();
-                }
+            // unless the key has a value in the hash part, where it stays
+            if idx == arr_len + 1 && !val.is_nil() && self.map.get(&key).map_or(true, LuaValue::is_nil) {
+                self.arr.push(val);
+                self.expand_array();
                 return;
             }
         }
 
-        if !val.is_nil() {
-            self.map.insert(key, val);
-        } else {
+        if val.is_nil() {
             self.map.remove(&key);
+            return;
         }
+        if self.map.len() == self.map.capacity() && !self.map.contains_key(&key) {
+            // the hash part is full: drop the dead keys, and if that is not
+            // enough move integer keys between the two parts before it
+            // grows, the new key may then fit in the array
+            self.map.purge();
+            if self.map.len() == self.map.capacity() {
+                self.rehash(&key);
+                if let Some(idx) = to_index(&key) {
+                    if idx <= self.arr.len() {
+                        self.arr[idx - 1] = val;
+                        return;
+                    }
+                }
+            }
+        }
+        self.map.insert(key, val);
     }
 
-    fn shrink_array(&mut self) {
-        while !self.arr.is_empty() {
-            if self.arr.last().unwrap().is_nil() {
-                self.arr.pop();
-            } else {
-                break;
+    // the position in a traversal that follows 'key' (nil starts it): the
+    // array part comes first, then the hash part in insertion order; None
+    // if 'key' is not in the table
+    pub fn next_position(&self, key: &LuaValue) -> Option<usize> {
+        if key.is_nil() {
+            return Some(0);
+        }
+        let key = match int_key(key) {
+            Some(i) => LuaValue::Integer(i),
+            None => key.clone(),
+        };
+        match to_index(&key) {
+            Some(idx) if idx <= self.arr.len() => Some(idx),
+            _ => self.map.position(&key).map(|i| self.arr.len() + i + 1),
+        }
+    }
+
+    // the first entry with a value at position 'pos' of a traversal or
+    // after it
+    pub fn entry_from(&self, pos: usize) -> Option<(LuaValue, LuaValue)> {
+        for (i, val) in self.arr.iter().enumerate().skip(pos) {
+            if !val.is_nil() {
+                return Some((LuaValue::Integer(i as i64 + 1), val.clone()));
+            }
+        }
+        let start = pos.saturating_sub(self.arr.len());
+        self.map.entries[start.min(self.map.len())..]
+            .iter()
+            .find(|(_, v)| !v.is_nil())
+            .map(|(k, v)| (k.clone(), v.clone()))
+    }
+
+    // whether putting a value at 'key' needs a new slot in a full part,
+    // which then grows
+    pub fn grows_for(&self, key: &LuaValue) -> bool {
+        if let Some(idx) = to_index(key) {
+            if idx <= self.arr.len() {
+                return false;
+            }
+            if idx == self.arr.len() + 1 {
+                return self.arr.len() == self.arr.capacity();
+            }
+        }
+        let key = match int_key(key) {
+            Some(i) => LuaValue::Integer(i),
+            None => key.clone(),
+        };
+        self.map.len() == self.map.capacity() && !self.map.contains_key(&key)
+    }
+
+    // sizes the array part as the largest n (a power of 2) such that more
+    // than half of the slots 1..n would be in use, like luaH_resize
+    fn rehash(&mut self, extra_key: &LuaValue) {
+        // nums[i] = number of integer keys k where 2^(i-1) < k <= 2^i
+        let mut nums = [0; MAXABITS + 1];
+        let mut total = 0;
+        let mut count = |idx: usize| {
+            nums[ceil_log2(idx)] += 1;
+            total += 1;
+        };
+        for (i, val) in self.arr.iter().enumerate() {
+            if !val.is_nil() {
+                count(i + 1);
+            }
+        }
+        self.map.iter().filter(|(_, v)| !v.is_nil()).filter_map(|(k, _)| array_index(k)).for_each(&mut count);
+        if let Some(idx) = array_index(extra_key) {
+            count(idx);
+        }
+
+        let size = compute_size(&nums, total);
+        self.resize_array(size);
+    }
+
+    fn resize_array(&mut self, size: usize) {
+        let old_size = self.arr.len();
+        if size > old_size {
+            self.arr.resize(size, LuaValue::Nil);
+            for idx in (old_size + 1)..(size + 1) {
+                if let Some(val) = self.map.remove(&LuaValue::Integer(idx as i64)) {
+                    self.arr[idx - 1] = val;
+                }
+            }
+            if !self.arr[size - 1].is_nil() {
+                self.expand_array(); // also take the keys that follow
+            }
+        } else if size < old_size {
+            for (i, val) in self.arr.drain(size..).enumerate() {
+                if !val.is_nil() {
+                    self.map.insert(LuaValue::Integer((size + i + 1) as i64), val);
+                }
             }
         }
     }
 
     fn expand_array(&mut self) {
         let mut idx = self.arr.len() + 1;
-        loop {
-            let key = LuaValue::Integer(idx as i64);
-            if self.map.contains_key(&key) {
-                let val = self.map.remove(&key).unwrap();
-                self.arr.push(val);
-                idx += 1;
-            } else {
-                break;
-            }
+        while let Some(val) = self.map.remove(&LuaValue::Integer(idx as i64)) {
+            self.arr.push(val);
+            idx += 1;
         }
     }
 }
 
+// float keys with an exact integer value are the same key as that integer
+fn int_key(key: &LuaValue) -> Option<i64> {
+    match key {
+        LuaValue::Integer(i) => Some(*i),
+        LuaValue::Number(n) => super::math::float_to_integer(*n),
+        _ => None,
+    }
+}
+
+// keys that may live in the array part
+fn array_index(key: &LuaValue) -> Option<usize> {
+    match key {
+        LuaValue::Integer(i) if *i >= 1 && *i <= 1 << MAXABITS => Some(*i as usize),
+        _ => None,
+    }
+}
+
+// ceil(log2(x)) for x >= 1
+fn ceil_log2(x: usize) -> usize {
+    (usize::BITS - (x - 1).leading_zeros()) as usize
+}
+
+fn compute_size(nums: &[usize], total: usize) -> usize {
+    let mut a = 0; // number of keys smaller than 2^i
+    let mut optimal = 0;
+    let mut two_to_i: usize = 1;
+    for n in nums {
+        if total <= two_to_i / 2 {
+            break; // no more keys that could fill half of a bigger array
+        }
+        a += n;
+        if a > two_to_i / 2 {
+            optimal = two_to_i;
+        }
+        two_to_i *= 2;
+    }
+    optimal
+}
+
 fn to_index(key: &LuaValue) -> Option<usize> {
-    if let LuaValue::Integer(i) = key {
-        if *i >= 1 {
-            return Some(*i as usize);
-        }
-    } else if let LuaValue::Number(n) = key {
-        if let Some(i) = super::math::float_to_integer(*n) {
-            if i >= 1 {
-                return Some(i as usize);
+    match int_key(key) {
+        Some(i) if i >= 1 => Some(i as usize),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(i: i64) -> LuaValue {
+        LuaValue::Integer(i)
+    }
+
+    fn is_border(t: &LuaTable, n: usize) -> bool {
+        (n == 0 || !t.get_int(n as i64).is_nil()) && t.get_int(n as i64 + 1).is_nil()
+    }
+
+    #[test]
+    fn out_of_order_fills_end_in_the_array() {
+        let mut t = LuaTable::new(0, 0);
+        for i in (1..=100).rev() {
+            t.put(int(i), int(i));
+        }
+        // the 65th key finds the hash part full, more than half of 1..128
+        // are in use: the array part takes them all, as in luaH_resize
+        assert_eq!(t.arr.len(), 128);
+        assert!(t.map.values().all(LuaValue::is_nil));
+        assert_eq!(t.len(), 100);
+
+        let mut t = LuaTable::new(0, 0);
+        for &i in [3, 2, 1].iter() {
+            t.put(int(i), int(i));
+        }
+        assert_eq!(t.arr.len(), 3);
+        assert_eq!(t.len(), 3);
+    }
+
+    #[test]
+    fn rehash_sizes_the_array_from_the_integer_keys() {
+        let mut t = LuaTable::new(0, 0);
+        t.put(LuaValue::new_string("x"), int(0));
+        t.put(int(1000), int(0));
+        for i in (1..=8).rev() {
+            t.put(int(i), int(i));
+        }
+        t.put(LuaValue::new_string("y"), int(0));
+        assert_eq!(t.arr.len(), 8);
+        assert_eq!(t.map.len(), 3); // "x", "y" and 1000
+        assert_eq!(t.get_int(1000), int(0));
+
+        // more than half of 1..n in use: 1, 2, 3 fill 1..4, 8 and 16 don't
+        let mut nums = [0; MAXABITS + 1];
+        for &k in [1usize, 2, 3, 8, 16].iter() {
+            nums[ceil_log2(k)] += 1;
+        }
+        assert_eq!(compute_size(&nums, 5), 4);
+        let mut nums = [0; MAXABITS + 1];
+        for &k in [1usize, 2, 4, 8, 16, 32].iter() {
+            nums[ceil_log2(k)] += 1;
+        }
+        assert_eq!(compute_size(&nums, 6), 4);
+    }
+
+    #[test]
+    fn float_keys_with_integer_values_share_the_slot() {
+        let mut t = LuaTable::new(0, 0);
+        t.put(LuaValue::Number(1.0), int(10));
+        t.put(int(2), int(20));
+        assert_eq!(t.get(&int(1)), int(10));
+        assert_eq!(t.get(&LuaValue::Number(2.0)), int(20));
+        assert_eq!(t.arr.len(), 2);
+        assert_eq!(t.len(), 2);
+    }
+
+    #[test]
+    fn length_is_a_border_with_nil_holes() {
+        // a fixed pseudo-random sequence of stores and removals
+        let mut seed: u64 = 12345;
+        let mut next = |n: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+        let mut t = LuaTable::new(0, 0);
+        for round in 0..2000 {
+            let k = next(64) as i64 + 1;
+            if next(3) == 0 {
+                t.put(int(k), LuaValue::Nil);
+            } else {
+                t.put(int(k), int(round));
             }
+            let n = t.len();
+            assert!(is_border(&t, n), "#t = {} is not a border (round {})", n, round);
+        }
+    }
+
+    #[test]
+    fn length_follows_the_hash_part() {
+        let mut t = LuaTable::new(0, 0);
+        t.arr = (1..=4).map(int).collect();
+        for i in 5..=7 {
+            t.map.insert(int(i), int(i));
+        }
+        assert_eq!(t.len(), 7);
+
+        t.arr[3] = LuaValue::Nil; // a border inside the array part
+        let n = t.len();
+        assert!(n == 3 && is_border(&t, n));
+
+        let empty = LuaTable::new(0, 0);
+        assert_eq!(empty.len(), 0);
+    }
+
+    // the keys of a traversal with next_position/entry_from
+    fn traverse(t: &LuaTable) -> Vec<LuaValue> {
+        let mut keys = Vec::new();
+        let mut key = LuaValue::Nil;
+        while let Some((k, _)) = t.next_position(&key).and_then(|pos| t.entry_from(pos)) {
+            keys.push(k.clone());
+            key = k;
+        }
+        keys
+    }
+
+    #[test]
+    fn traversal_takes_the_array_then_the_hash_part_in_order() {
+        let mut t = LuaTable::new(0, 0);
+        t.put(LuaValue::new_string("x"), int(0));
+        t.put(int(2), int(2));
+        t.put(int(1), int(1));
+        t.put(LuaValue::Number(0.5), int(0));
+        t.put(int(10), int(0));
+        t.put(LuaValue::new_string("y"), int(0));
+        t.put(LuaValue::new_string("x"), LuaValue::Nil);
+        let keys = traverse(&t);
+        let expected = [int(1), int(2), LuaValue::Number(0.5), int(10), LuaValue::new_string("y")];
+        assert!(keys == expected, "{:?}", keys);
+
+        // a float key with an integer value finds the integer slot
+        assert_eq!(t.next_position(&LuaValue::Number(2.0)), Some(2));
+        assert_eq!(t.next_position(&LuaValue::new_string("z")), None);
+        assert_eq!(t.next_position(&int(3)), None);
+    }
+
+    #[test]
+    fn clearing_fields_keeps_a_traversal_going() {
+        let mut t = LuaTable::new(0, 0);
+        for i in 1..=4 {
+            t.put(int(i), int(i));
+            t.put(LuaValue::new_string(&i.to_string()), int(i));
+        }
+        let mut count = 0;
+        let mut key = LuaValue::Nil;
+        while let Some((k, _)) = t.next_position(&key).and_then(|pos| t.entry_from(pos)) {
+            t.put(k.clone(), LuaValue::Nil);
+            count += 1;
+            key = k;
+        }
+        assert_eq!(count, 8);
+        assert_eq!(t.len(), 0);
+        assert!(traverse(&t).is_empty());
+
+        // the dead keys stay until a new key needs their room
+        assert_eq!(t.map.len(), 4);
+        while t.map.len() < t.map.capacity() {
+            let k = t.map.len() as f64 + 0.5;
+            t.put(LuaValue::Number(k), int(0));
         }
+        let n = t.map.len();
+        t.put(LuaValue::new_string("new"), int(0));
+        assert_eq!(t.map.len(), n - 4 + 1);
+        assert_eq!(t.get(&LuaValue::new_string("new")), int(0));
     }
-    None
 }
@@ -1,6 +1,9 @@
 use super::lua_table::LuaTable;
 use super::math::float_to_integer;
+use super::number::{float_to_str, str_to_number};
 use super::closure::Closure;
+use super::lua_string::LuaString;
+use super::cmp_ops::eq_int_float;
 use crate::api::{consts::*,RustFn};
 use crate::binary::chunk::Prototype;
 use std::cell::RefCell;
@@ -14,7 +17,7 @@ pub enum LuaValue {
     Boolean(bool),
     Integer(i64),
     Number(f64),
-    Str(String),                  // TODO::
+    Str(LuaString),
     Table(Rc<RefCell<LuaTable>>), // https://doc.rust-lang.org/std/cell/index.html#introducing-mutability-inside-of-something-immutable
     Function(Rc<Closure>)
 }
@@ -27,7 +30,7 @@ impl fmt::Debug for LuaValue {//TODO::
             LuaValue::Integer(i) => write!(f, "({})", i),
             LuaValue::Number(n) => write!(f, "({})", n),
             LuaValue::Str(s) => write!(f, "({})", s),
-            LuaValue::Table(t) => write!(f,"(table<{:?},{:?}>)",t.borrow().arr,t.borrow().map),
+            LuaValue::Table(_) => write!(f, "(table: {:#x})", self.to_pointer()),
             LuaValue::Function(_) => write!(f, "(function)"),
         }
     }
@@ -42,7 +45,11 @@ impl PartialEq for LuaValue {
         } else if let (LuaValue::Integer(x), LuaValue::Integer(y)) = (self, other) {
             x == y
         } else if let (LuaValue::Number(x), LuaValue::Number(y)) = (self, other) {
-            x == y // TODO
+            x == y
+        } else if let (LuaValue::Integer(x), LuaValue::Number(y)) = (self, other) {
+            eq_int_float(*x, *y)
+        } else if let (LuaValue::Number(x), LuaValue::Integer(y)) = (self, other) {
+            eq_int_float(*y, *x)
         } else if let (LuaValue::Str(x), LuaValue::Str(y)) = (self, other) {
             x == y
         } else if let (LuaValue::Table(x), LuaValue::Table(y)) = (self, other) {
@@ -65,10 +72,14 @@ impl Hash for LuaValue {
             LuaValue::Nil => 0.hash(state),
             LuaValue::Boolean(b) => b.hash(state),
             LuaValue::Integer(i) => i.hash(state),
-            LuaValue::Number(n) => n.to_bits().hash(state),
+            // must agree with ==, so integral floats hash like integers
+            LuaValue::Number(n) => match float_to_integer(*n) {
+                Some(i) => i.hash(state),
+                None => n.to_bits().hash(state),
+            },
             LuaValue::Str(s) => s.hash(state),
-            LuaValue::Table(t) => t.borrow().hash(state),
-            LuaValue::Function(c) => c.hash(state),
+            // reference values hash by identity, like they compare
+            LuaValue::Table(_) | LuaValue::Function(_) => self.to_pointer().hash(state),
         }
     }
 }
@@ -79,6 +90,11 @@ impl LuaValue {
         LuaValue::Table(Rc::new(RefCell::new(LuaTable::new(narr, nrec))))
     }
 
+    // a string outside of the string table of any state
+    pub fn new_string(s: &str) -> LuaValue {
+        LuaValue::Str(LuaString::from(s))
+    }
+
     pub fn new_lua_closure(proto: Rc<Prototype>) -> LuaValue {
         LuaValue::Function(Rc::new(Closure::new_lua_closure(proto)))
     }
@@ -87,13 +103,19 @@ impl LuaValue {
         LuaValue::Function(Rc::new(Closure::new_rust_closure(f,n_upvals)))
     }
 
-    pub fn is_nil(&self) -> bool {
+    // the address of the object behind a reference value, 0 otherwise
+    pub fn to_pointer(&self) -> usize {
         match self {
-            LuaValue::Nil => true,
-            _ => false,
+            LuaValue::Table(t) => Rc::as_ptr(t) as *const u8 as usize,
+            LuaValue::Function(c) => Rc::as_ptr(c) as *const u8 as usize,
+            _ => 0,
         }
     }
 
+    pub fn is_nil(&self) -> bool {
+        matches!(self, LuaValue::Nil)
+    }
+
     pub fn type_id(&self) -> i8 {
         match self {
             LuaValue::Nil => LUA_TNIL,
@@ -106,6 +128,17 @@ impl LuaValue {
         }
     }
 
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LuaValue::Nil => "nil",
+            LuaValue::Boolean(_) => "boolean",
+            LuaValue::Integer(_) | LuaValue::Number(_) => "number",
+            LuaValue::Str(_) => "string",
+            LuaValue::Table(_) => "table",
+            LuaValue::Function(_) => "function",
+        }
+    }
+
     pub fn to_boolean(&self) -> bool {
         match self {
             LuaValue::Nil => false,
@@ -119,7 +152,7 @@ impl LuaValue {
         match self {
             LuaValue::Integer(i) => Some(*i as f64),
             LuaValue::Number(n) => Some(*n),
-            LuaValue::Str(s) => s.parse::<f64>().ok(), // TODO
+            LuaValue::Str(s) => s.to_str().and_then(str_to_number).and_then(|n| n.to_number()),
             _ => None,
         }
     }
@@ -129,18 +162,28 @@ impl LuaValue {
         match self {
             LuaValue::Integer(i) => Some(*i),
             LuaValue::Number(n) => float_to_integer(*n),
-            LuaValue::Str(s) => string_to_integer(s),
+            LuaValue::Str(s) => s.to_str().and_then(str_to_number).and_then(|n| n.to_integer()),
+            _ => None,
+        }
+    }
+
+    // numbers as they are, strings converted to an integer or a float
+    // following their syntax, everything else is not a number
+    pub fn to_numeric(&self) -> Option<LuaValue> {
+        match self {
+            LuaValue::Integer(_) | LuaValue::Number(_) => Some(self.clone()),
+            LuaValue::Str(s) => s.to_str().and_then(str_to_number),
             _ => None,
         }
     }
-}
 
-fn string_to_integer(s: &String) -> Option<i64> {
-    if let Ok(i) = s.parse::<i64>() {
-        Some(i)
-    } else if let Ok(n) = s.parse::<f64>() {
-        float_to_integer(n)
-    } else {
-        None
+    // http://www.lua.org/manual/5.3/manual.html#3.4.3
+    pub fn to_str(&self) -> Option<String> {
+        match self {
+            LuaValue::Str(s) => Some(s.to_string()),
+            LuaValue::Integer(i) => Some(i.to_string()),
+            LuaValue::Number(n) => Some(float_to_str(*n)),
+            _ => None,
+        }
     }
 }
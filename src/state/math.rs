@@ -1,24 +1,39 @@
 // a % b == a - ((a // b) * b)
+// b != 0, division by zero is reported by the caller
 pub fn i_mod(a: i64, b: i64) -> i64 {
-    a - i_floor_div(a, b) * b
+    if b == -1 {
+        0 // avoid overflow with MININTEGER % -1
+    } else {
+        let r = a % b;
+        if r != 0 && (r ^ b) < 0 {
+            r + b // the result must have the sign of the divisor
+        } else {
+            r
+        }
+    }
 }
 
 // a % b == a - ((a // b) * b)
 pub fn f_mod(a: f64, b: f64) -> f64 {
-    if a > 0.0 && is_positive_infinite(b) || a < 0.0 && is_negative_infinite(b) {
-        a
-    } else if a > 0.0 && is_negative_infinite(b) || a < 0.0 && is_positive_infinite(b) {
-        b
+    let m = a % b; // C fmod
+    if (m > 0.0 && b < 0.0) || (m < 0.0 && b > 0.0) {
+        m + b
     } else {
-        a - (a / b).floor() * b
+        m
     }
 }
 
+// b != 0, division by zero is reported by the caller
 pub fn i_floor_div(a: i64, b: i64) -> i64 {
-    if a > 0 && b > 0 || a < 0 && b < 0 || a % b == 0 {
-        a / b
+    if b == -1 {
+        a.wrapping_neg() // avoid overflow with MININTEGER // -1
     } else {
-        a / b - 1
+        let q = a / b;
+        if (a ^ b) < 0 && a % b != 0 {
+            q - 1 // operands have different signs, round towards minus infinity
+        } else {
+            q
+        }
     }
 }
 
@@ -27,44 +42,34 @@ pub fn f_floor_div(a: f64, b: f64) -> f64 {
 }
 
 pub fn shift_left(a: i64, n: i64) -> i64 {
-    if n >= 64 {
+    if n >= 64 || n <= -64 {
         0
     } else if n >= 0 {
-        a << n
+        ((a as u64) << n) as i64
     } else {
-        shift_right(a, -n)
+        ((a as u64) >> -n) as i64
     }
 }
 
 // logical shift right
 pub fn shift_right(a: i64, n: i64) -> i64 {
-    if n >= 64 {
+    if n >= 64 || n <= -64 {
         0
     } else if n >= 0 {
-        (a as u64 >> n) as i64
+        ((a as u64) >> n) as i64
     } else {
-        shift_left(a, -n)
+        ((a as u64) << -n) as i64
     }
 }
 
-fn is_positive_infinite(n: f64) -> bool {
-    n.is_infinite() && n.is_sign_positive()
-}
-
-fn is_negative_infinite(n: f64) -> bool {
-    n.is_infinite() && n.is_sign_negative()
-}
-
-// https://users.rust-lang.org/t/random-number-without-using-the-external-crate/17260/8
-pub fn random() -> usize {
-    let ptr = Box::into_raw(Box::new(123));
-    ptr as usize
-}
+// -2^63 and 2^63 are exact in f64, the latter is already out of range
+const MIN_INTEGER_F: f64 = -9223372036854775808.0;
+const MAX_INTEGER_F: f64 = 9223372036854775808.0;
 
+// succeeds only when n has an exact integer value inside the i64 range
 pub fn float_to_integer(n: f64) -> Option<i64> {
-    let i = n as i64;
-    if i as f64 == n {
-        Some(i)
+    if n.floor() == n && (MIN_INTEGER_F..MAX_INTEGER_F).contains(&n) {
+        Some(n as i64)
     } else {
         None
     }
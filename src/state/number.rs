@@ -0,0 +1,304 @@
+use super::lua_value::LuaValue;
+
+/*
+** string -> number conversions, following lua_stringtonumber: the string
+** may have leading and trailing whitespace and a sign, integers may be
+** decimal or hexadecimal (hex integers wrap around), decimal integers that
+** overflow become floats, and floats may be decimal or hexadecimal with a
+** binary exponent ("0x1p4"). "inf" and "nan" are not numerals.
+*/
+
+pub fn str_to_number(s: &str) -> Option<LuaValue> {
+    if let Some(i) = str_to_integer(s) {
+        Some(LuaValue::Integer(i))
+    } else {
+        str_to_float(s).map(LuaValue::Number)
+    }
+}
+
+pub fn str_to_integer(s: &str) -> Option<i64> {
+    let s = trim_space(s);
+    let (neg, digits) = split_sign(s);
+    let mut a: u64 = 0;
+
+    if let Some(hex) = strip_hex_prefix(digits) {
+        if hex.is_empty() {
+            return None;
+        }
+        for c in hex.chars() {
+            let d = c.to_digit(16)? as u64;
+            a = a.wrapping_mul(16).wrapping_add(d); // wraps around
+        }
+    } else {
+        if digits.is_empty() {
+            return None;
+        }
+        // the magnitude of MININTEGER is one more than MAXINTEGER
+        let max = i64::MAX as u64 + neg as u64;
+        for c in digits.chars() {
+            let d = c.to_digit(10)? as u64;
+            a = a.checked_mul(10).and_then(|a| a.checked_add(d))?;
+            if a > max {
+                return None; // overflow, accept it as a float instead
+            }
+        }
+    }
+
+    let i = a as i64;
+    Some(if neg { i.wrapping_neg() } else { i })
+}
+
+pub fn str_to_float(s: &str) -> Option<f64> {
+    if s.contains(['n', 'N']) {
+        return None; // reject 'inf' and 'nan'
+    }
+    let s = trim_space(s);
+    let (neg, digits) = split_sign(s);
+    let n = if let Some(hex) = strip_hex_prefix(digits) {
+        parse_hex_float(hex)?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        digits.parse::<f64>().ok()?
+    } else {
+        return None; // no second sign
+    };
+    Some(if neg { -n } else { n })
+}
+
+// mantissa digits with an optional '.', then an optional 'p' exponent
+fn parse_hex_float(s: &str) -> Option<f64> {
+    const MAX_SIG_DIGITS: i32 = 30;
+
+    let mut chars = s.chars().peekable();
+    let mut r = 0.0;
+    let mut e: i32 = 0; // binary exponent correction
+    let mut sig_digits = 0;
+    let mut any_digit = false;
+    let mut has_dot = false;
+
+    while let Some(&c) = chars.peek() {
+        if c == '.' {
+            if has_dot {
+                return None;
+            }
+            has_dot = true;
+        } else if let Some(d) = c.to_digit(16) {
+            any_digit = true;
+            if sig_digits == 0 && d == 0 {
+                // leading zeros are not significant
+                if has_dot {
+                    e -= 4;
+                }
+            } else if sig_digits < MAX_SIG_DIGITS {
+                sig_digits += 1;
+                r = r * 16.0 + d as f64;
+                if has_dot {
+                    e -= 4;
+                }
+            } else if !has_dot {
+                e += 4; // too many digits, ignore but still count for exponent
+            }
+        } else {
+            break;
+        }
+        chars.next();
+    }
+    if !any_digit {
+        return None;
+    }
+
+    if let Some(c) = chars.next() {
+        if c != 'p' && c != 'P' {
+            return None;
+        }
+        let exp: String = chars.collect();
+        let (neg, digits) = split_sign(&exp);
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let exp = digits.parse::<i32>().unwrap_or(i32::MAX);
+        e = e.saturating_add(if neg { -exp } else { exp });
+    }
+
+    Some(ldexp(r, e))
+}
+
+// r * 2^e, in steps so that intermediate powers don't overflow
+fn ldexp(mut r: f64, mut e: i32) -> f64 {
+    while e > 1000 {
+        r *= 2f64.powi(1000);
+        e -= 1000;
+    }
+    while e < -1000 {
+        r *= 2f64.powi(-1000);
+        e += 1000;
+    }
+    r * 2f64.powi(e)
+}
+
+fn trim_space(s: &str) -> &str {
+    // same set as C isspace
+    s.trim_matches(|c| c == ' ' || ('\t'..='\r').contains(&c))
+}
+
+fn split_sign(s: &str) -> (bool, &str) {
+    if let Some(rest) = s.strip_prefix('-') {
+        (true, rest)
+    } else if let Some(rest) = s.strip_prefix('+') {
+        (false, rest)
+    } else {
+        (false, s)
+    }
+}
+
+fn strip_hex_prefix(s: &str) -> Option<&str> {
+    s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))
+}
+
+/*
+** number -> string conversions: floats are written with "%.14g" and get a
+** ".0" suffix when they would otherwise look like an integer.
+*/
+
+pub fn float_to_str(n: f64) -> String {
+    if n.is_nan() {
+        return String::from(if n.is_sign_negative() { "-nan" } else { "nan" });
+    }
+    if n.is_infinite() {
+        return String::from(if n > 0.0 { "inf" } else { "-inf" });
+    }
+
+    let mut s = fmt_g14(n);
+    if s.bytes().all(|b| b == b'-' || b.is_ascii_digit()) {
+        s.push_str(".0"); // looks like an int
+    }
+    s
+}
+
+// printf("%.14g", n) for finite n
+fn fmt_g14(n: f64) -> String {
+    const PRECISION: i32 = 14;
+
+    // the exponent after rounding to PRECISION significant digits
+    let sci = format!("{:.*e}", (PRECISION - 1) as usize, n);
+    let (mantissa, exp) = sci.split_at(sci.find('e').unwrap());
+    let exp: i32 = exp[1..].parse().unwrap();
+
+    if !(-4..PRECISION).contains(&exp) {
+        let mantissa = strip_zeros(mantissa);
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exp.abs())
+    } else {
+        let fixed = format!("{:.*}", (PRECISION - 1 - exp) as usize, n);
+        strip_zeros(&fixed).to_string()
+    }
+}
+
+// removes trailing zeros of the fractional part, and the '.' if nothing is left
+fn strip_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(s: &str) -> Option<i64> {
+        match str_to_number(s) {
+            Some(LuaValue::Integer(i)) => Some(i),
+            _ => None,
+        }
+    }
+
+    fn float(s: &str) -> Option<f64> {
+        match str_to_number(s) {
+            Some(LuaValue::Number(n)) => Some(n),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn hex_numerals() {
+        assert_eq!(int("0x1F"), Some(31));
+        assert_eq!(int("0Xff"), Some(255));
+        assert_eq!(int("-0x10"), Some(-16));
+        assert_eq!(float("0x1p4"), Some(16.0));
+        assert_eq!(float("0x.8"), Some(0.5));
+        assert_eq!(float("0x1.8p-1"), Some(0.75));
+        assert_eq!(float("0xA."), Some(10.0));
+        assert_eq!(str_to_number("0x"), None);
+        assert_eq!(str_to_number("0x."), None);
+        assert_eq!(str_to_number("0x1p"), None);
+        assert_eq!(str_to_number("0x1g"), None);
+    }
+
+    #[test]
+    fn hex_integers_wrap_around() {
+        assert_eq!(int("0x7fffffffffffffff"), Some(i64::MAX));
+        assert_eq!(int("0xffffffffffffffff"), Some(-1));
+        assert_eq!(int("0x8000000000000000"), Some(i64::MIN));
+        assert_eq!(int("0x10000000000000001"), Some(1));
+        assert_eq!(int("-0x8000000000000000"), Some(i64::MIN));
+    }
+
+    #[test]
+    fn surrounding_whitespace() {
+        assert_eq!(int(" \t42\n"), Some(42));
+        assert_eq!(int("\x0b\x0c-7\r"), Some(-7));
+        assert_eq!(float("  1.5  "), Some(1.5));
+        assert_eq!(float(" 0x1p1 "), Some(2.0));
+        assert_eq!(str_to_number("1 2"), None);
+        assert_eq!(str_to_number("- 1"), None);
+        assert_eq!(str_to_number("   "), None);
+        assert_eq!(str_to_number(""), None);
+    }
+
+    #[test]
+    fn inf_and_nan_are_not_numerals() {
+        for s in ["inf", "-inf", "nan", "NaN", "infinity", " INF ", "1e5n"].iter() {
+            assert_eq!(str_to_number(s), None, "{:?}", s);
+        }
+        assert_eq!(str_to_number("--1"), None);
+        assert_eq!(str_to_number("+-1"), None);
+        assert_eq!(int("+1"), Some(1));
+    }
+
+    #[test]
+    fn decimal_overflow_becomes_a_float() {
+        assert_eq!(int("9223372036854775807"), Some(i64::MAX));
+        assert_eq!(int("-9223372036854775808"), Some(i64::MIN));
+        assert_eq!(float("9223372036854775808"), Some(9223372036854775808.0));
+        assert_eq!(float("-9223372036854775809"), Some(-9223372036854775808.0));
+        assert_eq!(float("100000000000000000000"), Some(1e20));
+        assert_eq!(str_to_integer("9223372036854775808"), None);
+    }
+
+    #[test]
+    fn floats_to_strings() {
+        assert_eq!(float_to_str(3.0), "3.0");
+        assert_eq!(float_to_str(-0.0), "-0.0");
+        assert_eq!(float_to_str(0.5), "0.5");
+        assert_eq!(float_to_str(1e15), "1e+15");
+        assert_eq!(float_to_str(1e14), "1e+14");
+        assert_eq!(float_to_str(1e13), "10000000000000.0");
+        assert_eq!(float_to_str(1e-5), "1e-05");
+        assert_eq!(float_to_str(0.0001), "0.0001");
+        assert_eq!(float_to_str(2f64.powi(63)), "9.2233720368548e+18");
+        assert_eq!(float_to_str(f64::INFINITY), "inf");
+        assert_eq!(float_to_str(f64::NEG_INFINITY), "-inf");
+    }
+
+    #[test]
+    fn g14_rounds_to_14_digits() {
+        assert_eq!(float_to_str(0.1 + 0.2), "0.3");
+        assert_eq!(float_to_str(1.0 / 3.0), "0.33333333333333");
+        assert_eq!(float_to_str(2.0 / 3.0), "0.66666666666667");
+        assert_eq!(float_to_str(123456789012345.6), "1.2345678901235e+14");
+        assert_eq!(float_to_str(99999999999999.99), "1e+14");
+        assert_eq!(float_to_str(9.99999999999999), "10.0");
+        assert_eq!(float_to_str(std::f64::consts::PI), "3.1415926535898");
+    }
+}
@@ -0,0 +1,35 @@
+use super::lua_string::StringTable;
+use super::lua_value::LuaValue;
+
+/*
+** The events of metatables, in the order of ltm.h. A state interns their
+** names once ('tm_names'), so looking up a handler compares interned keys
+** instead of building a string for each probe.
+*/
+
+pub const TM_INDEX: usize = 0;
+pub const TM_NEWINDEX: usize = 1;
+pub const TM_GC: usize = 2;
+pub const TM_MODE: usize = 3;
+pub const TM_LEN: usize = 4;
+pub const TM_EQ: usize = 5;
+pub const TM_ADD: usize = 6; // TM_ADD + LUA_OP* for the arithmetic and bitwise operators
+pub const TM_LT: usize = 20;
+pub const TM_LE: usize = 21;
+pub const TM_CONCAT: usize = 22;
+pub const TM_CALL: usize = 23;
+pub const TM_TOSTRING: usize = 24; // not events of the VM, used by tostring
+pub const TM_NAME: usize = 25;
+pub const TM_N: usize = 26; // number of elements in the enum
+
+const TM_NAMES: [&str; TM_N] = [
+    "__index", "__newindex", "__gc", "__mode", "__len", "__eq",
+    "__add", "__sub", "__mul", "__mod", "__pow", "__div", "__idiv",
+    "__band", "__bor", "__bxor", "__shl", "__shr", "__unm", "__bnot",
+    "__lt", "__le", "__concat", "__call", "__tostring", "__name",
+];
+
+// the names of the events, interned in 'strings', by TM_*
+pub fn tm_names(strings: &mut StringTable) -> Vec<LuaValue> {
+    TM_NAMES.iter().map(|name| LuaValue::Str(strings.intern(name.as_bytes()))).collect()
+}
@@ -0,0 +1,13 @@
+mod auxlib;
+mod base;
+mod debug;
+mod string;
+
+use crate::api::{LuaAPI, LuaResult};
+
+// registers the standard functions in the global table
+pub fn open_libs(ls: &mut dyn LuaAPI) -> LuaResult<()> {
+    base::open_base(ls)?;
+    debug::open_debug(ls)?;
+    string::open_string(ls)
+}
@@ -0,0 +1,124 @@
+use crate::api::consts::*;
+use crate::api::{LuaAPI, LuaError, LuaResult, LuaString};
+
+/*
+** Argument checks for Rust functions, like lauxlib. Rust functions do not
+** know the name they were called by, so it is passed in 'fname'.
+*/
+
+pub fn arg_error(ls: &mut dyn LuaAPI, arg: isize, fname: &str, extra_msg: &str) -> LuaError {
+    let msg = format!("bad argument #{} to '{}' ({})", arg, fname, extra_msg);
+    error(ls, &msg)
+}
+
+pub fn type_error(ls: &mut dyn LuaAPI, arg: isize, fname: &str, expected: &str) -> LuaError {
+    let actual = ls.type_name(ls.type_id(arg)).to_string();
+    let msg = format!("{} expected, got {}", expected, actual);
+    arg_error(ls, arg, fname, &msg)
+}
+
+// an error with the position of the calling Lua code
+pub fn error(ls: &mut dyn LuaAPI, msg: &str) -> LuaError {
+    let msg = format!("{}{}", ls._where(1), msg);
+    ls.push_string(msg);
+    ls.error()
+}
+
+pub fn check_any(ls: &mut dyn LuaAPI, arg: isize, fname: &str) -> LuaResult<()> {
+    if ls.is_none(arg) {
+        Err(arg_error(ls, arg, fname, "value expected"))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn check_type(ls: &mut dyn LuaAPI, arg: isize, fname: &str, tp: i8) -> LuaResult<()> {
+    if ls.type_id(arg) != tp {
+        let expected = ls.type_name(tp).to_string();
+        Err(type_error(ls, arg, fname, &expected))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn arg_check(ls: &mut dyn LuaAPI, cond: bool, arg: isize, fname: &str, extra_msg: &str) -> LuaResult<()> {
+    if cond {
+        Ok(())
+    } else {
+        Err(arg_error(ls, arg, fname, extra_msg))
+    }
+}
+
+// pushes the field 'event' of the metatable of the value at obj, if the
+// value has a metatable; returns the type of the pushed value, or
+// LUA_TNIL if nothing was pushed
+pub fn get_meta_field(ls: &mut dyn LuaAPI, obj: isize, event: &str) -> LuaResult<i8> {
+    if !ls.get_metatable(obj) {
+        return Ok(LUA_TNIL); // no metatable
+    }
+    ls.push_bytes(event.as_bytes());
+    let tt = ls.raw_get(-2)?;
+    if tt == LUA_TNIL {
+        ls.pop(2); // remove metatable and metafield
+    } else {
+        ls.remove(-2)?; // remove only metatable
+    }
+    Ok(tt)
+}
+
+pub fn check_integer(ls: &mut dyn LuaAPI, arg: isize, fname: &str) -> LuaResult<i64> {
+    match ls.to_integerx(arg) {
+        Some(i) => Ok(i),
+        None if ls.is_number(arg) => {
+            Err(arg_error(ls, arg, fname, "number has no integer representation"))
+        }
+        None => Err(type_error(ls, arg, fname, "number")),
+    }
+}
+
+// a string argument, numbers are converted
+pub fn check_string(ls: &mut dyn LuaAPI, arg: isize, fname: &str) -> LuaResult<String> {
+    match ls.to_stringx(arg) {
+        Some(s) => Ok(s),
+        None => Err(type_error(ls, arg, fname, "string")),
+    }
+}
+
+// a string argument as the bytes it holds, numbers are converted
+pub fn check_bytes(ls: &mut dyn LuaAPI, arg: isize, fname: &str) -> LuaResult<LuaString> {
+    match ls.to_bytes(arg) {
+        Some(s) => Ok(s),
+        None => Err(type_error(ls, arg, fname, "string")),
+    }
+}
+
+pub fn opt_bytes(ls: &mut dyn LuaAPI, arg: isize, fname: &str, def: &[u8]) -> LuaResult<LuaString> {
+    if ls.is_none_or_nil(arg) {
+        Ok(LuaString::new(def))
+    } else {
+        check_bytes(ls, arg, fname)
+    }
+}
+
+// the index of the string argument in 'lst', which it must be one of
+pub fn check_option(ls: &mut dyn LuaAPI, arg: isize, fname: &str, def: Option<&str>, lst: &[&str]) -> LuaResult<usize> {
+    let name = match def {
+        Some(def) if ls.is_none_or_nil(arg) => def.to_string(),
+        _ => check_string(ls, arg, fname)?,
+    };
+    match lst.iter().position(|&opt| opt == name) {
+        Some(i) => Ok(i),
+        None => {
+            let msg = format!("invalid option '{}'", name);
+            Err(arg_error(ls, arg, fname, &msg))
+        }
+    }
+}
+
+pub fn opt_integer(ls: &mut dyn LuaAPI, arg: isize, fname: &str, def: i64) -> LuaResult<i64> {
+    if ls.is_none_or_nil(arg) {
+        Ok(def)
+    } else {
+        check_integer(ls, arg, fname)
+    }
+}
@@ -0,0 +1,202 @@
+use super::auxlib::*;
+use crate::api::consts::*;
+use crate::api::{LuaAPI, LuaResult};
+use std::io::{self, Write};
+
+pub fn open_base(ls: &mut dyn LuaAPI) -> LuaResult<()> {
+    ls.register("collectgarbage", collect_garbage)?;
+    ls.register("error", error)?;
+    ls.register("getmetatable", get_metatable)?;
+    ls.register("next", next)?;
+    ls.register("pairs", pairs)?;
+    ls.register("setmetatable", set_metatable)?;
+    ls.register("pcall", pcall)?;
+    ls.register("print", print)?;
+    ls.register("rawequal", raw_equal)?;
+    ls.register("rawget", raw_get)?;
+    ls.register("rawlen", raw_len)?;
+    ls.register("rawset", raw_set)?;
+    ls.register("tostring", tostring)?;
+    ls.register("xpcall", xpcall)?;
+    Ok(())
+}
+
+// collectgarbage ([opt [, arg]])
+fn collect_garbage(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    const OPTS: [&str; 8] = [
+        "stop", "restart", "collect", "count", "step", "setpause", "setstepmul", "isrunning",
+    ];
+    const OPTSNUM: [u8; 8] = [
+        LUA_GCSTOP, LUA_GCRESTART, LUA_GCCOLLECT, LUA_GCCOUNT, LUA_GCSTEP, LUA_GCSETPAUSE,
+        LUA_GCSETSTEPMUL, LUA_GCISRUNNING,
+    ];
+    let o = OPTSNUM[check_option(ls, 1, "collectgarbage", Some("collect"), &OPTS)?];
+    let ex = opt_integer(ls, 2, "collectgarbage", 0)?;
+    let res = ls.gc(o, ex as isize)?;
+    match o {
+        LUA_GCCOUNT => {
+            let b = ls.gc(LUA_GCCOUNTB, 0)?;
+            ls.push_number(res as f64 + b as f64 / 1024.0);
+        }
+        LUA_GCSTEP | LUA_GCISRUNNING => ls.push_boolean(res != 0),
+        _ => ls.push_integer(res as i64),
+    }
+    Ok(1)
+}
+
+// error (message [, level])
+fn error(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let level = opt_integer(ls, 2, "error", 1)?;
+    ls.set_top(1)?;
+    if ls.type_id(1) == LUA_TSTRING && level > 0 {
+        // add position information
+        let pos = ls._where(level as usize);
+        ls.push_string(pos);
+        ls.insert(1)?;
+        ls.concat(2)?;
+    }
+    Err(ls.error())
+}
+
+// getmetatable (object)
+fn get_metatable(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    check_any(ls, 1, "getmetatable")?;
+    if !ls.get_metatable(1) {
+        ls.push_nil();
+        return Ok(1); // no metatable
+    }
+    get_meta_field(ls, 1, "__metatable")?;
+    Ok(1) // returns either __metatable field (if present) or metatable
+}
+
+// next (table [, index])
+fn next(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    check_type(ls, 1, "next", LUA_TTABLE)?;
+    ls.set_top(2)?; // create a 2nd argument if there isn't one
+    if ls.next(1)? {
+        Ok(2)
+    } else {
+        ls.push_nil();
+        Ok(1)
+    }
+}
+
+// pairs (t)
+fn pairs(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    if get_meta_field(ls, 1, "__pairs")? == LUA_TNIL {
+        check_any(ls, 1, "pairs")?;
+        ls.push_rust_function(next); // will return generator,
+        ls.push_value(1); // state,
+        ls.push_nil(); // and initial value
+    } else {
+        ls.push_value(1); // argument 'self' to metamethod
+        ls.call(1, 3)?; // get 3 values from metamethod
+    }
+    Ok(3)
+}
+
+// setmetatable (table, metatable)
+fn set_metatable(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let t = ls.type_id(2);
+    check_type(ls, 1, "setmetatable", LUA_TTABLE)?;
+    arg_check(ls, t == LUA_TNIL || t == LUA_TTABLE, 2, "setmetatable", "nil or table expected")?;
+    if get_meta_field(ls, 1, "__metatable")? != LUA_TNIL {
+        return Err(super::auxlib::error(ls, "cannot change a protected metatable"));
+    }
+    ls.set_top(2)?;
+    ls.set_metatable(1)?;
+    Ok(1)
+}
+
+// print (...)
+fn print(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let n = ls.get_top(); // number of arguments
+    let mut out = io::stdout();
+    for i in 1..=n {
+        ls.to_display_string(i)?;
+        if i > 1 {
+            let _ = out.write_all(b"\t");
+        }
+        if let Some(s) = ls.to_bytes(-1) {
+            let _ = out.write_all(s.as_bytes()); // the bytes as they are
+        }
+        ls.pop(1); // pop result
+    }
+    let _ = out.write_all(b"\n");
+    Ok(0)
+}
+
+// rawequal (v1, v2)
+fn raw_equal(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    check_any(ls, 1, "rawequal")?;
+    check_any(ls, 2, "rawequal")?;
+    let b = ls.raw_equal(1, 2);
+    ls.push_boolean(b);
+    Ok(1)
+}
+
+// rawlen (v)
+fn raw_len(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let t = ls.type_id(1);
+    arg_check(ls, t == LUA_TTABLE || t == LUA_TSTRING, 1, "rawlen", "table or string expected")?;
+    let n = ls.raw_len(1);
+    ls.push_integer(n as i64);
+    Ok(1)
+}
+
+// rawget (table, index)
+fn raw_get(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    check_type(ls, 1, "rawget", LUA_TTABLE)?;
+    check_any(ls, 2, "rawget")?;
+    ls.set_top(2)?;
+    ls.raw_get(1)?;
+    Ok(1)
+}
+
+// rawset (table, index, value)
+fn raw_set(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    check_type(ls, 1, "rawset", LUA_TTABLE)?;
+    check_any(ls, 2, "rawset")?;
+    check_any(ls, 3, "rawset")?;
+    ls.set_top(3)?;
+    ls.raw_set(1)?;
+    Ok(1)
+}
+
+// tostring (v)
+fn tostring(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    check_any(ls, 1, "tostring")?;
+    ls.to_display_string(1)?;
+    Ok(1)
+}
+
+// pcall (f [, arg1, ...])
+fn pcall(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    check_any(ls, 1, "pcall")?;
+    ls.push_boolean(true); // first result if no errors
+    ls.insert(1)?;
+    let nargs = ls.get_top() - 2;
+    let status = ls.pcall(nargs as usize, LUA_MULTRET, 0);
+    finish_pcall(ls, status, 0)
+}
+
+// xpcall (f, msgh [, arg1, ...])
+fn xpcall(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let n = ls.get_top();
+    check_type(ls, 2, "xpcall", LUA_TFUNCTION)?;
+    ls.push_boolean(true); // first result
+    ls.push_value(1); // function
+    ls.rotate(3, 2)?; // move them below function's arguments
+    let status = ls.pcall((n - 2) as usize, LUA_MULTRET, 2);
+    finish_pcall(ls, status, 2)
+}
+
+fn finish_pcall(ls: &mut dyn LuaAPI, status: u8, extra: isize) -> LuaResult<usize> {
+    if status != LUA_OK {
+        ls.push_boolean(false);
+        ls.push_value(-2); // error object
+        Ok(2) // return false, msg
+    } else {
+        Ok((ls.get_top() - extra) as usize) // return all results
+    }
+}
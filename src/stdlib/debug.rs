@@ -0,0 +1,22 @@
+use super::auxlib::*;
+use crate::api::{LuaAPI, LuaResult};
+
+pub fn open_debug(ls: &mut dyn LuaAPI) -> LuaResult<()> {
+    ls.new_table();
+    ls.push_rust_function(traceback);
+    ls.set_field(-2, "traceback")?;
+    ls.set_global("debug")
+}
+
+// debug.traceback ([message [, level]])
+fn traceback(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let msg = ls.to_stringx(1);
+    if msg.is_none() && !ls.is_none_or_nil(1) {
+        ls.push_value(1); // return non-string 'msg' untouched
+    } else {
+        let level = opt_integer(ls, 2, "traceback", 1)?;
+        let text = ls.traceback(msg.as_deref(), level.max(0) as usize);
+        ls.push_string(text);
+    }
+    Ok(1)
+}
@@ -0,0 +1,159 @@
+use super::auxlib::*;
+use crate::api::{LuaAPI, LuaResult, RustFn};
+
+const FUNCS: [(&str, RustFn); 8] = [
+    ("byte", str_byte),
+    ("char", str_char),
+    ("len", str_len),
+    ("lower", str_lower),
+    ("rep", str_rep),
+    ("reverse", str_reverse),
+    ("sub", str_sub),
+    ("upper", str_upper),
+];
+
+pub fn open_string(ls: &mut dyn LuaAPI) -> LuaResult<()> {
+    ls.new_table();
+    for (name, f) in FUNCS.iter() {
+        ls.push_rust_function(*f);
+        ls.set_field(-2, name)?;
+    }
+    create_metatable(ls)?;
+    ls.set_global("string")
+}
+
+// the metatable shared by all strings, so that s:f(...) calls string.f
+fn create_metatable(ls: &mut dyn LuaAPI) -> LuaResult<()> {
+    ls.new_table(); // table to be metatable for strings
+    ls.push_value(-2); // string library
+    ls.set_field(-2, "__index")?; // metatable.__index = string
+    ls.push_string(String::new()); // dummy string
+    ls.push_value(-2); // copy table
+    ls.set_metatable(-2)?; // set table as metatable for strings
+    ls.pop(2); // pop dummy string and metatable
+    Ok(())
+}
+
+// translates a relative string position: negative means back from end
+fn pos_relat(pos: i64, len: usize) -> i64 {
+    if pos >= 0 {
+        pos
+    } else if pos.unsigned_abs() > len as u64 {
+        0
+    } else {
+        len as i64 + pos + 1
+    }
+}
+
+// string.len (s)
+fn str_len(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let s = check_bytes(ls, 1, "len")?;
+    ls.push_integer(s.len() as i64);
+    Ok(1)
+}
+
+// string.sub (s, i [, j])
+fn str_sub(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let s = check_bytes(ls, 1, "sub")?;
+    let l = s.len();
+    let mut start = pos_relat(check_integer(ls, 2, "sub")?, l);
+    let mut end = pos_relat(opt_integer(ls, 3, "sub", -1)?, l);
+    if start < 1 {
+        start = 1;
+    }
+    if end > l as i64 {
+        end = l as i64;
+    }
+    if start <= end {
+        ls.push_bytes(&s.as_bytes()[start as usize - 1..end as usize]);
+    } else {
+        ls.push_bytes(b"");
+    }
+    Ok(1)
+}
+
+// string.reverse (s)
+fn str_reverse(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let s = check_bytes(ls, 1, "reverse")?;
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.reverse();
+    ls.push_bytes(&bytes);
+    Ok(1)
+}
+
+// string.lower (s)
+fn str_lower(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let s = check_bytes(ls, 1, "lower")?;
+    ls.push_bytes(&s.as_bytes().to_ascii_lowercase());
+    Ok(1)
+}
+
+// string.upper (s)
+fn str_upper(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let s = check_bytes(ls, 1, "upper")?;
+    ls.push_bytes(&s.as_bytes().to_ascii_uppercase());
+    Ok(1)
+}
+
+// string.rep (s, n [, sep])
+fn str_rep(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let s = check_bytes(ls, 1, "rep")?;
+    let n = check_integer(ls, 2, "rep")?;
+    let sep = opt_bytes(ls, 3, "rep", b"")?;
+    if n <= 0 || s.len() + sep.len() == 0 {
+        ls.push_bytes(b"");
+        return Ok(1);
+    }
+    // n copies of 's' with n - 1 separators between them
+    let n = n as usize;
+    let total = (s.len() + sep.len())
+        .checked_mul(n)
+        .map(|total| total - sep.len())
+        .filter(|&total| total < i32::MAX as usize);
+    let total = match total {
+        Some(total) => total,
+        None => return Err(error(ls, "resulting string too large")),
+    };
+    ls.reserve(total)?;
+    let mut bytes = Vec::with_capacity(total);
+    for i in 0..n {
+        if i > 0 {
+            bytes.extend_from_slice(sep.as_bytes());
+        }
+        bytes.extend_from_slice(s.as_bytes());
+    }
+    ls.push_bytes(&bytes);
+    Ok(1)
+}
+
+// string.byte (s [, i [, j]])
+fn str_byte(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let s = check_bytes(ls, 1, "byte")?;
+    let l = s.len();
+    let posi = pos_relat(opt_integer(ls, 2, "byte", 1)?, l);
+    let pose = pos_relat(opt_integer(ls, 3, "byte", posi)?, l);
+    let posi = posi.max(1);
+    let pose = pose.min(l as i64);
+    if posi > pose {
+        return Ok(0); // empty interval; return no values
+    }
+    let n = (pose - posi + 1) as usize;
+    ls.check_stack(n);
+    for &b in &s.as_bytes()[posi as usize - 1..pose as usize] {
+        ls.push_integer(b as i64);
+    }
+    Ok(n)
+}
+
+// string.char (...)
+fn str_char(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let n = ls.get_top(); // number of arguments
+    let mut bytes = Vec::with_capacity(n as usize);
+    for i in 1..=n {
+        let c = check_integer(ls, i, "char")?;
+        arg_check(ls, (0..=255).contains(&c), i, "char", "value out of range")?;
+        bytes.push(c as u8);
+    }
+    ls.push_bytes(&bytes);
+    Ok(1)
+}
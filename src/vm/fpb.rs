@@ -3,7 +3,7 @@
 ** (eeeeexxx), where the real value is (1xxx) * 2^(eeeee - 1) if
 ** eeeee != 0 and (xxx) otherwise.
  */
-#[allow(dead_code)]
+#[allow(dead_code)] // for the compiler, which encodes NEWTABLE sizes
 pub fn int2fb(mut x: usize) -> usize {
     let mut e = 0; /* exponent */
     if x < 8 {
@@ -19,7 +19,7 @@ pub fn int2fb(mut x: usize) -> usize {
         x = (x + 1) >> 1; /* x = ceil(x / 2) */
         e += 1;
     }
-    return ((e + 1) << 3) | (x - 8);
+    ((e + 1) << 3) | (x - 8)
 }
 
 /* converts back */
@@ -1,76 +1,100 @@
 use super::instructions::Instruction;
-use crate::api::LuaVM;
+use crate::api::{LuaResult, LuaVM};
 
 // R(A+1) := R(B); R(A) := R(B)[RK(C)]
-pub fn _self(i: u32, vm: &mut LuaVM) {
+pub fn _self(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a, mut b, c) = i.abc();
     a += 1;
     b += 1;
 
-    vm.copy(b, a + 1);
+    vm.copy(b, a + 1)?;
     vm.get_rk(c);
-    vm.get_table(b);
-    vm.replace(a);
+    vm.get_table(b)?;
+    vm.replace(a)?;
+    Ok(())
 }
 
 // R(A) := closure(KPROTO[Bx])
-pub fn closure(i: u32, vm: &mut LuaVM) {
+pub fn closure(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a, bx) = i.a_bx();
     a += 1;
 
     vm.load_proto(bx as usize);
-    vm.replace(a);
+    vm.replace(a)?;
+    Ok(())
 }
 
 // R(A), R(A+1), ..., R(A+B-2) = vararg
-pub fn vararg(i: u32, vm: &mut LuaVM) {
+pub fn vararg(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a, b, _) = i.abc();
     a += 1;
 
     if b != 1 {
         // b==0 or b>1
         vm.load_vararg(b - 1);
-        pop_results(a, b, vm)
+        pop_results(a, b, vm)?;
     }
+    Ok(())
 }
 
 // return R(A)(R(A+1), ... ,R(A+B-1))
-pub fn tail_call(i: u32, vm: &mut LuaVM) {
+pub fn tail_call(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a, b, _) = i.abc();
     a += 1;
 
-    // todo: optimize tail call!
-    let c = 0;
-    let nargs = push_func_and_args(a, b, vm);
-    vm.call(nargs, c - 1);
-    pop_results(a, c, vm);
+    let nargs = push_func_and_args(a, b, vm)?;
+    if vm.tail_call(nargs)? {
+        // a Rust function, the RETURN that follows returns its results
+        pop_results(a, 0, vm)?;
+    }
+    Ok(())
 }
 
 // R(A), ... ,R(A+C-2) := R(A)(R(A+1), ... ,R(A+B-1))
-pub fn call(i: u32, vm: &mut LuaVM) {
+pub fn call(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a, b, c) = i.abc();
     a += 1;
 
-    // println(":::"+ vm.StackToString())
-    let nargs = push_func_and_args(a, b, vm);
-    vm.call(nargs, c - 1);
-    pop_results(a, c, vm);
+    let nargs = push_func_and_args(a, b, vm)?;
+    if vm.pre_call(nargs, c - 1)? {
+        pop_results(a, c, vm)?;
+    }
+    Ok(())
+}
+
+// the results of a CALL to a Lua function, once it has returned
+pub fn finish_call(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (mut a, _, c) = i.abc();
+    a += 1;
+
+    pop_results(a, c, vm)
 }
 
-fn push_func_and_args(a: isize, b: isize, vm: &mut LuaVM) -> usize {
+// R(A+3), ... ,R(A+2+C) := R(A)(R(A+1), R(A+2));
+pub fn tfor_call(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (mut a, _, c) = i.abc();
+    a += 1;
+
+    push_func_and_args(a, 3, vm)?;
+    vm.call(2, c)?;
+    pop_results(a + 3, c + 1, vm)?;
+    Ok(())
+}
+
+fn push_func_and_args(a: isize, b: isize, vm: &mut dyn LuaVM) -> LuaResult<usize> {
     if b >= 1 {
         vm.check_stack(b as usize);
         for i in a..(a + b) {
             vm.push_value(i);
         }
-        b as usize - 1
+        Ok(b as usize - 1)
     } else {
-        fix_stack(a, vm);
-        vm.get_top() as usize - vm.register_count() - 1
+        fix_stack(a, vm)?;
+        Ok(vm.get_top() as usize - vm.register_count() - 1)
     }
 }
 
-fn fix_stack(a: isize, vm: &mut LuaVM) {
+fn fix_stack(a: isize, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let x = vm.to_integer(-1) as isize;
     vm.pop(1);
 
@@ -78,25 +102,29 @@ fn fix_stack(a: isize, vm: &mut LuaVM) {
     for i in a..x {
         vm.push_value(i);
     }
-    vm.rotate(vm.register_count() as isize + 1, x - a);
+    if x > a {
+        vm.rotate(vm.register_count() as isize + 1, x - a)?;
+    }
+    Ok(())
 }
 
-fn pop_results(a: isize, c: isize, vm: &mut LuaVM) {
+fn pop_results(a: isize, c: isize, vm: &mut dyn LuaVM) -> LuaResult<()> {
     if c == 1 {
         // no results
     } else if c > 1 {
         for i in (a..(a + c - 1)).rev() {
-            vm.replace(i);
+            vm.replace(i)?;
         }
     } else {
         // leave results on stack
         vm.check_stack(1);
         vm.push_integer(a as i64);
     }
+    Ok(())
 }
 
 // return R(A), ... ,R(A+B-2)
-pub fn _return(i: u32, vm: &mut LuaVM) {
+pub fn _return(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a, b, _) = i.abc();
     a += 1;
 
@@ -109,6 +137,7 @@ pub fn _return(i: u32, vm: &mut LuaVM) {
             vm.push_value(i);
         }
     } else {
-        fix_stack(a, vm);
+        fix_stack(a, vm)?;
     }
+    Ok(())
 }
@@ -1,49 +1,316 @@
 use super::instructions::Instruction;
-use crate::api::{consts::*, LuaVM};
+use crate::api::{LuaResult, LuaVM};
+use crate::state::float_to_integer;
 
-// R(A)-=R(A+2); pc+=sBx
-pub fn for_prep(i: u32, vm: &mut LuaVM) {
+/*
+** Integer loops precompute their iteration count: after FORPREP, R(A+1)
+** holds the number of iterations still to run (an unsigned count stored in
+** the integer bits), so FORLOOP never compares and can never overflow.
+** Float loops keep the limit in R(A+1) and compare on every iteration.
+** As in Lua 5.3, a zero step is not an error: the loop runs for ever when
+** the limit is not above the initial value and not at all otherwise.
+** Strings are converted to numbers; an integer loop needs an actual
+** integer initial value and step, while its limit may be any number.
+** FORPREP runs the first iteration itself by falling through into the body,
+** or skips the whole loop (FORLOOP included) when it must not run.
+*/
+
+// if the loop runs: R(A+1) := count or limit; R(A+3) := R(A)
+// else: pc+=sBx+1
+pub fn for_prep(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a, sbx) = i.a_sbx();
     a += 1;
 
-    if vm.type_id(a) == LUA_TSTRING {
-        vm.push_number(vm.to_number(a));
-        vm.replace(a);
-    }
-    if vm.type_id(a + 1) == LUA_TSTRING {
-        vm.push_number(vm.to_number(a + 1));
-        vm.replace(a + 1);
-    }
-    if vm.type_id(a + 2) == LUA_TSTRING {
-        vm.push_number(vm.to_number(a + 2));
-        vm.replace(a + 2);
-    }
+    let skip = if vm.is_integer(a) && vm.is_integer(a + 2) {
+        let (init, step) = (vm.to_integer(a), vm.to_integer(a + 2));
+        prep_integer_loop(vm, a, init, step)?
+    } else {
+        prep_float_loop(vm, a)?
+    };
 
-    vm.push_value(a);
-    vm.push_value(a + 2);
-    vm.arith(LUA_OPSUB);
-    vm.replace(a);
-    vm.add_pc(sbx);
+    if skip {
+        vm.add_pc(sbx + 1);
+    } else {
+        vm.copy(a, a + 3)?;
+    }
+    Ok(())
 }
 
-// R(A)+=R(A+2);
-// if R(A) <?= R(A+1) then {
-//   pc+=sBx; R(A+3)=R(A)
+// if there is another iteration then {
+//   R(A)+=R(A+2); pc+=sBx; R(A+3)=R(A)
 // }
-pub fn for_loop(i: u32, vm: &mut LuaVM) {
+pub fn for_loop(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a, sbx) = i.a_sbx();
     a += 1;
 
-    // R(A)+=R(A+2);
-    vm.push_value(a + 2);
-    vm.push_value(a);
-    vm.arith(LUA_OPADD);
-    vm.replace(a);
+    if vm.is_integer(a + 2) {
+        let step = vm.to_integer(a + 2);
+        let count = vm.to_integer(a + 1) as u64;
+        if count > 0 {
+            let idx = vm.to_integer(a).wrapping_add(step);
+            if step != 0 {
+                vm.set_integer(a + 1, (count - 1) as i64)?;
+            }
+            vm.set_integer(a, idx)?;
+            vm.set_integer(a + 3, idx)?;
+            vm.add_pc(sbx);
+        }
+    } else {
+        let step = vm.to_number(a + 2);
+        let limit = vm.to_number(a + 1);
+        let idx = vm.to_number(a) + step;
+        let more = if step > 0.0 { idx <= limit } else { limit <= idx };
+        if more {
+            vm.set_number(a, idx)?;
+            vm.set_number(a + 3, idx)?;
+            vm.add_pc(sbx);
+        }
+    }
+    Ok(())
+}
 
-    let positive_step = vm.to_number(a + 2) >= 0.0;
-    if positive_step && vm.compare(a, a + 1, LUA_OPLE) || !positive_step && vm.compare(a + 1, a, LUA_OPLE) {
-        // pc+=sBx; R(A+3)=R(A)
+// if R(A+1) ~= nil then {
+//   R(A)=R(A+1); pc += sBx
+// }
+pub fn tfor_loop(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (mut a, sbx) = i.a_sbx();
+    a += 1;
+
+    if !vm.is_nil(a + 1) {
+        vm.copy(a + 1, a)?;
         vm.add_pc(sbx);
-        vm.copy(a, a + 3);
+    }
+    Ok(())
+}
+
+// returns true if the loop must not run
+fn prep_integer_loop(vm: &mut dyn LuaVM, a: isize, init: i64, step: i64) -> LuaResult<bool> {
+    let limit = match for_limit(vm, a + 1, step)? {
+        Some(limit) => limit,
+        None => return Ok(true),
+    };
+    let skip = if step > 0 { init > limit } else { init < limit };
+    if skip {
+        return Ok(true);
+    }
+
+    // count of iterations after the first one, computed without overflow;
+    // a zero step never reaches the limit, FORLOOP keeps its count at 1
+    let count = if step == 0 {
+        1
+    } else if step > 0 {
+        (limit as u64).wrapping_sub(init as u64) / step as u64
+    } else {
+        // -(step + 1) + 1 avoids negating MININTEGER
+        (init as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1)
+    };
+    vm.set_integer(a + 1, count as i64)?;
+    Ok(false)
+}
+
+// converts the limit of an integer loop, like forlimit: integer strings
+// stay integers, float limits are rounded towards the loop and clipped
+// when they fall outside the integer range; returns None if the loop must
+// not run
+fn for_limit(vm: &mut dyn LuaVM, idx: isize, step: i64) -> LuaResult<Option<i64>> {
+    if let Some(limit) = vm.to_integerx(idx) {
+        return Ok(Some(limit));
+    }
+    let flimit = for_number(vm, idx, "limit")?;
+    let rounded = if step < 0 { flimit.ceil() } else { flimit.floor() };
+    let limit = if let Some(limit) = float_to_integer(rounded) {
+        Some(limit)
+    } else if flimit.is_nan() {
+        None
+    } else if 0.0 < flimit {
+        // too large (or +inf): a descending loop can't start below it
+        if step < 0 {
+            None
+        } else {
+            Some(i64::MAX)
+        }
+    } else if step >= 0 {
+        // too small or -inf: an ascending loop can't start above it
+        None
+    } else {
+        Some(i64::MIN)
+    };
+    Ok(limit)
+}
+
+// returns true if the loop must not run
+fn prep_float_loop(vm: &mut dyn LuaVM, a: isize) -> LuaResult<bool> {
+    let limit = for_number(vm, a + 1, "limit")?;
+    let step = for_number(vm, a + 2, "step")?;
+    let init = for_number(vm, a, "initial value")?;
+    // every comparison with NaN is false, so a NaN anywhere skips the loop
+    let runs = if step > 0.0 { init <= limit } else { limit <= init };
+    if !runs || step.is_nan() {
+        return Ok(true);
+    }
+
+    vm.set_number(a, init)?;
+    vm.set_number(a + 1, limit)?;
+    vm.set_number(a + 2, step)?;
+    Ok(false)
+}
+
+// control values must be numbers or strings convertible to numbers
+fn for_number(vm: &mut dyn LuaVM, idx: isize, what: &str) -> LuaResult<f64> {
+    match vm.to_numberx(idx) {
+        Some(n) => Ok(n),
+        None => Err(vm.runtime_error(&format!("'for' {} must be a number", what))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::LuaAPI;
+    use crate::state::LuaState;
+    use crate::vm::opcodes::{OP_FORLOOP, OP_FORPREP};
+
+    fn asbx(op: u8, sbx: isize) -> u32 {
+        op as u32 | ((sbx + 131071) as u32) << 14
+    }
+
+    // runs 'for v = init, limit, step' with the control values in R(0..2),
+    // returns the values taken by v (at most 10 of them)
+    fn run_for(push: impl Fn(&mut LuaState)) -> LuaResult<Vec<f64>> {
+        let mut ls = LuaState::new();
+        push(&mut ls);
+        ls.set_top(4).unwrap();
+        let mut seen = Vec::new();
+        for_prep(asbx(OP_FORPREP, 5), &mut ls)?;
+        if ls.pc() != 0 {
+            return Ok(seen); // skipped
+        }
+        while seen.len() < 10 {
+            seen.push(ls.to_number(4));
+            for_loop(asbx(OP_FORLOOP, -1), &mut ls)?;
+            if ls.pc() == 0 {
+                break;
+            }
+            ls.add_pc(1);
+        }
+        Ok(seen)
+    }
+
+    fn floats(init: f64, limit: f64, step: f64) -> Vec<f64> {
+        run_for(|ls| {
+            ls.push_number(init);
+            ls.push_number(limit);
+            ls.push_number(step);
+        })
+        .unwrap()
+    }
+
+    fn integers(init: i64, limit: f64, step: i64) -> Vec<f64> {
+        run_for(|ls| {
+            ls.push_integer(init);
+            ls.push_number(limit);
+            ls.push_integer(step);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn float_loops() {
+        assert_eq!(floats(1.0, 2.0, 0.5), vec![1.0, 1.5, 2.0]);
+        assert_eq!(floats(1.0, 0.0, -0.5), vec![1.0, 0.5, 0.0]);
+        assert_eq!(floats(1.0, 0.5, 1.0), vec![]);
+        assert_eq!(floats(0.5, 1.0, -1.0), vec![]);
+    }
+
+    #[test]
+    fn nan_control_values_skip_the_loop() {
+        let nan = f64::NAN;
+        for &step in [1.0, -1.0].iter() {
+            assert_eq!(floats(nan, 10.0, step), vec![]);
+            assert_eq!(floats(1.0, nan, step), vec![]);
+            assert_eq!(floats(-1.0, nan, step), vec![]);
+        }
+        assert_eq!(floats(1.0, 10.0, nan), vec![]);
+        assert_eq!(floats(10.0, 1.0, nan), vec![]);
+        assert_eq!(integers(1, nan, 1), vec![]);
+        assert_eq!(integers(1, nan, -1), vec![]);
+    }
+
+    #[test]
+    fn integer_loops_clip_float_limits() {
+        assert_eq!(integers(1, 3.5, 1), vec![1.0, 2.0, 3.0]);
+        assert_eq!(integers(3, 0.5, -1), vec![3.0, 2.0, 1.0]);
+        assert_eq!(integers(i64::MAX - 1, f64::INFINITY, 1).len(), 2);
+        assert_eq!(integers(i64::MIN + 1, f64::NEG_INFINITY, -1).len(), 2);
+        assert_eq!(integers(1, f64::NEG_INFINITY, 1), vec![]);
+        assert_eq!(integers(1, f64::INFINITY, -1), vec![]);
+    }
+
+    fn strings(init: &str, limit: &str, step: &str) -> LuaResult<Vec<f64>> {
+        run_for(|ls| {
+            ls.push_string(init.to_string());
+            ls.push_string(limit.to_string());
+            ls.push_string(step.to_string());
+        })
+    }
+
+    // whether 'for v = init, limit, 1' gives v an integer value
+    fn integer_loop(init: impl Fn(&mut LuaState), limit: &str) -> bool {
+        let mut ls = LuaState::new();
+        init(&mut ls);
+        ls.push_string(limit.to_string());
+        ls.push_integer(1);
+        ls.set_top(4).unwrap();
+        for_prep(asbx(OP_FORPREP, 5), &mut ls).unwrap();
+        ls.is_integer(4)
+    }
+
+    #[test]
+    fn zero_steps_run_for_ever_or_not_at_all() {
+        assert_eq!(integers(1, 1.0, 0), vec![1.0; 10]);
+        assert_eq!(integers(2, 1.0, 0), vec![2.0; 10]);
+        assert_eq!(integers(1, 2.0, 0), vec![]);
+        assert_eq!(integers(1, f64::INFINITY, 0), vec![]);
+        assert_eq!(integers(1, f64::NEG_INFINITY, 0), vec![]);
+        assert_eq!(floats(1.0, 1.0, 0.0), vec![1.0; 10]);
+        assert_eq!(floats(2.0, 1.0, -0.0), vec![2.0; 10]);
+        assert_eq!(floats(1.0, 2.0, 0.0), vec![]);
+    }
+
+    #[test]
+    fn string_control_values_are_converted() {
+        assert_eq!(strings("1", "3", "1").unwrap(), vec![1.0, 2.0, 3.0]);
+        assert_eq!(strings(" 0x10 ", "15.5", "-0.5").unwrap(), vec![16.0, 15.5]);
+        assert_eq!(strings("1", "2", "0.5").unwrap(), vec![1.0, 1.5, 2.0]);
+
+        // an integer string limit keeps the loop on integers, strings as
+        // initial value make it a float loop
+        assert!(integer_loop(|ls| ls.push_integer(1), "3"));
+        assert!(integer_loop(|ls| ls.push_integer(1), "3.5"));
+        assert!(!integer_loop(|ls| ls.push_string("1".to_string()), "3"));
+        assert!(!integer_loop(|ls| ls.push_number(1.0), "3"));
+        assert_eq!(
+            run_for(|ls| {
+                ls.push_integer(3);
+                ls.push_string("0.5".to_string());
+                ls.push_integer(-1);
+            })
+            .unwrap(),
+            vec![3.0, 2.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn non_numeric_control_values_are_errors() {
+        let message = |r: LuaResult<Vec<f64>>| r.unwrap_err().to_string();
+        assert!(message(strings("1", "x", "1")).ends_with("'for' limit must be a number"));
+        assert!(message(strings("1", "2", "")).ends_with("'for' step must be a number"));
+        assert!(message(strings("1e", "2", "1")).ends_with("'for' initial value must be a number"));
+        let r = run_for(|ls| {
+            ls.push_integer(1);
+            ls.push_boolean(true);
+            ls.push_integer(1);
+        });
+        assert!(message(r).ends_with("'for' limit must be a number"));
     }
 }
@@ -1,21 +1,23 @@
 use super::instructions::*;
-use crate::api::LuaVM;
+use crate::api::{LuaResult, LuaVM};
 
 // R(A) := R(B)
-pub fn _move(i: u32, vm: &mut LuaVM) {
+pub fn _move(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a, mut b, _) = i.abc();
     a += 1;
     b += 1;
 
-    vm.copy(b, a);
+    vm.copy(b, a)?;
+    Ok(())
 }
 
 // pc+=sBx; if (A) close all upvalues >= R(A - 1)
-pub fn jmp(i: u32, vm: &mut LuaVM) {
+pub fn jmp(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (a, sbx) = i.a_sbx();
 
     vm.add_pc(sbx);
     if a != 0 {
         vm.close_upvalues(a);
     }
+    Ok(())
 }
@@ -1,50 +1,53 @@
 use super::fpb::fb2int;
 use super::instructions::Instruction;
-use crate::api::LuaVM;
+use crate::api::{LuaResult, LuaVM};
 
 /* number of list items to accumulate before a SETLIST instruction */
 const LFIELDS_PER_FLUSH: isize = 50;
 
 // R(A) := {} (size = B,C)
-pub fn new_table(i: u32, vm: &mut LuaVM) {
+pub fn new_table(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a, b, c) = i.abc();
     a += 1;
 
     let narr = fb2int(b as usize);
     let nrec = fb2int(c as usize);
     vm.create_table(narr, nrec);
-    vm.replace(a);
+    vm.replace(a)?;
+    Ok(())
 }
 
 // R(A) := R(B)[RK(C)]
-pub fn get_table(i: u32, vm: &mut LuaVM) {
+pub fn get_table(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a, mut b, c) = i.abc();
     a += 1;
     b += 1;
 
     vm.get_rk(c);
-    vm.get_table(b);
-    vm.replace(a);
+    vm.get_table(b)?;
+    vm.replace(a)?;
+    Ok(())
 }
 
 // R(A)[RK(B)] := RK(C)
-pub fn set_table(i: u32, vm: &mut LuaVM) {
+pub fn set_table(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a, b, c) = i.abc();
     a += 1;
 
     vm.get_rk(b);
     vm.get_rk(c);
-    vm.set_table(a);
+    vm.set_table(a)?;
+    Ok(())
 }
 
 
 // R(A)[(C-1)*FPF+i] := R(A+i), 1 <= i <= B
-pub fn set_list(i: u32, vm: &mut LuaVM) {
+pub fn set_list(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a, mut b, mut c) = i.abc();
     a += 1;
 
     if c > 0 {
-        c = c - 1;
+        c -= 1;
     } else {
         c = vm.fetch().ax();
     }
@@ -60,7 +63,7 @@ pub fn set_list(i: u32, vm: &mut LuaVM) {
     for j in 1..(b + 1) {
         idx += 1;
         vm.push_value(a + j);
-        vm.set_i(a, idx);
+        vm.raw_set_i(a, idx)?;
     }
 
     if b_is_zero {
@@ -68,10 +71,11 @@ pub fn set_list(i: u32, vm: &mut LuaVM) {
         for j in (nreg + 1)..(vm.get_top() + 1) {
             idx += 1;
             vm.push_value(j);
-            vm.set_i(a, idx);
+            vm.raw_set_i(a, idx)?;
         }
 
         // clear stack
-        vm.set_top(nreg);
+        vm.set_top(nreg)?;
     }
+    Ok(())
 }
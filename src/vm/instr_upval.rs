@@ -1,38 +1,40 @@
 use super::instructions::Instruction;
-use crate::api::LuaVM;
+use crate::api::{LuaResult, LuaVM};
 use crate::api::consts::*;
 
 // R(A) := UpValue[B][RK(C)]
-pub fn get_tab_up(i: u32, vm: &mut LuaVM) {
+pub fn get_tab_up(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     /*let (mut a, mut b, c) = i.abc();
     a += 1; b += 1;
 
     vm.push_global_table();
     vm.get_rk(c);
-    vm.get_table(-2);
-    vm.replace(a);
+    vm.get_table(-2)?;
+    vm.replace(a)?;
     vm.pop(1);
 
     println!("lua_upvalue_index = {}",lua_upvalue_index(b))
 */
-    let (mut a,mut b,mut c) = i.abc();
+    let (mut a,mut b,c) = i.abc();
     a += 1;
     b += 1;
     vm.get_rk(c);
-    vm.get_table(lua_upvalue_index(b));
-    vm.replace(a);
+    vm.get_table(lua_upvalue_index(b))?;
+    vm.replace(a)?;
+    Ok(())
 }
 
 
-pub fn set_tab_up(i: u32,vm: &mut LuaVM) {
+pub fn set_tab_up(i: u32,vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a,b,c) = i.abc();
     a += 1;
     vm.get_rk(b);
     vm.get_rk(c);
-    vm.set_table(lua_upvalue_index(a));
+    vm.set_table(lua_upvalue_index(a))?;
+    Ok(())
 }
 
-pub fn get_upval(i: u32, vm: &mut LuaVM) {
+pub fn get_upval(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a,mut b,_) = i.abc();
     a += 1;
     b += 1;
@@ -40,7 +42,7 @@ pub fn get_upval(i: u32, vm: &mut LuaVM) {
     vm.copy(lua_upvalue_index(b),a)
 }
 
-pub fn set_upval(i: u32, vm: &mut LuaVM) {
+pub fn set_upval(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
     let (mut a,mut b,_) = i.abc();
     a += 1;
     b += 1;
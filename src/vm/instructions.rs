@@ -1,6 +1,6 @@
 use super::opcodes::OPCODES;
 use super::opcodes::*;
-use crate::api::LuaVM;
+use crate::api::{LuaResult, LuaVM};
 
 use super::instr_for::*;
 use super::instr_load::*;
@@ -38,7 +38,9 @@ pub trait Instruction {
     fn a_bx(self) -> (isize, isize);
     fn a_sbx(self) -> (isize, isize);
     fn ax(self) -> isize;
-    fn execute(self, vm: &mut LuaVM);
+    fn execute(self, vm: &mut dyn LuaVM) -> LuaResult<()>;
+    // completes a call instruction once the Lua function it called returns
+    fn finish_call(self, vm: &mut dyn LuaVM) -> LuaResult<()>;
 }
 
 impl Instruction for u32 {
@@ -88,7 +90,7 @@ impl Instruction for u32 {
         (self >> 6) as isize
     }
 
-    fn execute(self, vm: &mut LuaVM) {
+    fn execute(self, vm: &mut dyn LuaVM) -> LuaResult<()> {
         match self.opcode() {
             OP_MOVE => _move(self, vm),
             OP_LOADK => load_k(self, vm),
@@ -131,8 +133,8 @@ impl Instruction for u32 {
             OP_RETURN => _return(self, vm),
             OP_FORLOOP => for_loop(self, vm),
             OP_FORPREP => for_prep(self, vm),
-            // OP_TFORCALL => (),
-            // OP_TFORLOOP => (),
+            OP_TFORCALL => tfor_call(self, vm),
+            OP_TFORLOOP => tfor_loop(self, vm),
             OP_SETLIST => set_list(self, vm),
             OP_CLOSURE => closure(self, vm),
             OP_VARARG => vararg(self, vm),
@@ -140,19 +142,27 @@ impl Instruction for u32 {
             _ => {
                 println!("TODO::not implemnted op: {}\n",self.opname());
                 //unimplemented!()
+                Ok(())
             }
         }
     }
+
+    fn finish_call(self, vm: &mut dyn LuaVM) -> LuaResult<()> {
+        match self.opcode() {
+            OP_CALL => finish_call(self, vm),
+            _ => Ok(()),
+        }
+    }
 }
 
 //instruction print assist method
-impl Instruction {
+impl dyn Instruction {
     pub fn print_operands(i: u32) {
         match i.opmode() {
-            OP_MODE_ABC => Instruction::print_abc(i),
-            OP_MODE_ABX => Instruction::print_abx(i),
-            OP_MODE_ASBX => Instruction::print_asbx(i),
-            OP_MODE_AX => Instruction::print_ax(i),
+            OP_MODE_ABC => <dyn Instruction>::print_abc(i),
+            OP_MODE_ABX => <dyn Instruction>::print_abx(i),
+            OP_MODE_ASBX => <dyn Instruction>::print_asbx(i),
+            OP_MODE_AX => <dyn Instruction>::print_ax(i),
             _ => panic!("corrupt!"),
         }
     }
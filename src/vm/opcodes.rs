@@ -76,7 +76,7 @@ pub struct OpCode {
     pub name: &'static str,
 }
 
-pub const OPCODES: &'static [OpCode] = &[
+pub const OPCODES: &[OpCode] = &[
     /*               B               C              mode                  name    */
     opcode(OP_ARG_R, OP_ARG_N, OP_MODE_ABC, "MOVE    "), // R(A) := R(B)
     opcode(OP_ARG_K, OP_ARG_N, OP_MODE_ABX, "LOADK   "), // R(A) := Kst(Bx)
@@ -0,0 +1,160 @@
+// The API as the host uses it, outside of any Lua function.
+mod common;
+
+use common::*;
+use lua::api::consts::*;
+use lua::api::{LuaAPI, LuaResult, RustFn};
+
+#[test]
+fn set_metatable_rejects_non_tables() {
+    let mut ls = new_state();
+    ls.new_table();
+    ls.push_integer(1);
+    let err = ls.set_metatable(-2).unwrap_err();
+    assert_eq!(err.status(), LUA_ERRRUN);
+    assert_eq!(err.to_string(), "table expected as metatable, got number");
+    assert_eq!(ls.get_top(), 1); // the metatable is popped
+
+    ls.new_table();
+    ls.set_metatable(-2).unwrap();
+    assert!(ls.get_metatable(-1));
+    ls.push_nil();
+    ls.set_metatable(-3).unwrap();
+    ls.pop(1);
+    assert!(!ls.get_metatable(-1));
+}
+
+#[test]
+fn strings_keep_any_bytes() {
+    let mut ls = new_state();
+    let bytes = [b'a', 0, 0xff, 0x80, b'z'];
+    ls.push_bytes(&bytes);
+    assert_eq!(ls.to_bytes(-1).unwrap().as_bytes(), &bytes);
+    assert_eq!(ls.raw_len(-1), 5);
+
+    ls.push_integer(10);
+    assert_eq!(ls.to_bytes(-1).unwrap().as_bytes(), b"10");
+    ls.new_table();
+    assert!(ls.to_bytes(-1).is_none());
+}
+
+#[test]
+fn metamethods_set_by_the_host_are_found() {
+    let mut ls = new_state();
+    ls.new_table(); // t
+    ls.new_table(); // mt
+    ls.new_table(); // defaults
+    ls.push_integer(42);
+    ls.set_field(-2, "answer").unwrap();
+    ls.set_field(-2, "__index").unwrap(); // mt.__index = defaults
+    ls.set_metatable(-2).unwrap();
+    assert_eq!(ls.get_field(-1, "answer").unwrap(), LUA_TNUMBER);
+    assert_eq!(ls.to_integer(-1), 42);
+}
+
+#[test]
+fn nan_and_nil_keys_are_errors() {
+    let mut ls = new_state();
+    ls.new_table();
+    ls.push_number(f64::NAN);
+    ls.push_integer(1);
+    assert_eq!(ls.set_table(-3).unwrap_err().to_string(), "table index is NaN");
+    ls.set_top(1).unwrap();
+    ls.push_nil();
+    ls.push_integer(1);
+    assert_eq!(ls.set_table(-3).unwrap_err().to_string(), "table index is nil");
+    ls.set_top(1).unwrap();
+    assert_eq!(ls.raw_len(1), 0);
+
+    // t[0/0] = 1 and t[nil] = 1 in Lua code, the error gets a position
+    let nan = abc(DIV, 1, rk(1), rk(1));
+    let nil = abc(LOADNIL, 1, 0, 0);
+    for &(key, msg) in [(nan, "test:3: table index is NaN"), (nil, "test:3: table index is nil")].iter() {
+        let main = Function::main(
+            vec![abc(NEWTABLE, 0, 0, 0), key, abc(SETTABLE, 0, 1, rk(0)), abc(RETURN, 0, 1, 0)],
+            vec![Constant::Int(1), Constant::Num(0.0)],
+        );
+        ls.load(main.dump(), "test", "b");
+        assert_eq!(ls.pcall(0, 0, 0), LUA_ERRRUN);
+        assert_eq!(ls.to_string(-1), msg);
+        ls.set_top(1).unwrap();
+    }
+}
+
+#[test]
+fn float_keys_with_integer_values_are_integer_keys() {
+    let mut ls = new_state();
+    ls.new_table();
+    ls.push_number(2.0);
+    ls.push_string("two".to_string());
+    ls.set_table(-3).unwrap();
+    assert_eq!(ls.get_i(-1, 2).unwrap(), LUA_TSTRING);
+    assert_eq!(ls.to_string(-1), "two");
+    ls.pop(1);
+
+    ls.push_string("TWO".to_string());
+    ls.set_i(-2, 2).unwrap();
+    ls.push_number(2.0);
+    assert_eq!(ls.get_table(-2).unwrap(), LUA_TSTRING);
+    assert_eq!(ls.to_string(-1), "TWO");
+    ls.pop(1);
+
+    // -0.0 is the key 0, 2.5 stays a float key
+    ls.push_number(-0.0);
+    ls.push_integer(0);
+    ls.set_table(-3).unwrap();
+    assert_eq!(ls.get_i(-1, 0).unwrap(), LUA_TNUMBER);
+    ls.pop(1);
+    ls.push_number(2.5);
+    ls.push_boolean(true);
+    ls.set_table(-3).unwrap();
+    assert_eq!(ls.get_i(-1, 2).unwrap(), LUA_TSTRING);
+    ls.pop(1);
+    ls.push_number(2.5);
+    assert_eq!(ls.get_table(-2).unwrap(), LUA_TBOOLEAN);
+    ls.pop(1);
+    assert_eq!(ls.raw_len(-1), 0);
+    assert_eq!(ls.get_top(), 1);
+}
+
+// moves the top below the bottom of its frame
+fn set_top_too_low(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    ls.push_integer(1);
+    ls.set_top(-3)?;
+    Ok(0)
+}
+
+// copies into an upvalue it doesn't have
+fn copy_to_missing_upvalue(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    ls.push_integer(1);
+    ls.copy(1, LUA_REGISTRYINDEX - 1)?; // upvalue 1
+    Ok(0)
+}
+
+#[test]
+fn bad_indices_are_errors() {
+    let mut ls = new_state();
+    ls.push_integer(1);
+    assert_eq!(ls.set_top(-3).unwrap_err().to_string(), "invalid new top");
+    assert_eq!(ls.set_top(LUA_REGISTRYINDEX).unwrap_err().to_string(), "invalid new top");
+    assert_eq!(ls.copy(1, 3).unwrap_err().to_string(), "invalid index");
+    assert_eq!(ls.replace(5).unwrap_err().to_string(), "invalid index");
+    assert_eq!(ls.get_top(), 1);
+    assert_eq!(ls.to_integer(1), 1);
+
+    // raised inside a call, the error unwinds it like any other
+    let depth = ls.stack_frames(0).len();
+    let cases: [(RustFn, &str); 2] = [(set_top_too_low, "invalid new top"), (copy_to_missing_upvalue, "invalid index")];
+    for &(f, msg) in cases.iter() {
+        ls.push_rust_function(f);
+        assert_eq!(ls.pcall(0, 0, 0), LUA_ERRRUN);
+        assert_eq!(ls.to_string(-1), msg);
+        assert_eq!(ls.get_top(), 2);
+        assert_eq!(ls.stack_frames(0).len(), depth);
+        ls.pop(1);
+    }
+    ls.set_top(3).unwrap();
+    assert!(ls.is_nil(3));
+    ls.set_top(0).unwrap();
+    assert_eq!(ls.get_top(), 0);
+}
@@ -0,0 +1,133 @@
+// Calling a value that is not a function goes through its __call
+// metamethod, which gets the value as an extra first argument. Every way
+// of making a call does it: CALL, TAILCALL, TFORCALL and the API.
+mod common;
+
+use common::*;
+use lua::api::consts::*;
+use lua::api::{LuaAPI, LuaResult};
+use lua::state::LuaState;
+
+// returns "<number of arguments>: <type of each argument>"
+fn describe(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let n = ls.get_top();
+    let types: Vec<&str> = (1..=n).map(|i| ls.type_name(ls.type_id(i))).collect();
+    let text = format!("{}: {}", n, types.join(" "));
+    ls.push_string(text);
+    Ok(1)
+}
+
+// a state with the global 'obj', a table whose metatable has __call = f
+fn state_with_callable(push_call: impl Fn(&mut LuaState)) -> LuaState {
+    let mut ls = new_state();
+    ls.new_table();
+    ls.new_table();
+    push_call(&mut ls);
+    ls.set_field(-2, "__call").unwrap();
+    ls.set_metatable(-2).unwrap();
+    ls.set_global("obj").unwrap();
+    ls
+}
+
+fn callable() -> LuaState {
+    state_with_callable(|ls| ls.push_rust_function(describe))
+}
+
+// runs 'main' and returns its result as a string
+fn result_of(ls: &mut LuaState, main: &Function) -> String {
+    run(ls, main, 1).unwrap();
+    ls.to_string(-1)
+}
+
+#[test]
+fn api_calls() {
+    let mut ls = callable();
+    ls.get_global("obj").unwrap();
+    ls.push_integer(1);
+    ls.push_string("two".to_string());
+    ls.call(2, 1).unwrap();
+    assert_eq!(ls.to_string(-1), "3: table number string");
+    ls.pop(1);
+
+    ls.get_global("obj").unwrap();
+    ls.push_boolean(true);
+    assert_eq!(ls.pcall(1, 1, 0), LUA_OK);
+    assert_eq!(ls.to_string(-1), "2: table boolean");
+    ls.pop(1);
+    assert_eq!(ls.get_top(), 0);
+}
+
+#[test]
+fn call_instruction() {
+    // return obj("x")
+    let main = Function::main(
+        vec![abc(GETTABUP, 0, 0, rk(0)), abx(LOADK, 1, 1), abc(CALL, 0, 2, 2), abc(RETURN, 0, 2, 0)],
+        vec![Constant::Str(b"obj"), Constant::Str(b"x")],
+    );
+    assert_eq!(result_of(&mut callable(), &main), "2: table string");
+}
+
+#[test]
+fn tail_call_instruction() {
+    // return (function () return obj(1, 2) end)()
+    let f = Function::new(
+        0,
+        vec![
+            abc(GETTABUP, 0, 0, rk(0)),
+            abx(LOADK, 1, 1),
+            abx(LOADK, 2, 2),
+            abc(TAILCALL, 0, 3, 0),
+            abc(RETURN, 0, 0, 0),
+        ],
+        vec![Constant::Str(b"obj"), Constant::Int(1), Constant::Int(2)],
+        vec![(false, 0)],
+    );
+    let main = Function::main(vec![abx(CLOSURE, 0, 0), abc(CALL, 0, 1, 2), abc(RETURN, 0, 2, 0)], vec![])
+        .with_protos(vec![f]);
+    assert_eq!(result_of(&mut callable(), &main), "3: table number number");
+}
+
+#[test]
+fn generic_for_iterator() {
+    // for k in obj, "s", nil do return k end
+    let main = Function::main(
+        vec![
+            abc(GETTABUP, 0, 0, rk(0)),
+            abx(LOADK, 1, 1),
+            abc(LOADNIL, 2, 0, 0),
+            asbx(JMP, 0, 1),
+            abc(RETURN, 3, 2, 0),
+            abc(TFORCALL, 0, 0, 1),
+            asbx(TFORLOOP, 2, -3),
+            abc(RETURN, 0, 1, 0),
+        ],
+        vec![Constant::Str(b"obj"), Constant::Str(b"s")],
+    );
+    assert_eq!(result_of(&mut callable(), &main), "3: table string nil");
+}
+
+#[test]
+fn call_metamethods_must_be_functions() {
+    // __call = {} is not called in turn
+    let mut ls = state_with_callable(|ls| ls.new_table());
+    ls.get_global("obj").unwrap();
+    let err = ls.call(0, 0).unwrap_err();
+    assert_eq!(err.to_string(), "attempt to call a table value");
+    ls.get_global("obj").unwrap();
+    assert_eq!(ls.pcall(0, 0, 0), LUA_ERRRUN);
+    assert_eq!(ls.to_string(-1), "attempt to call a table value");
+    ls.pop(1);
+
+    // obj()
+    let main = Function::main(
+        vec![abc(GETTABUP, 0, 0, rk(0)), abc(CALL, 0, 1, 1), abc(RETURN, 0, 1, 0)],
+        vec![Constant::Str(b"obj")],
+    );
+    let err = run(&mut ls, &main, 0).unwrap_err();
+    assert_eq!(err.to_string(), "test:2: attempt to call a table value (global 'obj')");
+
+    // a value without a metatable
+    ls.push_integer(3);
+    assert_eq!(ls.pcall(0, 0, 0), LUA_ERRRUN);
+    assert_eq!(ls.to_string(-1), "attempt to call a number value");
+}
@@ -0,0 +1,183 @@
+// Call depth: calls from Lua to Lua are limited by the stack slots
+// (LUAI_MAXSTACK), calls made from Rust by LUAI_MAXCCALLS, and tail calls
+// reuse the frame of the caller.
+mod common;
+
+use common::*;
+use lua::api::consts::*;
+use lua::api::{LuaAPI, LuaResult};
+use std::cell::Cell;
+
+// the limit of nested calls from Rust, not exported by the library
+const LUAI_MAXCCALLS: usize = 200;
+
+// a main chunk that defines the global 'name' as 'f' and returns name(n)
+fn define_and_call(name: &'static [u8], n: Constant, f: Function) -> Function {
+    Function::main(
+        vec![
+            abx(CLOSURE, 0, 0),
+            abc(SETTABUP, 0, rk(0), 0),
+            abc(GETTABUP, 0, 0, rk(0)),
+            abx(LOADK, 1, 1),
+            abc(CALL, 0, 2, 2),
+            abc(RETURN, 0, 2, 0),
+        ],
+        vec![Constant::Str(name), n],
+    )
+    .with_protos(vec![f])
+}
+
+// function sum(n) if n == 0 then return 0 end return n + sum(n - 1) end
+fn sum(n: i64, registers: u8) -> Function {
+    let mut sum = Function::new(
+        1,
+        vec![
+            abc(EQ, 0, 0, rk(0)),
+            asbx(JMP, 0, 2),
+            abx(LOADK, 1, 0),
+            abc(RETURN, 1, 2, 0),
+            abc(GETTABUP, 1, 0, rk(1)),
+            abc(SUB, 2, 0, rk(2)),
+            abc(CALL, 1, 2, 2),
+            abc(ADD, 1, 0, 1),
+            abc(RETURN, 1, 2, 0),
+        ],
+        vec![Constant::Int(0), Constant::Str(b"sum"), Constant::Int(1)],
+        vec![(false, 0)],
+    );
+    sum.registers = registers;
+    define_and_call(b"sum", Constant::Int(n), sum)
+}
+
+// function loop(n) if n == 0 then return "done" end return loop(n - 1) end
+fn tail_loop(n: i64) -> Function {
+    let f = Function::new(
+        1,
+        vec![
+            abc(EQ, 0, 0, rk(0)),
+            asbx(JMP, 0, 2),
+            abx(LOADK, 1, 3),
+            abc(RETURN, 1, 2, 0),
+            abc(GETTABUP, 1, 0, rk(1)),
+            abc(SUB, 2, 0, rk(2)),
+            abc(TAILCALL, 1, 2, 0),
+            abc(RETURN, 1, 0, 0),
+        ],
+        vec![Constant::Int(0), Constant::Str(b"loop"), Constant::Int(1), Constant::Str(b"done")],
+        vec![(false, 0)],
+    );
+    define_and_call(b"loop", Constant::Int(n), f)
+}
+
+#[test]
+fn lua_recursion_is_not_limited_by_rust_calls() {
+    let mut ls = new_state();
+    run(&mut ls, &sum(30_000, 3), 1).unwrap();
+    assert_eq!(ls.to_integer(-1), 30_000 * 30_001 / 2);
+}
+
+#[test]
+fn tail_calls_reuse_the_frame() {
+    let mut ls = new_state();
+    run(&mut ls, &tail_loop(1_000_000), 1).unwrap();
+    assert_eq!(ls.to_string(-1), "done");
+}
+
+#[test]
+fn infinite_recursion_overflows_the_stack() {
+    let mut ls = new_state();
+    // 100 registers a frame, the limit is reached at 10000 calls
+    ls.load(sum(i64::MAX, 100).dump(), "test", "b");
+    assert_eq!(ls.pcall(0, 1, 0), LUA_ERRRUN);
+    let msg = ls.to_string(-1);
+    assert!(msg.ends_with("stack overflow"), "{}", msg);
+    assert!(!msg.contains("C stack"), "{}", msg);
+    ls.pop(1);
+
+    // the frames are gone, the whole stack is available again
+    run(&mut ls, &sum(5_000, 100), 1).unwrap();
+    assert_eq!(ls.to_integer(-1), 5_000 * 5_001 / 2);
+}
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// calls the global 'f' from Rust
+fn reenter(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    DEPTH.with(|d| d.set(d.get() + 1));
+    ls.get_global("f")?;
+    ls.call(0, 0)?;
+    Ok(0)
+}
+
+#[test]
+fn calls_from_rust_are_limited() {
+    // function f() reenter() end
+    let f = Function::new(
+        0,
+        vec![abc(GETTABUP, 0, 0, rk(0)), abc(CALL, 0, 1, 1), abc(RETURN, 0, 1, 0)],
+        vec![Constant::Str(b"reenter")],
+        vec![(false, 0)],
+    );
+    let main = define_and_call(b"f", Constant::Nil, f);
+
+    let mut ls = new_state();
+    ls.register("reenter", reenter).unwrap();
+    ls.load(main.dump(), "test", "b");
+    assert_eq!(ls.pcall(0, 0, 0), LUA_ERRRUN);
+    let msg = ls.to_string(-1);
+    assert!(msg.ends_with("C stack overflow"), "{}", msg);
+    let depth = DEPTH.with(|d| d.get());
+    assert!((190..=LUAI_MAXCCALLS).contains(&depth), "{}", depth);
+    ls.pop(1);
+
+    // the count of nested calls went back down with the error
+    run(&mut ls, &tail_loop(10), 1).unwrap();
+    assert_eq!(ls.to_string(-1), "done");
+}
+
+#[test]
+fn traceback_marks_tail_calls() {
+    // function g() local t = debug.traceback() return t end
+    let g = Function::new(
+        0,
+        vec![
+            abc(GETTABUP, 0, 0, rk(0)),
+            abc(GETTABLE, 0, 0, rk(1)),
+            abc(CALL, 0, 1, 2),
+            abc(RETURN, 0, 2, 0),
+        ],
+        vec![Constant::Str(b"debug"), Constant::Str(b"traceback")],
+        vec![(false, 0)],
+    );
+    // function h() return g() end
+    let h = Function::new(
+        0,
+        vec![abc(GETTABUP, 0, 0, rk(0)), abc(TAILCALL, 0, 1, 0), abc(RETURN, 0, 0, 0)],
+        vec![Constant::Str(b"g")],
+        vec![(false, 0)],
+    );
+    let main = Function::main(
+        vec![
+            abx(CLOSURE, 0, 0),
+            abc(SETTABUP, 0, rk(0), 0),
+            abx(CLOSURE, 0, 1),
+            abc(SETTABUP, 0, rk(1), 0),
+            abc(GETTABUP, 0, 0, rk(1)),
+            abc(CALL, 0, 1, 2),
+            abc(RETURN, 0, 2, 0),
+        ],
+        vec![Constant::Str(b"g"), Constant::Str(b"h")],
+    )
+    .with_protos(vec![g, h]);
+
+    let mut ls = new_state();
+    run(&mut ls, &main, 1).unwrap();
+    let text = ls.to_string(-1);
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines[0], "stack traceback:", "{}", text);
+    assert!(lines[1].contains("in function 'g'"), "{}", text);
+    assert_eq!(lines[2], "\t(...tail calls...)", "{}", text);
+    assert!(lines[3].contains("in main chunk"), "{}", text);
+}
@@ -0,0 +1,209 @@
+// A small assembler for the tests. There is no compiler in the tree, so
+// the Lua code under test is written as instructions and dumped as a
+// precompiled chunk, which the state loads like any other.
+#![allow(dead_code)] // each test file uses its own part of it
+
+use lua::api::{LuaAPI, LuaResult};
+use lua::state::LuaState;
+use lua::stdlib;
+
+/* opcodes */
+pub const MOVE: u32 = 0;
+pub const LOADK: u32 = 1;
+pub const LOADNIL: u32 = 4;
+pub const GETUPVAL: u32 = 5;
+pub const GETTABUP: u32 = 6;
+pub const GETTABLE: u32 = 7;
+pub const SETTABUP: u32 = 8;
+pub const SETUPVAL: u32 = 9;
+pub const SETTABLE: u32 = 10;
+pub const NEWTABLE: u32 = 11;
+pub const SELF: u32 = 12;
+pub const ADD: u32 = 13;
+pub const SUB: u32 = 14;
+pub const DIV: u32 = 18;
+pub const CONCAT: u32 = 29;
+pub const JMP: u32 = 30;
+pub const EQ: u32 = 31;
+pub const LT: u32 = 32;
+pub const LE: u32 = 33;
+pub const TEST: u32 = 34;
+pub const CALL: u32 = 36;
+pub const TAILCALL: u32 = 37;
+pub const RETURN: u32 = 38;
+pub const FORLOOP: u32 = 39;
+pub const FORPREP: u32 = 40;
+pub const TFORCALL: u32 = 41;
+pub const TFORLOOP: u32 = 42;
+pub const CLOSURE: u32 = 44;
+
+pub fn abc(op: u32, a: u32, b: u32, c: u32) -> u32 {
+    op | a << 6 | c << 14 | b << 23
+}
+
+pub fn abx(op: u32, a: u32, bx: u32) -> u32 {
+    op | a << 6 | bx << 14
+}
+
+pub fn asbx(op: u32, a: u32, sbx: i32) -> u32 {
+    abx(op, a, (sbx + 131071) as u32)
+}
+
+// the RK operand of constant k
+pub fn rk(k: u32) -> u32 {
+    0x100 | k
+}
+
+pub enum Constant {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Num(f64),
+    Str(&'static [u8]),
+}
+
+pub struct Function {
+    pub params: u8,
+    pub vararg: bool,
+    pub registers: u8,
+    pub code: Vec<u32>,
+    pub constants: Vec<Constant>,
+    // (in the stack of the enclosing function, index) of each upvalue
+    pub upvalues: Vec<(bool, u8)>,
+    pub protos: Vec<Function>,
+    // debug info: (name, start pc, end pc) of each local, and upvalue names
+    pub locals: Vec<(&'static [u8], u32, u32)>,
+    pub upvalue_names: Vec<&'static [u8]>,
+}
+
+impl Function {
+    // a main chunk, its only upvalue is _ENV
+    pub fn main(code: Vec<u32>, constants: Vec<Constant>) -> Function {
+        Function {
+            params: 0,
+            vararg: true,
+            registers: 20,
+            code,
+            constants,
+            upvalues: vec![(true, 0)],
+            protos: Vec::new(),
+            locals: Vec::new(),
+            upvalue_names: vec![b"_ENV"],
+        }
+    }
+
+    // a nested function
+    pub fn new(params: u8, code: Vec<u32>, constants: Vec<Constant>, upvalues: Vec<(bool, u8)>) -> Function {
+        Function {
+            params,
+            vararg: false,
+            registers: 20,
+            code,
+            constants,
+            upvalues,
+            protos: Vec::new(),
+            locals: Vec::new(),
+            upvalue_names: Vec::new(),
+        }
+    }
+
+    pub fn with_protos(mut self, protos: Vec<Function>) -> Function {
+        self.protos = protos;
+        self
+    }
+
+    // names the registers from 0 on, each one live from 'start' to 'end'
+    pub fn with_locals(mut self, locals: Vec<(&'static [u8], u32, u32)>) -> Function {
+        self.locals = locals;
+        self
+    }
+
+    pub fn with_upvalue_names(mut self, names: Vec<&'static [u8]>) -> Function {
+        self.upvalue_names = names;
+        self
+    }
+
+    // the function as a precompiled chunk
+    pub fn dump(&self) -> Vec<u8> {
+        let mut out = vec![0x1b, b'L', b'u', b'a', 0x53, 0, 0x19, 0x93, b'\r', b'\n', 0x1a, b'\n'];
+        out.extend_from_slice(&[4, 8, 4, 8, 8]);
+        out.extend_from_slice(&0x5678i64.to_le_bytes());
+        out.extend_from_slice(&370.5f64.to_le_bytes());
+        out.push(self.upvalues.len() as u8);
+        self.dump_proto(&mut out, Some(b"=test"));
+        out
+    }
+
+    fn dump_proto(&self, out: &mut Vec<u8>, source: Option<&[u8]>) {
+        let int = |out: &mut Vec<u8>, n: u32| out.extend_from_slice(&n.to_le_bytes());
+        dump_string(out, source);
+        int(out, 0); // line defined
+        int(out, 0); // last line defined
+        out.extend_from_slice(&[self.params, self.vararg as u8, self.registers]);
+        int(out, self.code.len() as u32);
+        self.code.iter().for_each(|&i| int(out, i));
+        int(out, self.constants.len() as u32);
+        for k in self.constants.iter() {
+            match k {
+                Constant::Nil => out.push(0),
+                Constant::Bool(b) => out.extend_from_slice(&[1, *b as u8]),
+                Constant::Int(i) => {
+                    out.push(0x13);
+                    out.extend_from_slice(&i.to_le_bytes());
+                }
+                Constant::Num(n) => {
+                    out.push(3);
+                    out.extend_from_slice(&n.to_le_bytes());
+                }
+                Constant::Str(s) => {
+                    out.push(4);
+                    dump_string(out, Some(s));
+                }
+            }
+        }
+        int(out, self.upvalues.len() as u32);
+        for &(instack, idx) in self.upvalues.iter() {
+            out.extend_from_slice(&[instack as u8, idx]);
+        }
+        int(out, self.protos.len() as u32);
+        self.protos.iter().for_each(|p| p.dump_proto(out, None));
+        int(out, self.code.len() as u32); // line info: instruction i is on line i + 1
+        (1..=self.code.len() as u32).for_each(|line| int(out, line));
+        int(out, self.locals.len() as u32);
+        for &(name, start, end) in self.locals.iter() {
+            dump_string(out, Some(name));
+            int(out, start);
+            int(out, end);
+        }
+        int(out, self.upvalue_names.len() as u32);
+        self.upvalue_names.iter().for_each(|&name| dump_string(out, Some(name)));
+    }
+}
+
+fn dump_string(out: &mut Vec<u8>, s: Option<&[u8]>) {
+    match s {
+        None => out.push(0),
+        Some(s) if s.len() < 0xfe => {
+            out.push(s.len() as u8 + 1);
+            out.extend_from_slice(s);
+        }
+        Some(s) => {
+            out.push(0xff);
+            out.extend_from_slice(&(s.len() as u64 + 1).to_le_bytes());
+            out.extend_from_slice(s);
+        }
+    }
+}
+
+// a state with the standard libraries
+pub fn new_state() -> LuaState {
+    let mut ls = LuaState::new();
+    stdlib::open_libs(&mut ls).unwrap();
+    ls
+}
+
+// runs the main function, leaving its first 'nresults' results
+pub fn run(ls: &mut LuaState, main: &Function, nresults: isize) -> LuaResult<()> {
+    ls.load(main.dump(), "test", "b");
+    ls.call(0, nresults)
+}
@@ -0,0 +1,134 @@
+// The variables of each frame in the traceback of an uncaught error, with
+// set_dump_locals or the --locals flag of the lua binary. Only the locals
+// live at the current instruction are shown, and the values are cut short.
+mod common;
+
+use common::*;
+use lua::api::LuaAPI;
+use lua::state::LuaState;
+use std::process::Command;
+
+// do local gone = "x" end
+// local nested, big, long = nested, big, long
+// local f; f()
+// local later
+fn failing_chunk() -> Function {
+    Function::main(
+        vec![
+            abx(LOADK, 0, 0),
+            abc(GETTABUP, 0, 0, rk(1)),
+            abc(GETTABUP, 1, 0, rk(2)),
+            abc(GETTABUP, 2, 0, rk(3)),
+            abc(LOADNIL, 3, 1, 0),
+            abc(CALL, 3, 1, 1),
+            abc(RETURN, 0, 1, 0),
+        ],
+        vec![Constant::Str(b"x"), Constant::Str(b"nested"), Constant::Str(b"big"), Constant::Str(b"long")],
+    )
+    .with_locals(vec![
+        (b"gone", 1, 2),
+        (b"nested", 2, 7),
+        (b"big", 3, 7),
+        (b"long", 4, 7),
+        (b"f", 5, 7),
+        (b"later", 6, 7),
+    ])
+}
+
+// nested = {b = {c = {}}, ["a b"] = 1}, big = {1, ..., 10, x = true},
+// long = 50 'a's
+fn set_globals(ls: &mut LuaState) {
+    ls.new_table();
+    ls.new_table();
+    ls.new_table();
+    ls.set_field(-2, "c").unwrap();
+    ls.set_field(-2, "b").unwrap();
+    ls.push_integer(1);
+    ls.set_field(-2, "a b").unwrap();
+    ls.set_global("nested").unwrap();
+
+    ls.new_table();
+    for i in 1..=10 {
+        ls.push_integer(i);
+        ls.set_i(-2, i).unwrap();
+    }
+    ls.push_boolean(true);
+    ls.set_field(-2, "x").unwrap();
+    ls.set_global("big").unwrap();
+
+    ls.push_string("a".repeat(50));
+    ls.set_global("long").unwrap();
+}
+
+const LOCALS: [&str; 4] = [
+    "\t\tlocal nested = {[\"a b\"] = 1, b = {c = {...}}}",
+    "\t\tlocal big = {1, 2, 3, 4, 5, 6, 7, 8, ...}",
+    "\t\tlocal long = \"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\"...",
+    "\t\tlocal f = nil",
+];
+
+// the lines of the traceback that show locals
+fn locals_in(traceback: &str) -> Vec<&str> {
+    traceback.lines().filter(|line| line.starts_with("\t\tlocal ")).collect()
+}
+
+#[test]
+fn live_locals_are_dumped_cut_short() {
+    let mut ls = new_state();
+    set_globals(&mut ls);
+    ls.set_dump_locals(true);
+    let err = run(&mut ls, &failing_chunk(), 0).unwrap_err();
+    assert_eq!(err.to_string(), "test:6: attempt to call a nil value (local 'f')");
+    let traceback = err.traceback().unwrap();
+    assert_eq!(locals_in(&traceback), LOCALS, "{}", traceback);
+    assert!(traceback.contains("\t\tupvalue _ENV = {"), "{}", traceback);
+}
+
+#[test]
+fn locals_are_dumped_only_when_asked() {
+    let mut ls = new_state();
+    set_globals(&mut ls);
+    let err = run(&mut ls, &failing_chunk(), 0).unwrap_err();
+    let traceback = err.traceback().unwrap();
+    assert!(locals_in(&traceback).is_empty(), "{}", traceback);
+    assert!(!traceback.contains("upvalue _ENV"), "{}", traceback);
+}
+
+// the binary has no way to set globals, the chunk makes its own values
+#[test]
+fn the_binary_dumps_locals_with_the_flag() {
+    // local s = <50 'b's>; local t = {}; t.u = {v = {}}; s()
+    let main = Function::main(
+        vec![
+            abx(LOADK, 0, 0),
+            abc(NEWTABLE, 1, 0, 0),
+            abc(NEWTABLE, 2, 0, 0),
+            abc(NEWTABLE, 3, 0, 0),
+            abc(SETTABLE, 2, rk(1), 3),
+            abc(SETTABLE, 1, rk(2), 2),
+            abc(CALL, 0, 1, 1),
+            abc(RETURN, 0, 1, 0),
+        ],
+        vec![Constant::Str(&[b'b'; 50]), Constant::Str(b"v"), Constant::Str(b"u")],
+    )
+    .with_locals(vec![(b"s", 1, 8), (b"t", 2, 8)]);
+    let path = std::env::temp_dir().join(format!("dump_locals_{}.luac", std::process::id()));
+    std::fs::write(&path, main.dump()).unwrap();
+
+    let lua = |flags: &[&str]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_lua")).args(flags).arg(&path).output().unwrap();
+        assert!(!out.status.success());
+        String::from_utf8(out.stderr).unwrap()
+    };
+    let with = lua(&["--locals"]);
+    let without = lua(&[]);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(with.starts_with("lua: test:7: attempt to call a string value (local 's')"), "{}", with);
+    let expected = [
+        "\t\tlocal s = \"bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\"...",
+        "\t\tlocal t = {u = {v = {...}}}",
+    ];
+    assert_eq!(locals_in(&with), expected, "{}", with);
+    assert!(locals_in(&without).is_empty(), "{}", without);
+}
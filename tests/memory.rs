@@ -0,0 +1,88 @@
+// Memory use of long-running loops, measured by the allocator itself so
+// that leaks the collector does not know about show up too.
+mod common;
+
+use common::*;
+use lua::api::consts::*;
+use lua::api::LuaAPI;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// counts the bytes in use and their peak
+struct Counter;
+
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counter {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = unsafe { System.alloc(layout) };
+        if !p.is_null() {
+            let n = IN_USE.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(n, Ordering::SeqCst);
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, p: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(p, layout) };
+        IN_USE.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counter = Counter;
+
+// for i = 1, n do
+//   local t = {}
+//   t.self = t
+//   t.f = function () return t end
+// end
+fn cyclic_loop(n: i64) -> Function {
+    let f = Function::new(0, vec![abc(GETUPVAL, 0, 0, 0), abc(RETURN, 0, 2, 0)], vec![], vec![(true, 4)]);
+    Function::main(
+        vec![
+            abx(LOADK, 0, 0),
+            abx(LOADK, 1, 1),
+            abx(LOADK, 2, 0),
+            asbx(FORPREP, 0, 4),
+            abc(NEWTABLE, 4, 0, 0),
+            abc(SETTABLE, 4, rk(2), 4),
+            abx(CLOSURE, 5, 0),
+            abc(SETTABLE, 4, rk(3), 5),
+            asbx(FORLOOP, 0, -5),
+            abc(RETURN, 0, 1, 0),
+        ],
+        vec![Constant::Int(1), Constant::Int(n), Constant::Str(b"self"), Constant::Str(b"f")],
+    )
+    .with_protos(vec![f])
+}
+
+// runs the loop in a new state, returns the peak of the bytes in use above
+// what was in use before, the count of the collector after the loop and
+// the bytes still in use once the state is gone
+fn measure(n: i64) -> (usize, usize, isize) {
+    let main = cyclic_loop(n);
+    let base = IN_USE.load(Ordering::SeqCst);
+    PEAK.store(base, Ordering::SeqCst);
+    let mut ls = new_state();
+    run(&mut ls, &main, 0).unwrap();
+    let peak = PEAK.load(Ordering::SeqCst) - base;
+    let count = ls.gc(LUA_GCCOUNT, 0).unwrap() as usize;
+    drop(ls);
+    let left = IN_USE.load(Ordering::SeqCst) as isize - base as isize;
+    (peak, count, left)
+}
+
+#[test]
+fn memory_stays_flat_in_a_table_creating_loop() {
+    let (small_peak, _, _) = measure(10_000);
+    let (peak, count, left) = measure(200_000);
+
+    // without collecting the cycles this would take tens of megabytes
+    assert!(peak < 4 << 20, "peak of {} bytes", peak);
+    assert!(peak < 2 * small_peak + (256 << 10), "peak of {} bytes, {} for 10000", peak, small_peak);
+    assert!(count < 1024, "collectgarbage('count') is {} KB", count);
+    // nothing is kept per object once the state is closed
+    assert!(left.abs() < 16 << 10, "{} bytes left behind", left);
+}
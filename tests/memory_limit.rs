@@ -0,0 +1,99 @@
+// The memory limit and the memory callback of a state. Allocations are
+// checked against the limit before they are made, so the memory in use
+// never goes over it and the error leaves the state usable.
+mod common;
+
+use common::*;
+use lua::api::consts::*;
+use lua::api::{LuaAPI, LuaResult};
+use lua::state::LuaState;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const LIMIT: usize = 512 << 10;
+
+// the memory in use, in bytes
+fn in_use(ls: &mut LuaState) -> usize {
+    ((ls.gc(LUA_GCCOUNT, 0).unwrap() as usize) << 10) + ls.gc(LUA_GCCOUNTB, 0).unwrap() as usize
+}
+
+// t[i] = i for ever
+fn grow_table(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    ls.new_table();
+    for i in 1.. {
+        ls.push_integer(i);
+        ls.set_i(-2, i)?;
+    }
+    unreachable!()
+}
+
+// s = s .. s for ever
+fn grow_string(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    ls.push_bytes(b"0123456789abcdef");
+    loop {
+        ls.push_value(-1);
+        ls.concat(2)?;
+    }
+}
+
+// t[i] = <a new string> for ever
+fn new_strings(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    ls.new_table();
+    for i in 1.. {
+        ls.push_string(format!("{:0>32}", i));
+        ls.set_i(-2, i)?;
+    }
+    unreachable!()
+}
+
+// runs 'f' under the limit, it must fail for lack of memory without the
+// memory in use going over the limit
+fn fails_under_the_limit(ls: &mut LuaState, f: fn(&mut dyn LuaAPI) -> LuaResult<usize>) {
+    ls.push_rust_function(f);
+    assert_eq!(ls.pcall(0, 0, 0), LUA_ERRMEM);
+    assert!(in_use(ls) <= LIMIT, "{} bytes in use", in_use(ls));
+    assert_eq!(ls.to_string(-1), "not enough memory");
+    ls.pop(1);
+}
+
+#[test]
+fn allocations_stop_at_the_limit() {
+    let mut ls = new_state();
+    ls.gc(LUA_GCCOLLECT, 0).unwrap();
+    ls.set_memory_limit(Some(LIMIT));
+    fails_under_the_limit(&mut ls, grow_table);
+    fails_under_the_limit(&mut ls, grow_string);
+    fails_under_the_limit(&mut ls, new_strings);
+
+    // what the failed calls allocated is garbage, the state goes on
+    ls.gc(LUA_GCCOLLECT, 0).unwrap();
+    assert!(in_use(&mut ls) < LIMIT / 2, "{} bytes in use", in_use(&mut ls));
+    run(&mut ls, &Function::main(vec![abx(LOADK, 0, 0), abc(RETURN, 0, 2, 0)], vec![Constant::Int(42)]), 1).unwrap();
+    assert_eq!(ls.to_integer(-1), 42);
+    ls.pop(1);
+}
+
+#[test]
+fn the_callback_sees_the_thresholds_crossed() {
+    let mut ls = new_state();
+    ls.gc(LUA_GCSTOP, 0).unwrap();
+    ls.gc(LUA_GCCOLLECT, 0).unwrap();
+    let base = in_use(&mut ls);
+    let (low, high) = (base + (100 << 10), base + (200 << 10));
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let log = calls.clone();
+    ls.set_memory_callback(vec![low, high], move |threshold, bytes| {
+        log.borrow_mut().push((threshold, bytes >= threshold));
+    });
+
+    // 300KB of strings in a table, then let go
+    ls.new_table();
+    for i in 1..=300 {
+        ls.push_bytes(&[i as u8; 1024]);
+        ls.set_i(-2, i).unwrap();
+    }
+    assert_eq!(*calls.borrow(), [(low, true), (high, true)]);
+    ls.pop(1);
+    ls.gc(LUA_GCCOLLECT, 0).unwrap();
+    assert_eq!(*calls.borrow(), [(low, true), (high, true), (low, false), (high, false)]);
+}
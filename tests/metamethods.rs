@@ -0,0 +1,272 @@
+// Metamethods of arithmetic, comparison, length and concatenation, as the
+// API and the interpreter dispatch them. The metamethods are Rust functions
+// that report how they were called.
+mod common;
+
+use common::*;
+use lua::api::consts::*;
+use lua::api::{LuaAPI, LuaResult, RustFn};
+use lua::state::LuaState;
+
+// returns "<type of arg 1> <type of arg 2>"
+fn describe(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    let names = format!("{} {}", ls.type_name(ls.type_id(1)), ls.type_name(ls.type_id(2)));
+    ls.push_string(names);
+    Ok(1)
+}
+
+// a.v < b.v
+fn less_than(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    ls.get_field(1, "v")?;
+    ls.get_field(2, "v")?;
+    let lt = ls.to_integer(-2) < ls.to_integer(-1);
+    ls.push_boolean(lt);
+    Ok(1)
+}
+
+fn always(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    ls.push_boolean(true);
+    Ok(1)
+}
+
+fn never(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    ls.push_boolean(false);
+    Ok(1)
+}
+
+fn forty_two(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    ls.push_integer(42);
+    Ok(1)
+}
+
+// pushes a table {v = v} whose metatable has the given metamethods
+fn push_object(ls: &mut LuaState, v: i64, metamethods: &[(&str, RustFn)]) {
+    ls.new_table();
+    ls.push_integer(v);
+    ls.set_field(-2, "v").unwrap();
+    ls.new_table();
+    for &(event, f) in metamethods {
+        ls.push_rust_function(f);
+        ls.set_field(-2, event).unwrap();
+    }
+    ls.set_metatable(-2).unwrap();
+}
+
+#[test]
+fn arithmetic_looks_at_both_operands() {
+    let mut ls = new_state();
+    // 1 + t and t + 1 both find t's __add, called with the operands in order
+    ls.push_integer(1);
+    push_object(&mut ls, 0, &[("__add", describe)]);
+    ls.arith(LUA_OPADD).unwrap();
+    assert_eq!(ls.to_string(-1), "number table");
+    push_object(&mut ls, 0, &[("__add", describe)]);
+    ls.push_string("1".to_string());
+    ls.arith(LUA_OPADD).unwrap();
+    assert_eq!(ls.to_string(-1), "table string");
+
+    // the first operand's metamethod comes first
+    push_object(&mut ls, 0, &[("__shl", forty_two)]);
+    push_object(&mut ls, 0, &[("__shl", describe)]);
+    ls.arith(LUA_OPSHL).unwrap();
+    assert_eq!(ls.to_integer(-1), 42);
+
+    // without one the error blames the operand that is not a number
+    ls.push_integer(1);
+    push_object(&mut ls, 0, &[]);
+    let err = ls.arith(LUA_OPMUL).unwrap_err();
+    assert_eq!(err.to_string(), "attempt to perform arithmetic on a table value");
+    ls.push_number(1.5);
+    ls.push_integer(1);
+    let err = ls.arith(LUA_OPBOR).unwrap_err();
+    assert_eq!(err.to_string(), "number has no integer representation");
+}
+
+#[test]
+fn unary_minus_and_length() {
+    let mut ls = new_state();
+    // __unm gets the operand twice
+    push_object(&mut ls, 0, &[("__unm", describe)]);
+    ls.arith(LUA_OPUNM).unwrap();
+    assert_eq!(ls.to_string(-1), "table table");
+    ls.pop(1);
+
+    // __len replaces the length of a table, strings have their own
+    push_object(&mut ls, 0, &[("__len", describe)]);
+    ls.len(-1).unwrap();
+    assert_eq!(ls.to_string(-1), "table table");
+    ls.pop(2);
+    push_object(&mut ls, 0, &[("__len", forty_two)]);
+    ls.len(-1).unwrap();
+    assert_eq!(ls.to_integer(-1), 42);
+    ls.pop(2);
+    push_object(&mut ls, 0, &[]);
+    ls.push_integer(7);
+    ls.set_i(-2, 1).unwrap();
+    ls.len(-1).unwrap();
+    assert_eq!(ls.to_integer(-1), 1);
+    ls.push_string("four".to_string());
+    ls.len(-1).unwrap();
+    assert_eq!(ls.to_integer(-1), 4);
+    ls.push_boolean(true);
+    assert_eq!(ls.len(-1).unwrap_err().to_string(), "attempt to get length of a boolean value");
+}
+
+#[test]
+fn less_equal_falls_back_to_not_less_than() {
+    let mut ls = new_state();
+    push_object(&mut ls, 1, &[("__lt", less_than)]); // 1
+    push_object(&mut ls, 2, &[("__lt", less_than)]); // 2
+    assert!(ls.compare(1, 2, LUA_OPLT).unwrap());
+    assert!(!ls.compare(2, 1, LUA_OPLT).unwrap());
+    // a <= b is not (b < a)
+    assert!(ls.compare(1, 2, LUA_OPLE).unwrap());
+    assert!(!ls.compare(2, 1, LUA_OPLE).unwrap());
+    assert!(ls.compare(1, 1, LUA_OPLE).unwrap());
+
+    // __le is used first when there is one
+    push_object(&mut ls, 1, &[("__le", never), ("__lt", less_than)]); // 3
+    assert!(!ls.compare(3, 2, LUA_OPLE).unwrap());
+    assert!(ls.compare(3, 2, LUA_OPLT).unwrap());
+
+    push_object(&mut ls, 1, &[]); // 4
+    let err = ls.compare(4, 4, LUA_OPLE).unwrap_err();
+    assert_eq!(err.to_string(), "attempt to compare two table values");
+    ls.push_integer(1); // 5
+    let err = ls.compare(4, 5, LUA_OPLT).unwrap_err();
+    assert_eq!(err.to_string(), "attempt to compare table with number");
+}
+
+#[test]
+fn equality_asks_only_for_two_tables() {
+    let mut ls = new_state();
+    push_object(&mut ls, 1, &[("__eq", always)]); // 1
+    push_object(&mut ls, 2, &[]); // 2
+    ls.push_integer(1); // 3
+    ls.push_string("x".to_string()); // 4
+    // either table's __eq applies
+    assert!(ls.compare(1, 2, LUA_OPEQ).unwrap());
+    assert!(ls.compare(2, 1, LUA_OPEQ).unwrap());
+    // not with other types, even if __eq would say yes
+    assert!(!ls.compare(1, 3, LUA_OPEQ).unwrap());
+    assert!(!ls.compare(4, 1, LUA_OPEQ).unwrap());
+    // and not for the same table, which is always equal to itself
+    push_object(&mut ls, 1, &[("__eq", never)]); // 5
+    assert!(ls.compare(5, 5, LUA_OPEQ).unwrap());
+    assert!(!ls.compare(5, 2, LUA_OPEQ).unwrap());
+    // without __eq, two tables are different
+    push_object(&mut ls, 1, &[]); // 6
+    assert!(!ls.compare(2, 6, LUA_OPEQ).unwrap());
+}
+
+#[test]
+fn concat_calls_the_metamethod_of_either_operand() {
+    let mut ls = new_state();
+    ls.push_string("a".to_string());
+    push_object(&mut ls, 0, &[("__concat", describe)]);
+    ls.concat(2).unwrap();
+    assert_eq!(ls.to_string(-1), "string table");
+
+    // from the right: "x" .. 1 .. t is "x" .. (1 .. t)
+    ls.push_string("x".to_string());
+    ls.push_integer(1);
+    push_object(&mut ls, 0, &[("__concat", describe)]);
+    ls.concat(3).unwrap();
+    assert_eq!(ls.to_string(-1), "xnumber table");
+}
+
+// local s, t = "s", {}; return <the registers in 'order'> concatenated
+fn concat_error(order: [u32; 3]) -> String {
+    let mut code = vec![abx(LOADK, 0, 0), abc(NEWTABLE, 1, 0, 0)];
+    for (i, &reg) in order.iter().enumerate() {
+        code.push(abc(MOVE, 2 + i as u32, reg, 0));
+    }
+    code.push(abc(CONCAT, 5, 2, 4));
+    code.push(abc(RETURN, 5, 2, 0));
+    let main = Function::main(code, vec![Constant::Str(b"s")]).with_locals(vec![(b"s", 2, 7), (b"t", 2, 7)]);
+    let mut ls = new_state();
+    ls.load(main.dump(), "test", "b");
+    assert_eq!(ls.pcall(0, 1, 0), LUA_ERRRUN);
+    ls.to_string(-1)
+}
+
+#[test]
+fn concat_errors_name_the_faulty_operand() {
+    let expected = "test:6: attempt to concatenate a table value (local 't')";
+    assert_eq!(concat_error([0, 0, 1]), expected); // s .. s .. t
+    assert_eq!(concat_error([0, 1, 0]), expected); // s .. t .. s
+    assert_eq!(concat_error([1, 0, 0]), expected); // t .. s .. s
+}
+
+fn returns_a_table(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    ls.new_table();
+    Ok(1)
+}
+
+// sets field 'k' of the metatable of the value at 'idx' to the value on top
+fn set_metafield(ls: &mut LuaState, idx: isize, k: &str) {
+    ls.get_metatable(idx);
+    ls.insert(-2).unwrap();
+    ls.set_field(-2, k).unwrap();
+    ls.pop(1);
+}
+
+#[test]
+fn display_strings() {
+    let mut ls = new_state();
+    ls.push_nil();
+    assert_eq!(ls.to_display_string(-1).unwrap(), "nil");
+    ls.push_boolean(false);
+    assert_eq!(ls.to_display_string(-1).unwrap(), "false");
+    ls.push_number(3.0);
+    assert_eq!(ls.to_display_string(-1).unwrap(), "3.0");
+    ls.push_integer(-7);
+    assert_eq!(ls.to_display_string(-1).unwrap(), "-7");
+    ls.set_top(0).unwrap();
+
+    // tables and functions show their address; the result is also pushed
+    ls.new_table();
+    let s = ls.to_display_string(1).unwrap();
+    assert_eq!(s, format!("table: {:#x}", ls.to_pointer(1)));
+    assert!(s.starts_with("table: 0x") && ls.to_pointer(1) != 0, "{}", s);
+    assert_eq!(ls.get_top(), 2);
+    assert_eq!(ls.to_string(2), s);
+    ls.push_rust_function(describe);
+    assert_eq!(ls.to_display_string(3).unwrap(), format!("function: {:#x}", ls.to_pointer(3)));
+
+    // distinct tables, distinct strings
+    ls.new_table();
+    assert_ne!(ls.to_display_string(1).unwrap(), ls.to_display_string(5).unwrap());
+}
+
+#[test]
+fn display_strings_use_tostring_and_name() {
+    let mut ls = new_state();
+    push_object(&mut ls, 0, &[("__tostring", describe)]);
+    assert_eq!(ls.to_display_string(1).unwrap(), "table no value");
+    ls.set_top(0).unwrap();
+    push_object(&mut ls, 0, &[("__tostring", forty_two)]);
+    assert_eq!(ls.to_display_string(1).unwrap(), "42");
+    ls.set_top(0).unwrap();
+    push_object(&mut ls, 0, &[("__tostring", returns_a_table)]);
+    let err = ls.to_display_string(1).unwrap_err();
+    assert_eq!(err.to_string(), "'__tostring' must return a string");
+    ls.set_top(0).unwrap();
+
+    // a string __name replaces the type name
+    push_object(&mut ls, 0, &[]);
+    let address = ls.to_pointer(1);
+    ls.push_string("Point".to_string());
+    set_metafield(&mut ls, 1, "__name");
+    assert_eq!(ls.to_display_string(1).unwrap(), format!("Point: {:#x}", address));
+    ls.push_integer(1);
+    set_metafield(&mut ls, 1, "__name");
+    assert_eq!(ls.to_display_string(1).unwrap(), format!("table: {:#x}", address));
+
+    // and __tostring comes before __name
+    ls.push_string("Point".to_string());
+    set_metafield(&mut ls, 1, "__name");
+    ls.push_rust_function(forty_two);
+    set_metafield(&mut ls, 1, "__tostring");
+    assert_eq!(ls.to_display_string(1).unwrap(), "42");
+}
@@ -0,0 +1,118 @@
+// Variable names in error messages. The interpreter finds them from the
+// debug info of the function (local and upvalue names) and by going back
+// through the code to the instruction that loaded the faulty value.
+mod common;
+
+use common::*;
+use lua::api::consts::*;
+use lua::api::LuaAPI;
+
+// the error message of running 'main'
+fn error_of(main: Function) -> String {
+    let mut ls = new_state();
+    ls.load(main.dump(), "test", "b");
+    assert_eq!(ls.pcall(0, 0, 0), LUA_ERRRUN);
+    ls.to_string(-1)
+}
+
+#[test]
+fn globals() {
+    // x()
+    let main = Function::main(
+        vec![abc(GETTABUP, 0, 0, rk(0)), abc(CALL, 0, 1, 1), abc(RETURN, 0, 1, 0)],
+        vec![Constant::Str(b"x")],
+    );
+    assert_eq!(error_of(main), "test:2: attempt to call a nil value (global 'x')");
+
+    // return x + 1
+    let main = Function::main(
+        vec![abc(GETTABUP, 0, 0, rk(0)), abc(ADD, 0, 0, rk(1)), abc(RETURN, 0, 2, 0)],
+        vec![Constant::Str(b"x"), Constant::Int(1)],
+    );
+    assert_eq!(error_of(main), "test:2: attempt to perform arithmetic on a nil value (global 'x')");
+}
+
+#[test]
+fn locals() {
+    // local x; x()
+    let main = Function::main(vec![abc(LOADNIL, 0, 0, 0), abc(CALL, 0, 1, 1), abc(RETURN, 0, 1, 0)], vec![])
+        .with_locals(vec![(b"x", 1, 3)]);
+    assert_eq!(error_of(main), "test:2: attempt to call a nil value (local 'x')");
+
+    // local s, x = "a"; return s .. x
+    let main = Function::main(
+        vec![
+            abx(LOADK, 0, 0),
+            abc(LOADNIL, 1, 0, 0),
+            abc(MOVE, 2, 0, 0),
+            abc(MOVE, 3, 1, 0),
+            abc(CONCAT, 2, 2, 3),
+            abc(RETURN, 2, 2, 0),
+        ],
+        vec![Constant::Str(b"a")],
+    )
+    .with_locals(vec![(b"s", 2, 6), (b"x", 2, 6)]);
+    assert_eq!(error_of(main), "test:5: attempt to concatenate a nil value (local 'x')");
+
+    // a register is named only while its local is live
+    let main = Function::main(vec![abc(LOADNIL, 0, 0, 0), abc(CALL, 0, 1, 1), abc(RETURN, 0, 1, 0)], vec![])
+        .with_locals(vec![(b"x", 2, 3)]);
+    assert_eq!(error_of(main), "test:2: attempt to call a nil value");
+}
+
+#[test]
+fn methods_and_fields() {
+    // local t = {}; t:foo()
+    let main = Function::main(
+        vec![abc(NEWTABLE, 0, 0, 0), abc(SELF, 1, 0, rk(0)), abc(CALL, 1, 2, 1), abc(RETURN, 0, 1, 0)],
+        vec![Constant::Str(b"foo")],
+    )
+    .with_locals(vec![(b"t", 1, 4)]);
+    assert_eq!(error_of(main), "test:3: attempt to call a nil value (method 'foo')");
+
+    // local t = {}; t.f()
+    let main = Function::main(
+        vec![abc(NEWTABLE, 0, 0, 0), abc(GETTABLE, 1, 0, rk(0)), abc(CALL, 1, 1, 1), abc(RETURN, 0, 1, 0)],
+        vec![Constant::Str(b"f")],
+    )
+    .with_locals(vec![(b"t", 1, 4)]);
+    assert_eq!(error_of(main), "test:3: attempt to call a nil value (field 'f')");
+
+    // local t = {}; return t.f.g
+    let main = Function::main(
+        vec![
+            abc(NEWTABLE, 0, 0, 0),
+            abc(GETTABLE, 1, 0, rk(0)),
+            abc(GETTABLE, 1, 1, rk(1)),
+            abc(RETURN, 1, 2, 0),
+        ],
+        vec![Constant::Str(b"f"), Constant::Str(b"g")],
+    )
+    .with_locals(vec![(b"t", 1, 4)]);
+    assert_eq!(error_of(main), "test:3: attempt to index a nil value (field 'f')");
+}
+
+#[test]
+fn upvalues() {
+    // local y; (function () y() end)()
+    let f = Function::new(0, vec![abc(GETUPVAL, 0, 0, 0), abc(CALL, 0, 1, 1), abc(RETURN, 0, 1, 0)], vec![], vec![(true, 0)])
+        .with_upvalue_names(vec![b"y"]);
+    let main = Function::main(
+        vec![abc(LOADNIL, 0, 0, 0), abx(CLOSURE, 1, 0), abc(CALL, 1, 1, 1), abc(RETURN, 0, 1, 0)],
+        vec![],
+    )
+    .with_locals(vec![(b"y", 1, 4)])
+    .with_protos(vec![f]);
+    assert_eq!(error_of(main), "test:2: attempt to call a nil value (upvalue 'y')");
+
+    // local y; (function () return y.z end)()
+    let f = Function::new(0, vec![abc(GETTABUP, 0, 0, rk(0)), abc(RETURN, 0, 2, 0)], vec![Constant::Str(b"z")], vec![(true, 0)])
+        .with_upvalue_names(vec![b"y"]);
+    let main = Function::main(
+        vec![abc(LOADNIL, 0, 0, 0), abx(CLOSURE, 1, 0), abc(CALL, 1, 1, 1), abc(RETURN, 0, 1, 0)],
+        vec![],
+    )
+    .with_locals(vec![(b"y", 1, 4)])
+    .with_protos(vec![f]);
+    assert_eq!(error_of(main), "test:1: attempt to index a nil value (upvalue 'y')");
+}
@@ -0,0 +1,103 @@
+// Panics in Rust functions become Lua errors: the frames they leave behind
+// are popped with their accounting, and once the state asks for it the
+// panic hook stays quiet about them. One test only, the panic hook is
+// global to the process.
+mod common;
+
+use common::*;
+use lua::api::consts::*;
+use lua::api::{LuaAPI, LuaResult};
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static REPORTED: AtomicUsize = AtomicUsize::new(0);
+
+// calls the global 'f', a Lua function that panics inside the interpreter
+fn outer(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    ls.get_global("f")?;
+    ls.call(0, 0)?;
+    Ok(0)
+}
+
+#[test]
+fn caught_panics_unwind_frames_quietly() {
+    panic::set_hook(Box::new(|_| {
+        REPORTED.fetch_add(1, Ordering::SeqCst);
+    }));
+
+    // function f() local x = <a constant that is not there> end
+    let f = Function::new(0, vec![abx(LOADK, 0, 5), abc(RETURN, 0, 1, 0)], vec![], vec![]);
+    let define_f = Function::main(
+        vec![abx(CLOSURE, 0, 0), abc(SETTABUP, 0, rk(0), 0), abc(RETURN, 0, 1, 0)],
+        vec![Constant::Str(b"f")],
+    )
+    .with_protos(vec![f]);
+
+    let mut ls = new_state();
+    ls.register("outer", outer).unwrap();
+    run(&mut ls, &define_f, 0).unwrap();
+
+    // by default the hook hears about caught panics too
+    ls.get_global("outer").unwrap();
+    assert_eq!(ls.pcall(0, 0, 0), LUA_ERRRUN);
+    ls.pop(1);
+    assert_eq!(REPORTED.load(Ordering::SeqCst), 1);
+    ls.set_quiet_panics(true);
+
+    // each panic leaves the frame of 'f' behind, 20 slots; leaked, they
+    // would use up the stack
+    for _ in 0..60_000 {
+        ls.get_global("outer").unwrap();
+        assert_eq!(ls.pcall(0, 0, 0), LUA_ERRRUN);
+        let msg = ls.to_string(-1);
+        assert!(msg.starts_with("Rust function panicked: index out of bounds"), "{}", msg);
+        ls.pop(1);
+    }
+    assert_eq!(REPORTED.load(Ordering::SeqCst), 1);
+
+    // function sum(n) ... end, 5000 calls of 100 slots
+    let sum = Function::new(
+        1,
+        vec![
+            abc(EQ, 0, 0, rk(0)),
+            asbx(JMP, 0, 2),
+            abx(LOADK, 1, 0),
+            abc(RETURN, 1, 2, 0),
+            abc(GETTABUP, 1, 0, rk(1)),
+            abc(SUB, 2, 0, rk(2)),
+            abc(CALL, 1, 2, 2),
+            abc(ADD, 1, 0, 1),
+            abc(RETURN, 1, 2, 0),
+        ],
+        vec![Constant::Int(0), Constant::Str(b"sum"), Constant::Int(1)],
+        vec![(false, 0)],
+    );
+    let sum = Function { registers: 100, ..sum };
+    let main = Function::main(
+        vec![
+            abx(CLOSURE, 0, 0),
+            abc(SETTABUP, 0, rk(0), 0),
+            abc(GETTABUP, 0, 0, rk(0)),
+            abx(LOADK, 1, 1),
+            abc(CALL, 0, 2, 2),
+            abc(RETURN, 0, 2, 0),
+        ],
+        vec![Constant::Str(b"sum"), Constant::Int(5_000)],
+    )
+    .with_protos(vec![sum]);
+    run(&mut ls, &main, 1).unwrap();
+    assert_eq!(ls.to_integer(-1), 5_000 * 5_001 / 2);
+
+    // panics the state does not catch still reach the hook
+    assert!(panic::catch_unwind(|| panic!("not caught")).is_err());
+    assert_eq!(REPORTED.load(Ordering::SeqCst), 2);
+
+    // and so do the panics of states that didn't ask for quiet
+    let mut other = new_state();
+    other.register("outer", outer).unwrap();
+    run(&mut other, &define_f, 0).unwrap();
+    other.get_global("outer").unwrap();
+    assert_eq!(other.pcall(0, 0, 0), LUA_ERRRUN);
+    assert_eq!(REPORTED.load(Ordering::SeqCst), 3);
+    let _ = panic::take_hook();
+}
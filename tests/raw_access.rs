@@ -0,0 +1,147 @@
+// Raw access goes straight to the table or string and never calls
+// __index, __newindex, __eq or __len, through the API and through the
+// base functions rawget, rawset, rawequal and rawlen.
+mod common;
+
+use common::*;
+use lua::api::consts::*;
+use lua::api::{LuaAPI, LuaResult};
+use lua::state::LuaState;
+
+fn fail(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    ls.push_string("metamethod called".to_string());
+    Err(ls.error())
+}
+
+// pushes {[1] = "one"} whose metatable has failing __index, __newindex,
+// __eq and __len
+fn push_guarded(ls: &mut LuaState) {
+    ls.new_table();
+    ls.push_string("one".to_string());
+    ls.set_i(-2, 1).unwrap();
+    ls.new_table();
+    for &event in &["__index", "__newindex", "__eq", "__len"] {
+        ls.push_rust_function(fail);
+        ls.set_field(-2, event).unwrap();
+    }
+    ls.set_metatable(-2).unwrap();
+}
+
+// calls the global function 'name' with the values at 'args' and returns
+// its first result as a string
+fn call_global(ls: &mut LuaState, name: &str, args: &[isize]) -> String {
+    ls.get_global(name).unwrap();
+    for &i in args {
+        ls.push_value(i);
+    }
+    ls.call(args.len(), 1).unwrap();
+    let s = ls.to_display_string(-1).unwrap();
+    ls.pop(2);
+    s
+}
+
+#[test]
+fn api_bypasses_metamethods() {
+    let mut ls = new_state();
+    push_guarded(&mut ls); // 1
+    push_guarded(&mut ls); // 2
+
+    ls.push_string("absent".to_string());
+    assert_eq!(ls.raw_get(1).unwrap(), LUA_TNIL);
+    assert_eq!(ls.raw_get_i(1, 1).unwrap(), LUA_TSTRING);
+    assert_eq!(ls.to_string(-1), "one");
+    ls.pop(2);
+
+    ls.push_string("k".to_string());
+    ls.push_integer(5);
+    ls.raw_set(1).unwrap();
+    ls.push_boolean(true);
+    ls.raw_set_i(1, 2).unwrap();
+    ls.push_string("k".to_string());
+    ls.raw_get(1).unwrap();
+    assert_eq!(ls.to_integer(-1), 5);
+    ls.pop(1);
+
+    assert!(!ls.raw_equal(1, 2));
+    assert!(ls.raw_equal(1, 1));
+    assert_eq!(ls.raw_len(1), 2);
+    assert_eq!(ls.get_top(), 2);
+
+    // the ordinary operations do call them
+    assert!(ls.compare(1, 2, LUA_OPEQ).is_err());
+    ls.set_top(2).unwrap();
+    assert!(ls.len(1).is_err());
+}
+
+#[test]
+fn api_raw_access_needs_a_table() {
+    let mut ls = new_state();
+    ls.push_integer(3);
+    ls.push_string("k".to_string());
+    assert_eq!(ls.raw_get(1).unwrap_err().to_string(), "attempt to index a number value");
+    ls.push_boolean(true);
+    assert_eq!(ls.raw_get_i(-1, 1).unwrap_err().to_string(), "attempt to index a boolean value");
+    ls.set_top(1).unwrap();
+    ls.push_string("k".to_string());
+    ls.push_integer(1);
+    assert_eq!(ls.raw_set(1).unwrap_err().to_string(), "attempt to index a number value");
+    ls.push_integer(1);
+    assert_eq!(ls.raw_set_i(1, 1).unwrap_err().to_string(), "attempt to index a number value");
+
+    // nil and NaN keys are rejected as by a normal assignment
+    ls.new_table();
+    ls.push_nil();
+    ls.push_integer(1);
+    assert_eq!(ls.raw_set(-3).unwrap_err().to_string(), "table index is nil");
+    ls.push_number(f64::NAN);
+    ls.push_integer(1);
+    assert_eq!(ls.raw_set(-3).unwrap_err().to_string(), "table index is NaN");
+}
+
+#[test]
+fn base_functions_bypass_metamethods() {
+    let mut ls = new_state();
+    push_guarded(&mut ls); // 1
+    push_guarded(&mut ls); // 2
+    ls.push_integer(1); // 3
+    ls.push_string("two".to_string()); // 4
+    ls.push_integer(2); // 5
+
+    assert_eq!(call_global(&mut ls, "rawget", &[1, 3]), "one");
+    assert_eq!(call_global(&mut ls, "rawget", &[1, 4]), "nil");
+    // rawset returns the table
+    ls.get_global("rawset").unwrap();
+    ls.push_value(1);
+    ls.push_value(5);
+    ls.push_value(4);
+    ls.call(3, 1).unwrap();
+    assert!(ls.raw_equal(-1, 1));
+    ls.pop(1);
+    assert_eq!(call_global(&mut ls, "rawget", &[1, 5]), "two");
+
+    assert_eq!(call_global(&mut ls, "rawequal", &[1, 2]), "false");
+    assert_eq!(call_global(&mut ls, "rawequal", &[2, 2]), "true");
+    assert_eq!(call_global(&mut ls, "rawlen", &[1]), "2");
+    assert_eq!(call_global(&mut ls, "rawlen", &[4]), "3");
+    assert_eq!(ls.get_top(), 5);
+}
+
+#[test]
+fn base_functions_check_their_arguments() {
+    let mut ls = new_state();
+    ls.push_integer(1);
+    let error_of = |ls: &mut LuaState, name: &str, nargs: usize| {
+        ls.get_global(name).unwrap();
+        for _ in 0..nargs {
+            ls.push_value(1);
+        }
+        assert_eq!(ls.pcall(nargs, 0, 0), LUA_ERRRUN);
+        let msg = ls.to_string(-1);
+        ls.pop(1);
+        msg
+    };
+    assert_eq!(error_of(&mut ls, "rawget", 2), "bad argument #1 to 'rawget' (table expected, got number)");
+    assert_eq!(error_of(&mut ls, "rawset", 3), "bad argument #1 to 'rawset' (table expected, got number)");
+    assert_eq!(error_of(&mut ls, "rawlen", 1), "bad argument #1 to 'rawlen' (table or string expected)");
+    assert_eq!(error_of(&mut ls, "rawequal", 1), "bad argument #2 to 'rawequal' (value expected)");
+}
@@ -0,0 +1,98 @@
+// The string library works on the bytes of strings, whatever they are.
+mod common;
+
+use common::*;
+use lua::api::consts::*;
+use lua::api::LuaAPI;
+use lua::state::LuaState;
+
+// pushes string.<name>
+fn push_string_fn(ls: &mut LuaState, name: &str) {
+    ls.get_global("string").unwrap();
+    ls.get_field(-1, name).unwrap();
+    ls.remove(-2).unwrap();
+}
+
+// string.<name>(s, ...ints), its single result
+fn call_on(ls: &mut LuaState, name: &str, s: &[u8], ints: &[i64]) -> Vec<u8> {
+    push_string_fn(ls, name);
+    ls.push_bytes(s);
+    ints.iter().for_each(|&i| ls.push_integer(i));
+    ls.call(1 + ints.len(), 1).unwrap();
+    let bytes = ls.to_bytes(-1).unwrap().as_bytes().to_vec();
+    ls.pop(1);
+    bytes
+}
+
+const BYTES: &[u8] = &[0xff, 0, 0x80, b'a', 0xc3];
+
+#[test]
+fn char_makes_one_byte_per_code() {
+    let mut ls = new_state();
+    push_string_fn(&mut ls, "char");
+    BYTES.iter().for_each(|&b| ls.push_integer(b as i64));
+    ls.call(BYTES.len(), 1).unwrap();
+    assert_eq!(ls.to_bytes(-1).unwrap().as_bytes(), BYTES);
+}
+
+#[test]
+fn functions_keep_the_bytes() {
+    let mut ls = new_state();
+    push_string_fn(&mut ls, "len");
+    ls.push_bytes(BYTES);
+    ls.call(1, 1).unwrap();
+    assert_eq!(ls.to_integer(-1), 5);
+    ls.pop(1);
+
+    assert_eq!(call_on(&mut ls, "reverse", BYTES, &[]), [0xc3, b'a', 0x80, 0, 0xff]);
+    assert_eq!(call_on(&mut ls, "sub", BYTES, &[2, 3]), [0, 0x80]);
+    assert_eq!(call_on(&mut ls, "sub", BYTES, &[-1]), [0xc3]);
+    assert_eq!(call_on(&mut ls, "upper", BYTES, &[]), [0xff, 0, 0x80, b'A', 0xc3]);
+    assert_eq!(call_on(&mut ls, "lower", b"\xffAB", &[]), b"\xffab");
+    assert_eq!(call_on(&mut ls, "rep", BYTES, &[2]), [BYTES, BYTES].concat());
+
+    push_string_fn(&mut ls, "byte");
+    ls.push_bytes(BYTES);
+    ls.push_integer(1);
+    ls.push_integer(-1);
+    ls.call(3, LUA_MULTRET).unwrap();
+    let codes: Vec<i64> = (1..=5).map(|i| ls.to_integer(i)).collect();
+    assert_eq!(codes, [0xff, 0, 0x80, b'a' as i64, 0xc3]);
+}
+
+#[test]
+fn rep_with_separator() {
+    let mut ls = new_state();
+    push_string_fn(&mut ls, "rep");
+    ls.push_bytes(b"ab");
+    ls.push_integer(3);
+    ls.push_bytes(b"\xff");
+    ls.call(3, 1).unwrap();
+    assert_eq!(ls.to_bytes(-1).unwrap().as_bytes(), b"ab\xffab\xffab");
+    ls.pop(1);
+
+    assert_eq!(call_on(&mut ls, "rep", b"ab", &[0]), b"");
+    assert_eq!(call_on(&mut ls, "rep", b"", &[1 << 40]), b"");
+}
+
+#[test]
+fn rep_checks_the_size_before_allocating() {
+    let mut ls = new_state();
+    push_string_fn(&mut ls, "rep");
+    ls.push_bytes(b"x");
+    ls.push_integer(1 << 40);
+    assert_eq!(ls.pcall(2, 1, 0), LUA_ERRRUN);
+    assert!(ls.to_string(-1).ends_with("resulting string too large"));
+    ls.pop(1);
+
+    // 1GB would fit in a string, not under the limit
+    ls.set_memory_limit(Some(1 << 20));
+    push_string_fn(&mut ls, "rep");
+    ls.push_bytes(b"x");
+    ls.push_integer(1 << 30);
+    assert_eq!(ls.pcall(2, 1, 0), LUA_ERRMEM);
+    assert_eq!(ls.to_string(-1), "not enough memory");
+    ls.pop(1);
+
+    assert_eq!(call_on(&mut ls, "rep", b"x", &[1000]).len(), 1000);
+}
@@ -0,0 +1,149 @@
+// Table traversal with the base functions next and pairs, and with the
+// generic for over them.
+mod common;
+
+use common::*;
+use lua::api::consts::*;
+use lua::api::{LuaAPI, LuaResult};
+use lua::state::LuaState;
+
+// the global 't' = {10, 20, 30, x = 1, y = 2, z = 3}
+fn state_with_table() -> LuaState {
+    let mut ls = new_state();
+    ls.new_table();
+    for i in 1..=3 {
+        ls.push_integer(i * 10);
+        ls.set_i(-2, i).unwrap();
+    }
+    for (i, name) in ["x", "y", "z"].iter().enumerate() {
+        ls.push_integer(i as i64 + 1);
+        ls.set_field(-2, name).unwrap();
+    }
+    ls.set_global("t").unwrap();
+    ls
+}
+
+// calls next(t, key) with the key on top, leaving its results
+fn call_next(ls: &mut LuaState) -> isize {
+    let top = ls.get_top() - 1;
+    ls.get_global("next").unwrap();
+    ls.get_global("t").unwrap();
+    ls.rotate(-3, 2).unwrap();
+    ls.call(2, LUA_MULTRET).unwrap();
+    ls.get_top() - top
+}
+
+#[test]
+fn next_visits_every_field_once() {
+    let mut ls = state_with_table();
+    let mut keys = Vec::new();
+    ls.push_nil();
+    while call_next(&mut ls) == 2 {
+        ls.pop(1);
+        keys.push(ls.to_display_string(-1).unwrap());
+        ls.pop(1);
+    }
+    assert!(ls.is_nil(-1));
+    assert_eq!(keys, ["1", "2", "3", "x", "y", "z"]);
+
+    // with no key, or nil, it starts over; an empty table ends at once
+    ls.set_top(0).unwrap();
+    ls.get_global("next").unwrap();
+    ls.get_global("t").unwrap();
+    ls.call(1, 2).unwrap();
+    assert_eq!(ls.to_integer(1), 1);
+    assert_eq!(ls.to_integer(2), 10);
+    ls.set_top(0).unwrap();
+    ls.get_global("next").unwrap();
+    ls.new_table();
+    ls.call(1, LUA_MULTRET).unwrap();
+    assert_eq!(ls.get_top(), 1);
+    assert!(ls.is_nil(1));
+}
+
+#[test]
+fn next_rejects_bad_arguments() {
+    let mut ls = state_with_table();
+    ls.push_string("absent".to_string());
+    ls.get_global("next").unwrap();
+    ls.get_global("t").unwrap();
+    ls.push_value(1);
+    assert_eq!(ls.pcall(2, 0, 0), LUA_ERRRUN);
+    assert_eq!(ls.to_string(-1), "invalid key to 'next'");
+    ls.set_top(0).unwrap();
+
+    ls.get_global("next").unwrap();
+    ls.push_integer(1);
+    assert_eq!(ls.pcall(1, 0, 0), LUA_ERRRUN);
+    assert_eq!(ls.to_string(-1), "bad argument #1 to 'next' (table expected, got number)");
+}
+
+fn custom_pairs(ls: &mut dyn LuaAPI) -> LuaResult<usize> {
+    ls.push_string("f".to_string());
+    ls.push_string("s".to_string());
+    ls.push_integer(0);
+    Ok(3)
+}
+
+#[test]
+fn pairs_returns_next_or_calls_the_metamethod() {
+    let mut ls = state_with_table();
+    ls.get_global("pairs").unwrap();
+    ls.get_global("t").unwrap();
+    ls.call(1, LUA_MULTRET).unwrap();
+    assert_eq!(ls.get_top(), 3);
+    ls.get_global("t").unwrap();
+    assert!(ls.raw_equal(2, -1));
+    assert!(ls.is_nil(3));
+    // the first value is next
+    ls.set_top(3).unwrap();
+    ls.call(2, 2).unwrap();
+    assert_eq!(ls.to_integer(-2), 1);
+    assert_eq!(ls.to_integer(-1), 10);
+    ls.set_top(0).unwrap();
+
+    // __pairs gets the table and gives the three values
+    ls.get_global("pairs").unwrap();
+    ls.new_table();
+    ls.new_table();
+    ls.push_rust_function(custom_pairs);
+    ls.set_field(-2, "__pairs").unwrap();
+    ls.set_metatable(-2).unwrap();
+    ls.call(1, LUA_MULTRET).unwrap();
+    assert_eq!(ls.get_top(), 3);
+    assert_eq!(ls.to_string(1), "f");
+    assert_eq!(ls.to_string(2), "s");
+    assert_eq!(ls.to_integer(3), 0);
+    ls.set_top(0).unwrap();
+
+    ls.get_global("pairs").unwrap();
+    assert_eq!(ls.pcall(0, 0, 0), LUA_ERRRUN);
+    assert_eq!(ls.to_string(-1), "bad argument #1 to 'pairs' (value expected)");
+}
+
+#[test]
+fn generic_for_can_clear_the_fields() {
+    // local n = 0; for k in pairs(t) do t[k] = nil; n = n + 1 end; return n
+    let main = Function::main(
+        vec![
+            abx(LOADK, 0, 0),
+            abc(GETTABUP, 1, 0, rk(1)),
+            abc(GETTABUP, 2, 0, rk(2)),
+            abc(CALL, 1, 2, 4),
+            asbx(JMP, 0, 3),
+            abc(GETTABUP, 5, 0, rk(2)),
+            abc(SETTABLE, 5, 4, rk(3)),
+            abc(ADD, 0, 0, rk(4)),
+            abc(TFORCALL, 1, 0, 1),
+            asbx(TFORLOOP, 3, -5),
+            abc(RETURN, 0, 2, 0),
+        ],
+        vec![Constant::Int(0), Constant::Str(b"pairs"), Constant::Str(b"t"), Constant::Nil, Constant::Int(1)],
+    );
+    let mut ls = state_with_table();
+    run(&mut ls, &main, 1).unwrap();
+    assert_eq!(ls.to_integer(-1), 6);
+    ls.get_global("t").unwrap();
+    ls.push_nil();
+    assert!(!ls.next(-2).unwrap());
+}